@@ -0,0 +1,354 @@
+//! Tick and sqrt-price arithmetic of the concentrated liquidity pool, a port of Uniswap v3.
+//!
+//! The price is `token_b / token_a` in base units, the tick `i` is the price `1.0001^i`.
+//! The prices are kept as `sqrt(price) * 2^96` (Q64.96), the intermediate products
+//! are evaluated with 512-bit integers.
+
+use near_sdk::{require, Balance};
+
+use crate::math::{self, U256};
+
+pub use uint_types::U512;
+
+// Lints of the code generated by the macro are out of our control
+#[allow(clippy::all)]
+mod uint_types {
+    use uint::construct_uint;
+
+    construct_uint! {
+        /// 512-bit unsigned integer.
+        pub struct U512(8);
+    }
+}
+
+/// The lowest tick, `1.0001^MIN_TICK` is about 2^-128.
+pub const MIN_TICK: i32 = -887_272;
+/// The highest tick, `1.0001^MAX_TICK` is about 2^128.
+pub const MAX_TICK: i32 = 887_272;
+/// The largest distance between the ticks that can be used by the positions.
+pub const MAX_TICK_SPACING: i32 = 16_384;
+
+/// Sqrt price of `MIN_TICK`.
+pub const MIN_SQRT_PRICE: u128 = 4_295_128_739;
+
+/// Sqrt price of `MAX_TICK`.
+pub fn max_sqrt_price() -> U256 {
+    U256::from_dec_str("1461446703485210103287273052203988822378723970342").unwrap()
+}
+
+/// `2^96`, one in Q64.96.
+pub fn q96() -> U256 {
+    U256::one() << 96
+}
+
+/// Factors `2^128 / sqrt(1.0001^(2^i))` of the bits of the absolute tick from `2^1` to `2^19`.
+const TICK_FACTORS: [u128; 19] = [
+    0xfff97272373d413259a46990580e213a,
+    0xfff2e50f5f656932ef12357cf3c7fdcc,
+    0xffe5caca7e10e4e61c3624eaa0941cd0,
+    0xffcb9843d60f6159c9db58835c926644,
+    0xff973b41fa98c081472e6896dfb254c0,
+    0xff2ea16466c96a3843ec78b326b52861,
+    0xfe5dee046a99a2a811c461f1969c3053,
+    0xfcbe86c7900a88aedcffc83b479aa3a4,
+    0xf987a7253ac413176f2b074cf7815e54,
+    0xf3392b0822b70005940c7a398e4b70f3,
+    0xe7159475a2c29b7443b29c7fa6e889d9,
+    0xd097f3bdfd2022b8845ad8f792aa5825,
+    0xa9f746462d870fdf8a65dc1f90e061e5,
+    0x70d869a156d2a1b890bb3df62baf32f7,
+    0x31be135f97d08fd981231505542fcfa6,
+    0x9aa508b5b7a84e1c677de54f3e99bc9,
+    0x5d6af8dedb81196699c329225ee604,
+    0x2216e584f5fa1ea926041bedfe98,
+    0x48a170391f7dc42444e8fa2,
+];
+
+fn widen(x: U256) -> U512 {
+    let mut words = [0_u64; 8];
+    words[..4].copy_from_slice(&x.0);
+    U512(words)
+}
+
+fn narrow(x: U512) -> U256 {
+    require!(x.0[4..].iter().all(|word| *word == 0), "U256 overflow");
+    U256([x.0[0], x.0[1], x.0[2], x.0[3]])
+}
+
+/// Returns `a * b / c` rounded down, the product is not truncated.
+pub fn mul_div(a: U256, b: U256, c: U256) -> U256 {
+    require!(!c.is_zero(), "Division by zero");
+    narrow(widen(a) * widen(b) / widen(c))
+}
+
+/// Returns `a * b / c` rounded up, the product is not truncated.
+pub fn mul_div_rounding_up(a: U256, b: U256, c: U256) -> U256 {
+    require!(!c.is_zero(), "Division by zero");
+    let (product, c) = (widen(a) * widen(b), widen(c));
+    let result = product / c;
+    narrow(if (product % c).is_zero() { result } else { result + 1 })
+}
+
+fn div_rounding_up(a: U256, b: U256) -> U256 {
+    let result = a / b;
+    if (a % b).is_zero() { result } else { result + 1 }
+}
+
+fn to_balance(x: U256) -> Balance {
+    require!(x <= U256::from(Balance::MAX), "Balance overflow");
+    x.as_u128()
+}
+
+/// Returns the liquidity after adding the signed `delta` to it.
+pub fn add_delta(liquidity: u128, delta: i128) -> u128 {
+    let result = if delta < 0 {
+        liquidity.checked_sub(delta.unsigned_abs())
+    } else {
+        liquidity.checked_add(delta as u128)
+    };
+    require!(result.is_some(), "Liquidity overflow");
+    result.unwrap()
+}
+
+/// Returns the sqrt price of the tick: `sqrt(1.0001^tick) * 2^96`.
+pub fn get_sqrt_price_at_tick(tick: i32) -> U256 {
+    require!((MIN_TICK..=MAX_TICK).contains(&tick), "The tick is out of range");
+    let abs_tick = tick.unsigned_abs();
+
+    // Q128.128 product of the factors of the set bits
+    let mut ratio = if abs_tick & 1 != 0 {
+        U256::from(0xfffcb933bd6fad37aa2d162d1a594001_u128)
+    } else {
+        U256::one() << 128
+    };
+    for (i, factor) in TICK_FACTORS.iter().enumerate() {
+        if abs_tick & (1 << (i + 1)) != 0 {
+            ratio = (ratio * U256::from(*factor)) >> 128;
+        }
+    }
+    if tick > 0 {
+        ratio = U256::MAX / ratio;
+    }
+
+    // Q128.128 to Q64.96 rounded up, so the price of the tick is never below the true one
+    let remainder = ratio & U256::from(u32::MAX);
+    (ratio >> 32) + if remainder.is_zero() { 0 } else { 1 }
+}
+
+/// Returns the greatest tick whose sqrt price is not above `sqrt_price`.
+pub fn get_tick_at_sqrt_price(sqrt_price: U256) -> i32 {
+    require!(
+        sqrt_price >= U256::from(MIN_SQRT_PRICE) && sqrt_price < max_sqrt_price(),
+        "The sqrt price is out of range"
+    );
+    let (mut low, mut high) = (MIN_TICK, MAX_TICK);
+    while low < high {
+        let middle = low + (high - low + 1) / 2;
+        if get_sqrt_price_at_tick(middle) <= sqrt_price {
+            low = middle;
+        } else {
+            high = middle - 1;
+        }
+    }
+    low
+}
+
+/// Returns the amount of token A between two sqrt prices for the liquidity:
+/// `liquidity * (sqrt_upper - sqrt_lower) / (sqrt_lower * sqrt_upper)`.
+pub fn get_amount_a_delta(sqrt_price_a: U256, sqrt_price_b: U256, liquidity: u128, round_up: bool) -> U256 {
+    let (lower, upper) = if sqrt_price_a < sqrt_price_b { (sqrt_price_a, sqrt_price_b) } else { (sqrt_price_b, sqrt_price_a) };
+    require!(!lower.is_zero(), "The sqrt price should be a positive number");
+
+    let numerator = U256::from(liquidity) << 96;
+    if round_up {
+        div_rounding_up(mul_div_rounding_up(numerator, upper - lower, upper), lower)
+    } else {
+        mul_div(numerator, upper - lower, upper) / lower
+    }
+}
+
+/// Returns the amount of token B between two sqrt prices for the liquidity:
+/// `liquidity * (sqrt_upper - sqrt_lower)`.
+pub fn get_amount_b_delta(sqrt_price_a: U256, sqrt_price_b: U256, liquidity: u128, round_up: bool) -> U256 {
+    let (lower, upper) = if sqrt_price_a < sqrt_price_b { (sqrt_price_a, sqrt_price_b) } else { (sqrt_price_b, sqrt_price_a) };
+    if round_up {
+        mul_div_rounding_up(U256::from(liquidity), upper - lower, q96())
+    } else {
+        mul_div(U256::from(liquidity), upper - lower, q96())
+    }
+}
+
+/// Returns the sqrt price after `amount_in` is added to the pool, rounded in favor of the pool:
+/// up for token A, as the price goes down, and down for token B, as the price goes up.
+pub fn get_next_sqrt_price_from_input(sqrt_price: U256, liquidity: u128, amount_in: Balance, a_for_b: bool) -> U256 {
+    require!(liquidity > 0, "The pool has no liquidity");
+    if amount_in == 0 {
+        return sqrt_price;
+    }
+
+    let numerator = U256::from(liquidity) << 96;
+    if a_for_b {
+        // liquidity * sqrt_price / (liquidity + amount_in * sqrt_price)
+        let denominator = widen(numerator) + widen(U256::from(amount_in)) * widen(sqrt_price);
+        let product = widen(numerator) * widen(sqrt_price);
+        let result = product / denominator;
+        narrow(if (product % denominator).is_zero() { result } else { result + 1 })
+    } else {
+        // sqrt_price + amount_in / liquidity
+        sqrt_price + (U256::from(amount_in) << 96) / U256::from(liquidity)
+    }
+}
+
+/// One step of a swap within the price range of the same liquidity.
+#[derive(Debug, PartialEq)]
+pub struct SwapStep {
+    /// The sqrt price after the step
+    pub sqrt_price_next: U256,
+    /// The amount of the input token that is swapped, without the fee
+    pub amount_in: Balance,
+    pub amount_out: Balance,
+    /// The fee taken from the input amount
+    pub fee_amount: Balance,
+}
+
+/// Returns the step of a swap of `amount_remaining`, the fee of `fee_bps` basis points is taken from it,
+/// that moves the price towards `sqrt_price_target` and stops there if the amount is enough to reach it.
+pub fn compute_swap_step(
+    sqrt_price: U256,
+    sqrt_price_target: U256,
+    liquidity: u128,
+    amount_remaining: Balance,
+    fee_bps: u32,
+) -> SwapStep {
+    let a_for_b = sqrt_price >= sqrt_price_target;
+    let amount_remaining_less_fee =
+        math::mul_div(amount_remaining, (math::FEE_DIVISOR - fee_bps) as Balance, math::FEE_DIVISOR as Balance);
+
+    let amount_to_target = if a_for_b {
+        get_amount_a_delta(sqrt_price_target, sqrt_price, liquidity, true)
+    } else {
+        get_amount_b_delta(sqrt_price, sqrt_price_target, liquidity, true)
+    };
+    let reaches_target = U256::from(amount_remaining_less_fee) >= amount_to_target;
+    let sqrt_price_next = if reaches_target {
+        sqrt_price_target
+    } else {
+        get_next_sqrt_price_from_input(sqrt_price, liquidity, amount_remaining_less_fee, a_for_b)
+    };
+
+    let (amount_in, amount_out) = if a_for_b {
+        (
+            get_amount_a_delta(sqrt_price_next, sqrt_price, liquidity, true),
+            get_amount_b_delta(sqrt_price_next, sqrt_price, liquidity, false),
+        )
+    } else {
+        (
+            get_amount_b_delta(sqrt_price, sqrt_price_next, liquidity, true),
+            get_amount_a_delta(sqrt_price, sqrt_price_next, liquidity, false),
+        )
+    };
+    let amount_in = to_balance(amount_in);
+
+    // The rest of the amount is the fee if the step doesn't reach the target
+    let fee_amount = if reaches_target {
+        math::mul_div_ceil(amount_in, fee_bps as Balance, (math::FEE_DIVISOR - fee_bps) as Balance)
+    } else {
+        amount_remaining - amount_in
+    };
+
+    SwapStep { sqrt_price_next, amount_in, amount_out: to_balance(amount_out), fee_amount }
+}
+
+/// Returns the largest liquidity of the price range `[sqrt_price_lower, sqrt_price_upper]`
+/// that the amounts can provide at the current sqrt price.
+pub fn get_liquidity_for_amounts(
+    sqrt_price: U256,
+    sqrt_price_lower: U256,
+    sqrt_price_upper: U256,
+    amount_a: Balance,
+    amount_b: Balance,
+) -> u128 {
+    // The liquidity of the amount of token A in [lower, upper]: amount * lower * upper / (upper - lower)
+    let for_amount_a = |lower: U256, upper: U256| {
+        mul_div(U256::from(amount_a), mul_div(lower, upper, q96()), upper - lower)
+    };
+    // The liquidity of the amount of token B in [lower, upper]: amount / (upper - lower)
+    let for_amount_b = |lower: U256, upper: U256| mul_div(U256::from(amount_b), q96(), upper - lower);
+
+    let liquidity = if sqrt_price <= sqrt_price_lower {
+        for_amount_a(sqrt_price_lower, sqrt_price_upper)
+    } else if sqrt_price < sqrt_price_upper {
+        for_amount_a(sqrt_price, sqrt_price_upper).min(for_amount_b(sqrt_price_lower, sqrt_price))
+    } else {
+        for_amount_b(sqrt_price_lower, sqrt_price_upper)
+    };
+    to_balance(liquidity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqrt_price_at_tick() {
+        assert_eq!(get_sqrt_price_at_tick(0), q96());
+        assert_eq!(get_sqrt_price_at_tick(MIN_TICK), U256::from(MIN_SQRT_PRICE));
+        assert_eq!(get_sqrt_price_at_tick(MAX_TICK), max_sqrt_price());
+        // sqrt(1.0001) * 2^96
+        assert_eq!(get_sqrt_price_at_tick(1), U256::from_dec_str("79232123823359799118286999568").unwrap());
+        assert!(get_sqrt_price_at_tick(-1) < q96());
+    }
+
+    #[test]
+    fn test_tick_at_sqrt_price() {
+        for tick in [MIN_TICK, -100_000, -1, 0, 1, 12_345, MAX_TICK - 1] {
+            let sqrt_price = get_sqrt_price_at_tick(tick);
+            assert_eq!(get_tick_at_sqrt_price(sqrt_price), tick);
+            assert_eq!(get_tick_at_sqrt_price(sqrt_price + 1), tick);
+            if tick > MIN_TICK {
+                assert_eq!(get_tick_at_sqrt_price(sqrt_price - 1), tick - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_amount_deltas() {
+        let liquidity = 10_u128.pow(18);
+        let (lower, upper) = (get_sqrt_price_at_tick(-1_000), get_sqrt_price_at_tick(1_000));
+        let amount_a = get_amount_a_delta(lower, upper, liquidity, true);
+        let amount_b = get_amount_b_delta(lower, upper, liquidity, true);
+        // The range is symmetric around the price of 1
+        assert!(amount_a.abs_diff(amount_b) <= U256::from(1));
+        assert_eq!(get_amount_a_delta(lower, upper, liquidity, false) + 1, amount_a);
+    }
+
+    #[test]
+    fn test_compute_swap_step() {
+        let liquidity = 10_u128.pow(24);
+        let (price, target) = (q96(), get_sqrt_price_at_tick(-10));
+
+        // The amount is not enough to reach the target
+        let step = compute_swap_step(price, target, liquidity, 10_u128.pow(18), 30);
+        assert!(step.sqrt_price_next < price && step.sqrt_price_next > target);
+        assert_eq!(step.amount_in + step.fee_amount, 10_u128.pow(18));
+        assert_eq!(step.fee_amount, 3 * 10_u128.pow(15));
+        assert!(step.amount_out < step.amount_in);
+
+        // The amount is enough to reach the target, the rest of it stays unused
+        let step = compute_swap_step(price, target, liquidity, 10_u128.pow(24), 30);
+        assert_eq!(step.sqrt_price_next, target);
+        assert!(step.amount_in + step.fee_amount < 10_u128.pow(24));
+    }
+
+    #[test]
+    fn test_liquidity_for_amounts() {
+        let (lower, upper) = (get_sqrt_price_at_tick(-1_000), get_sqrt_price_at_tick(1_000));
+        let liquidity = get_liquidity_for_amounts(q96(), lower, upper, 10_u128.pow(18), 10_u128.pow(18));
+        assert!(get_amount_a_delta(q96(), upper, liquidity, true) <= U256::from(10_u128.pow(18)));
+        assert!(get_amount_b_delta(lower, q96(), liquidity, true) <= U256::from(10_u128.pow(18)));
+
+        // Only token A is used below the range
+        let below = get_liquidity_for_amounts(get_sqrt_price_at_tick(-2_000), lower, upper, 10_u128.pow(18), 0);
+        assert!(below > 0);
+    }
+}
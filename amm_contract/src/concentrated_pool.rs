@@ -0,0 +1,666 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance, PromiseResult};
+
+use crate::concentrated_math::{self as cl_math, MAX_TICK, MAX_TICK_SPACING, MIN_SQRT_PRICE, MIN_TICK};
+use crate::events::{AddLiquidity, RemoveLiquidity};
+use crate::external::ext_self;
+use crate::math::{self, U256};
+use crate::pool::Pool;
+use crate::*;
+
+/// State of an initialized tick, the bound of some positions.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct TickInfo {
+    /// Total liquidity of the positions bounded by the tick
+    pub liquidity_gross: u128,
+    /// Liquidity that becomes active when the price crosses the tick upwards
+    pub liquidity_net: i128,
+    /// Fee growth per unit of liquidity of the tokens on the other side of the tick from the current one, in Q64.64
+    pub fee_growth_outside: [u128; 2],
+}
+
+/// Liquidity of an owner in the price range `[tick_lower, tick_upper)`.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Position {
+    pub owner_id: AccountId,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: u128,
+    /// Fee growth inside the range at the last update of the position, in Q64.64
+    pub fee_growth_inside_last: [u128; 2],
+    /// Fees of the tokens accrued by the position until the last update
+    pub tokens_owed: [Balance; 2],
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PositionInfo {
+    pub position_id: u64,
+    pub owner_id: AccountId,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity: U128,
+    /// Fees of the tokens accrued by the position so far
+    pub tokens_owed: Vec<U128>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ConcentratedPoolInfo {
+    /// Current sqrt price in Q64.96
+    pub sqrt_price_x96: String,
+    pub tick: i32,
+    pub tick_spacing: i32,
+    /// Liquidity of the positions in range of the current price
+    pub liquidity: U128,
+}
+
+/// A swap computed against the current state of the pool, applied by `apply_swap`.
+struct SwapComputation {
+    amount_out: Balance,
+    protocol_fee: Balance,
+    sqrt_price: U256,
+    tick: i32,
+    liquidity: u128,
+    /// Fee growth of the input token after the swap
+    fee_growth_global_in: u128,
+    /// Crossed ticks with the fee growth of the input token at the moment of the crossing
+    crossed_ticks: Vec<(i32, u128)>,
+}
+
+/// Pool of two tokens where the LPs provide liquidity within price ranges, like Uniswap v3.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ConcentratedPool {
+    pub tokens: Vec<AccountId>,
+    /// Amounts of the tokens held for the positions including their uncollected fees, in base units
+    pub reserves: Vec<Balance>,
+    /// Swap fee in basis points, taken from the input amount
+    pub fee_bps: u32,
+    /// Part of the swap fee in basis points of the fee that is accrued for the owner instead of the LPs
+    pub protocol_share_bps: u32,
+    /// Protocol fees of the tokens accrued for the owner
+    pub protocol_fees: Vec<Balance>,
+    /// Only the ticks divisible by the spacing can bound the positions
+    pub tick_spacing: i32,
+    /// Current sqrt price of token B in token A, in Q64.96
+    pub sqrt_price: U256,
+    /// The greatest tick whose sqrt price is not above the current one
+    pub tick: i32,
+    /// Liquidity of the positions in range of the current price
+    pub liquidity: u128,
+    /// Fee growth per unit of liquidity of the tokens over the lifetime of the pool, in Q64.64
+    pub fee_growth_global: [u128; 2],
+    pub ticks: LookupMap<i32, TickInfo>,
+    /// Bit `i` of the word `w` is set if the tick `(128 * w + i) * tick_spacing` is initialized
+    pub tick_bitmap: LookupMap<i32, u128>,
+    pub positions: LookupMap<u64, Position>,
+    /// Ids of the positions of each owner
+    pub account_positions: LookupMap<AccountId, Vec<u64>>,
+    pub next_position_id: u64,
+}
+
+impl ConcentratedPool {
+    /// Creates the pool with the price of `initial_tick`.
+    pub fn new(pool_id: u64, token_a: AccountId, token_b: AccountId, fee_bps: u32, tick_spacing: i32, initial_tick: i32) -> Self {
+        require!(token_a != token_b, "The tokens of the pool should be different");
+        require!(
+            tick_spacing > 0 && tick_spacing <= MAX_TICK_SPACING,
+            format!("The tick spacing should be in [1, {}]", MAX_TICK_SPACING)
+        );
+
+        Self {
+            tokens: vec![token_a, token_b],
+            reserves: vec![0; 2],
+            fee_bps,
+            protocol_share_bps: 0,
+            protocol_fees: vec![0; 2],
+            tick_spacing,
+            sqrt_price: cl_math::get_sqrt_price_at_tick(initial_tick),
+            tick: initial_tick,
+            liquidity: 0,
+            fee_growth_global: [0; 2],
+            ticks: LookupMap::new(StorageKey::Ticks { pool_id }.try_to_vec().unwrap()),
+            tick_bitmap: LookupMap::new(StorageKey::TickBitmap { pool_id }.try_to_vec().unwrap()),
+            positions: LookupMap::new(StorageKey::Positions { pool_id }.try_to_vec().unwrap()),
+            account_positions: LookupMap::new(StorageKey::AccountPositions { pool_id }.try_to_vec().unwrap()),
+            next_position_id: 0,
+        }
+    }
+
+    /// Returns the index of the token, panics if `token_id` is not part of the pool.
+    pub fn index_of(&self, token_id: &AccountId) -> usize {
+        self.tokens
+            .iter()
+            .position(|t| t == token_id)
+            .unwrap_or_else(|| env::panic_str(format!("Unsupported token contract id: {}", token_id).as_str()))
+    }
+
+    /// Returns the virtual constant product `liquidity^2` of the current price range.
+    pub fn invariant(&self) -> U256 {
+        U256::from(self.liquidity) * U256::from(self.liquidity)
+    }
+
+    /// Returns the amount of `token_out` per one unit of `token_in` with `PRICE_PRECISION`, without the fee.
+    /// The price is defined by the current sqrt price even if no position is in range.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        require!(i != j, "The tokens of the swap should be different");
+        let precision = U256::from(math::PRICE_PRECISION);
+        if i == 0 {
+            cl_math::mul_div(cl_math::mul_div(self.sqrt_price, self.sqrt_price, cl_math::q96()), precision, cl_math::q96())
+        } else {
+            cl_math::mul_div(cl_math::mul_div(precision, cl_math::q96(), self.sqrt_price), cl_math::q96(), self.sqrt_price)
+        }
+    }
+
+    /// Returns the position of the compressed tick in the bitmap: the word and the bit.
+    fn bitmap_position(compressed: i32) -> (i32, u32) {
+        (compressed.div_euclid(128), compressed.rem_euclid(128) as u32)
+    }
+
+    /// Sets the bit of the tick if it's not set and clears it otherwise.
+    fn flip_tick(&mut self, tick: i32) {
+        let (word_pos, bit_pos) = Self::bitmap_position(tick / self.tick_spacing);
+        let word = self.tick_bitmap.get(&word_pos).unwrap_or(0) ^ (1 << bit_pos);
+        if word == 0 {
+            self.tick_bitmap.remove(&word_pos);
+        } else {
+            self.tick_bitmap.insert(&word_pos, &word);
+        }
+    }
+
+    /// Returns the next initialized tick in the word of the bitmap of `tick`, at or below it for `lte`
+    /// and above it otherwise, or the last tick of the word and false if there are none.
+    fn next_initialized_tick(&self, tick: i32, lte: bool) -> (i32, bool) {
+        let compressed = tick.div_euclid(self.tick_spacing);
+        if lte {
+            let (word_pos, bit_pos) = Self::bitmap_position(compressed);
+            let masked = self.tick_bitmap.get(&word_pos).unwrap_or(0) & (u128::MAX >> (127 - bit_pos));
+            if masked != 0 {
+                let most_significant_bit = 127 - masked.leading_zeros();
+                ((compressed - (bit_pos - most_significant_bit) as i32) * self.tick_spacing, true)
+            } else {
+                ((compressed - bit_pos as i32) * self.tick_spacing, false)
+            }
+        } else {
+            let (word_pos, bit_pos) = Self::bitmap_position(compressed + 1);
+            let masked = self.tick_bitmap.get(&word_pos).unwrap_or(0) & (u128::MAX << bit_pos);
+            if masked != 0 {
+                let least_significant_bit = masked.trailing_zeros();
+                ((compressed + 1 + (least_significant_bit - bit_pos) as i32) * self.tick_spacing, true)
+            } else {
+                ((compressed + 1 + (127 - bit_pos) as i32) * self.tick_spacing, false)
+            }
+        }
+    }
+
+    /// Computes the swap of `amount_in` without changing the state, the whole amount must be swapped.
+    fn compute_swap(&self, a_for_b: bool, amount_in: Balance, fee_bps: u32, protocol_share_bps: u32) -> SwapComputation {
+        let index_in = if a_for_b { 0 } else { 1 };
+        let sqrt_price_limit = if a_for_b {
+            U256::from(MIN_SQRT_PRICE) + 1
+        } else {
+            cl_math::max_sqrt_price() - 1
+        };
+
+        let mut swap = SwapComputation {
+            amount_out: 0,
+            protocol_fee: 0,
+            sqrt_price: self.sqrt_price,
+            tick: self.tick,
+            liquidity: self.liquidity,
+            fee_growth_global_in: self.fee_growth_global[index_in],
+            crossed_ticks: vec![],
+        };
+        let mut amount_remaining = amount_in;
+        while amount_remaining > 0 && swap.sqrt_price != sqrt_price_limit {
+            let (tick_next, initialized) = self.next_initialized_tick(swap.tick, a_for_b);
+            let tick_next = tick_next.clamp(MIN_TICK, MAX_TICK);
+            let sqrt_price_next = cl_math::get_sqrt_price_at_tick(tick_next);
+            let sqrt_price_target = if a_for_b {
+                sqrt_price_next.max(sqrt_price_limit)
+            } else {
+                sqrt_price_next.min(sqrt_price_limit)
+            };
+
+            let sqrt_price_start = swap.sqrt_price;
+            let step = cl_math::compute_swap_step(swap.sqrt_price, sqrt_price_target, swap.liquidity, amount_remaining, fee_bps);
+            swap.sqrt_price = step.sqrt_price_next;
+            amount_remaining -= step.amount_in + step.fee_amount;
+            swap.amount_out += step.amount_out;
+
+            // The LP part of the fee is distributed over the liquidity in range
+            let protocol_fee = math::fee_of(step.fee_amount, protocol_share_bps);
+            swap.protocol_fee += protocol_fee;
+            if swap.liquidity > 0 {
+                let growth = (U256::from(step.fee_amount - protocol_fee) << 64) / U256::from(swap.liquidity);
+                swap.fee_growth_global_in = swap.fee_growth_global_in.wrapping_add(growth.low_u128());
+            }
+
+            if swap.sqrt_price == sqrt_price_next {
+                if initialized {
+                    let liquidity_net = self.ticks.get(&tick_next).unwrap().liquidity_net;
+                    let liquidity_net = if a_for_b { -liquidity_net } else { liquidity_net };
+                    swap.liquidity = cl_math::add_delta(swap.liquidity, liquidity_net);
+                    swap.crossed_ticks.push((tick_next, swap.fee_growth_global_in));
+                }
+                swap.tick = if a_for_b { tick_next - 1 } else { tick_next };
+            } else if swap.sqrt_price != sqrt_price_start {
+                swap.tick = cl_math::get_tick_at_sqrt_price(swap.sqrt_price);
+            }
+        }
+        require!(amount_remaining == 0, "swap: not enough liquidity in the pool");
+
+        swap
+    }
+
+    /// Applies the swap computed by `compute_swap` to the state of the pool.
+    fn apply_swap(&mut self, a_for_b: bool, swap: &SwapComputation) {
+        let (index_in, index_out) = if a_for_b { (0, 1) } else { (1, 0) };
+        for (tick, fee_growth_global_in) in swap.crossed_ticks.iter() {
+            let mut info = self.ticks.get(tick).unwrap();
+            info.fee_growth_outside[index_in] = fee_growth_global_in.wrapping_sub(info.fee_growth_outside[index_in]);
+            info.fee_growth_outside[index_out] =
+                self.fee_growth_global[index_out].wrapping_sub(info.fee_growth_outside[index_out]);
+            self.ticks.insert(tick, &info);
+        }
+        self.sqrt_price = swap.sqrt_price;
+        self.tick = swap.tick;
+        self.liquidity = swap.liquidity;
+        self.fee_growth_global[index_in] = swap.fee_growth_global_in;
+    }
+
+    /// Returns the amount of `token_out` that a swap of `amount_in` of `token_in` would pay out.
+    pub fn get_return(&self, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> Balance {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        require!(i != j, "The tokens of the swap should be different");
+        self.compute_swap(i == 0, amount_in, self.fee_bps, self.protocol_share_bps).amount_out
+    }
+
+    /// Exchanges `amount_in` of `token_in` to `token_out`, crossing the ticks on the way.
+    /// The LP part of the fee is accrued to the positions in range, the protocol part is accrued separately.
+    /// Returns the amount of `token_out` that has to be paid out and the accrued protocol fee.
+    pub fn swap(&mut self, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> (Balance, Balance) {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        require!(i != j, "The tokens of the swap should be different");
+
+        let swap = self.compute_swap(i == 0, amount_in, self.fee_bps, self.protocol_share_bps);
+        require!(swap.amount_out > 0, "swap: the amount is too small to be swapped");
+        self.apply_swap(i == 0, &swap);
+        self.reserves[i] += amount_in - swap.protocol_fee;
+        self.reserves[j] -= swap.amount_out;
+        self.protocol_fees[i] += swap.protocol_fee;
+
+        (swap.amount_out, swap.protocol_fee)
+    }
+
+    /// Returns the fee growth of the tokens inside the range, in Q64.64.
+    fn fee_growth_inside(&self, tick_lower: i32, tick_upper: i32) -> [u128; 2] {
+        let (lower, upper) = (self.ticks.get(&tick_lower).unwrap_or_default(), self.ticks.get(&tick_upper).unwrap_or_default());
+        let mut inside = [0; 2];
+        for (k, growth) in inside.iter_mut().enumerate() {
+            let global = self.fee_growth_global[k];
+            let below = if self.tick >= tick_lower {
+                lower.fee_growth_outside[k]
+            } else {
+                global.wrapping_sub(lower.fee_growth_outside[k])
+            };
+            let above = if self.tick < tick_upper {
+                upper.fee_growth_outside[k]
+            } else {
+                global.wrapping_sub(upper.fee_growth_outside[k])
+            };
+            *growth = global.wrapping_sub(below).wrapping_sub(above);
+        }
+        inside
+    }
+
+    /// Returns the fees of the tokens accrued by the position since its last update.
+    fn accrued_fees(&self, position: &Position) -> [Balance; 2] {
+        let inside = self.fee_growth_inside(position.tick_lower, position.tick_upper);
+        let mut fees = [0; 2];
+        for (k, fee) in fees.iter_mut().enumerate() {
+            let growth = inside[k].wrapping_sub(position.fee_growth_inside_last[k]);
+            *fee = ((U256::from(growth) * U256::from(position.liquidity)) >> 64).as_u128();
+        }
+        fees
+    }
+
+    /// Adds the signed `liquidity_delta` to the bound tick.
+    /// Returns true if the tick is initialized or cleared by the update.
+    fn update_tick(&mut self, tick: i32, liquidity_delta: i128, upper: bool) -> bool {
+        let mut info = self.ticks.get(&tick).unwrap_or_default();
+        let liquidity_gross_before = info.liquidity_gross;
+        if liquidity_gross_before == 0 && tick <= self.tick {
+            // By convention, all the fee growth before the initialization happened below the tick
+            info.fee_growth_outside = self.fee_growth_global;
+        }
+        info.liquidity_gross = cl_math::add_delta(liquidity_gross_before, liquidity_delta);
+        info.liquidity_net = if upper {
+            info.liquidity_net.checked_sub(liquidity_delta)
+        } else {
+            info.liquidity_net.checked_add(liquidity_delta)
+        }
+        .unwrap_or_else(|| env::panic_str("Liquidity overflow"));
+        self.ticks.insert(&tick, &info);
+
+        (liquidity_gross_before == 0) != (info.liquidity_gross == 0)
+    }
+
+    /// Adds the signed `liquidity_delta` to the position and its ticks, the fees are accrued to the position.
+    /// Returns the amounts of the tokens that the delta is worth at the current price,
+    /// rounded up for the added liquidity and down for the removed one.
+    fn update_position(&mut self, position: &mut Position, liquidity_delta: i128) -> [Balance; 2] {
+        let (tick_lower, tick_upper) = (position.tick_lower, position.tick_upper);
+        let flipped_lower = self.update_tick(tick_lower, liquidity_delta, false);
+        let flipped_upper = self.update_tick(tick_upper, liquidity_delta, true);
+        if flipped_lower {
+            self.flip_tick(tick_lower);
+        }
+        if flipped_upper {
+            self.flip_tick(tick_upper);
+        }
+
+        let fees = self.accrued_fees(position);
+        position.tokens_owed[0] += fees[0];
+        position.tokens_owed[1] += fees[1];
+        position.fee_growth_inside_last = self.fee_growth_inside(tick_lower, tick_upper);
+        position.liquidity = cl_math::add_delta(position.liquidity, liquidity_delta);
+
+        // The ticks that don't bound any position anymore are cleared
+        if liquidity_delta < 0 {
+            if flipped_lower {
+                self.ticks.remove(&tick_lower);
+            }
+            if flipped_upper {
+                self.ticks.remove(&tick_upper);
+            }
+        }
+
+        let (round_up, liquidity) = (liquidity_delta > 0, liquidity_delta.unsigned_abs());
+        let (sqrt_price_lower, sqrt_price_upper) =
+            (cl_math::get_sqrt_price_at_tick(tick_lower), cl_math::get_sqrt_price_at_tick(tick_upper));
+        let (amount_a, amount_b) = if self.tick < tick_lower {
+            (cl_math::get_amount_a_delta(sqrt_price_lower, sqrt_price_upper, liquidity, round_up), U256::zero())
+        } else if self.tick < tick_upper {
+            self.liquidity = cl_math::add_delta(self.liquidity, liquidity_delta);
+            (
+                cl_math::get_amount_a_delta(self.sqrt_price, sqrt_price_upper, liquidity, round_up),
+                cl_math::get_amount_b_delta(sqrt_price_lower, self.sqrt_price, liquidity, round_up),
+            )
+        } else {
+            (U256::zero(), cl_math::get_amount_b_delta(sqrt_price_lower, sqrt_price_upper, liquidity, round_up))
+        };
+        require!(amount_a <= U256::from(Balance::MAX) && amount_b <= U256::from(Balance::MAX), "Balance overflow");
+        [amount_a.as_u128(), amount_b.as_u128()]
+    }
+
+    /// Opens a position of the owner in `[tick_lower, tick_upper)` with the largest liquidity the amounts can provide.
+    /// Returns the id of the position, its liquidity and the amounts of the tokens that are actually used.
+    pub fn add_position(
+        &mut self,
+        owner_id: &AccountId,
+        tick_lower: i32,
+        tick_upper: i32,
+        amounts: &[Balance],
+    ) -> (u64, u128, [Balance; 2]) {
+        require!(amounts.len() == 2, "The amounts should be given for all the tokens of the pool");
+        require!(tick_lower < tick_upper, "The lower tick should be below the upper one");
+        require!(tick_lower >= MIN_TICK && tick_upper <= MAX_TICK, "The tick is out of range");
+        require!(
+            tick_lower % self.tick_spacing == 0 && tick_upper % self.tick_spacing == 0,
+            format!("The ticks should be divisible by the tick spacing {}", self.tick_spacing)
+        );
+
+        let liquidity = cl_math::get_liquidity_for_amounts(
+            self.sqrt_price,
+            cl_math::get_sqrt_price_at_tick(tick_lower),
+            cl_math::get_sqrt_price_at_tick(tick_upper),
+            amounts[0],
+            amounts[1],
+        );
+        require!(liquidity > 0 && liquidity <= i128::MAX as u128, "Insufficient liquidity minted");
+
+        let mut position = Position {
+            owner_id: owner_id.clone(),
+            tick_lower,
+            tick_upper,
+            liquidity: 0,
+            fee_growth_inside_last: [0; 2],
+            tokens_owed: [0; 2],
+        };
+        let amounts = self.update_position(&mut position, liquidity as i128);
+        self.reserves[0] += amounts[0];
+        self.reserves[1] += amounts[1];
+
+        let position_id = self.next_position_id;
+        self.next_position_id += 1;
+        self.positions.insert(&position_id, &position);
+        let mut account_positions = self.account_positions.get(owner_id).unwrap_or_default();
+        account_positions.push(position_id);
+        self.account_positions.insert(owner_id, &account_positions);
+
+        (position_id, liquidity, amounts)
+    }
+
+    /// Removes `liquidity` of the position of the owner and collects all its fees.
+    /// The position is closed when all its liquidity is removed.
+    /// Returns the amounts of the tokens that have to be paid out.
+    pub fn remove_position(&mut self, owner_id: &AccountId, position_id: u64, liquidity: u128) -> [Balance; 2] {
+        let mut position = self.unwrap_position(position_id);
+        require!(position.owner_id == *owner_id, "The position doesn't belong to the account");
+        require!(liquidity <= position.liquidity, "The position doesn't have enough liquidity");
+
+        let amounts = self.update_position(&mut position, -(liquidity as i128));
+        let amounts = [amounts[0] + position.tokens_owed[0], amounts[1] + position.tokens_owed[1]];
+        position.tokens_owed = [0; 2];
+        self.reserves[0] -= amounts[0];
+        self.reserves[1] -= amounts[1];
+
+        if position.liquidity == 0 {
+            self.close_position(position_id, &position.owner_id);
+        } else {
+            self.positions.insert(&position_id, &position);
+        }
+        amounts
+    }
+
+    fn close_position(&mut self, position_id: u64, owner_id: &AccountId) {
+        self.positions.remove(&position_id);
+        let mut account_positions = self.account_positions.get(owner_id).unwrap_or_default();
+        account_positions.retain(|id| *id != position_id);
+        if account_positions.is_empty() {
+            self.account_positions.remove(owner_id);
+        } else {
+            self.account_positions.insert(owner_id, &account_positions);
+        }
+    }
+
+    fn unwrap_position(&self, position_id: u64) -> Position {
+        self.positions
+            .get(&position_id)
+            .unwrap_or_else(|| env::panic_str(format!("Position {} doesn't exist", position_id).as_str()))
+    }
+
+    /// Returns the position with the fees accrued so far.
+    pub fn position_info(&self, position_id: u64) -> PositionInfo {
+        let position = self.unwrap_position(position_id);
+        let fees = self.accrued_fees(&position);
+        PositionInfo {
+            position_id,
+            owner_id: position.owner_id.clone(),
+            tick_lower: position.tick_lower,
+            tick_upper: position.tick_upper,
+            liquidity: position.liquidity.into(),
+            tokens_owed: vec![
+                (position.tokens_owed[0] + fees[0]).into(),
+                (position.tokens_owed[1] + fees[1]).into(),
+            ],
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Registers a new concentrated liquidity pool of the pair with the given swap fee in basis points
+    /// and tick spacing, starting at the price of `initial_tick`, and returns its id. Requires the role `PoolCreator`.
+    pub fn add_concentrated_pool(
+        &mut self,
+        token_a: AccountId,
+        token_b: AccountId,
+        fee_bps: u32,
+        tick_spacing: i32,
+        initial_tick: i32,
+    ) -> u64 {
+        self.internal_assert_role(Role::PoolCreator);
+        require!(fee_bps <= fees::MAX_FEE_BPS, format!("The fee can't exceed {} bps", fees::MAX_FEE_BPS));
+
+        let pool_id = self.pools.len();
+        let pool = ConcentratedPool::new(pool_id, token_a.clone(), token_b.clone(), fee_bps, tick_spacing, initial_tick);
+        self.internal_add_pool(Pool::ConcentratedPool(pool));
+        log!(
+            "add_concentrated_pool: pool {} of {} and {} with fee {} bps, tick spacing {} at tick {}",
+            pool_id, token_a, token_b, fee_bps, tick_spacing, initial_tick
+        );
+        pool_id
+    }
+
+    /// Provides liquidity to the concentrated pool `pool_id` within the price range `[tick_lower, tick_upper)`.
+    /// The position gets the largest liquidity `amounts` can provide at the current price, only the used part
+    /// of the amounts is taken from the internal deposit of the caller, the rest stays in the deposit.
+    /// Returns the id of the new position.
+    pub fn add_position(
+        &mut self,
+        pool_id: u64,
+        tick_lower: i32,
+        tick_upper: i32,
+        amounts: Vec<U128>,
+        min_liquidity: Option<U128>,
+    ) -> u64 {
+        self.internal_assert_pool_not_paused(pool_id);
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        let amounts: Vec<Balance> = amounts.iter().map(|a| a.0).collect();
+        let (position_id, liquidity, amounts) =
+            pool.as_concentrated_mut().add_position(&account_id, tick_lower, tick_upper, &amounts);
+        self.internal_update_account_pools(pool_id, &pool, &account_id);
+        let min_liquidity = min_liquidity.map_or(1, |v| v.0);
+        require!(
+            liquidity >= min_liquidity,
+            format!("add_position: liquidity {} is less than min liquidity {}", liquidity, min_liquidity)
+        );
+        self.internal_save_pool(pool_id, &pool);
+        for (token_id, amount) in pool.tokens().iter().zip(amounts).filter(|(_, amount)| *amount > 0) {
+            self.internal_withdraw(&account_id, token_id, amount);
+        }
+        self.internal_charge_storage(&account_id, initial_bytes);
+        self.internal_reserve_storage(&account_id, self.bytes_for_position);
+        log!("add_position: position {} of {} with liquidity {} for {:?}", position_id, account_id, liquidity, amounts);
+        AddLiquidity {
+            pool_id,
+            account_id: &account_id,
+            amounts: &amounts.iter().copied().map(U128).collect::<Vec<_>>(),
+            shares: &U128(liquidity),
+            position_id: Some(position_id),
+            reserves: &internal::reserves_of(&pool),
+        }
+        .emit();
+
+        position_id
+    }
+
+    /// Removes `liquidity` of the position and pays out the tokens together with all the fees of the position,
+    /// `liquidity` of zero only collects the fees. The position is closed when all its liquidity is removed.
+    /// The call fails if the payout is less than `min_amounts`.
+    pub fn remove_position(&mut self, pool_id: u64, position_id: u64, liquidity: U128, min_amounts: Option<Vec<U128>>) {
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        let amounts = pool.as_concentrated_mut().remove_position(&account_id, position_id, liquidity.0);
+        self.internal_update_account_pools(pool_id, &pool, &account_id);
+        if let Some(min_amounts) = min_amounts {
+            require!(min_amounts.len() == 2, "The min amounts should be given for all the tokens of the pool");
+            require!(
+                amounts.iter().zip(&min_amounts).all(|(amount, min_amount)| *amount >= min_amount.0),
+                "remove_position: the amounts out are less than the min amounts"
+            );
+        }
+        require!(amounts.iter().any(|a| *a > 0), "remove_position: there is nothing to pay out");
+        self.internal_save_pool(pool_id, &pool);
+        self.internal_charge_storage(&account_id, initial_bytes);
+        if pool.as_concentrated().positions.get(&position_id).is_none() {
+            self.internal_release_storage(&account_id, self.bytes_for_position);
+        }
+        RemoveLiquidity {
+            pool_id,
+            account_id: &account_id,
+            amounts: &amounts.iter().copied().map(U128).collect::<Vec<_>>(),
+            shares: &liquidity,
+            position_id: Some(position_id),
+            reserves: &internal::reserves_of(&pool),
+        }
+        .emit();
+
+        pool.tokens()
+            .iter()
+            .zip(amounts)
+            .filter(|(_, amount)| *amount > 0)
+            .map(|(token_id, amount)| self.internal_ft_transfer(token_id, &account_id, amount, "AMM remove position".to_string()))
+            .reduce(|payouts, payout| payouts.and(payout))
+            .unwrap()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_remove_position(pool_id, account_id, amounts.into_iter().map(U128).collect()),
+            );
+    }
+
+    /// Records the payouts that failed as pending for the owner of the position.
+    #[private]
+    pub fn on_remove_position(&mut self, pool_id: u64, account_id: AccountId, amounts: Vec<U128>) {
+        let tokens = self.internal_unwrap_pool(pool_id).tokens();
+        let mut results = (0..env::promise_results_count()).map(env::promise_result);
+        for (token_id, amount) in tokens.iter().zip(amounts).filter(|(_, amount)| amount.0 > 0) {
+            self.internal_end_transfer(token_id);
+            if !matches!(results.next(), Some(PromiseResult::Successful(_))) {
+                log!("on_remove_position: the payout of {} {} to {} failed", amount.0, token_id, account_id);
+                self.internal_add_pending("remove_position", &account_id, token_id, amount.0);
+            }
+        }
+    }
+
+    // Returns the price state of the concentrated pool
+    pub fn get_concentrated_pool_info(&self, pool_id: u64) -> ConcentratedPoolInfo {
+        let pool = self.internal_unwrap_pool(pool_id);
+        let pool = pool.as_concentrated();
+        ConcentratedPoolInfo {
+            sqrt_price_x96: pool.sqrt_price.to_string(),
+            tick: pool.tick,
+            tick_spacing: pool.tick_spacing,
+            liquidity: pool.liquidity.into(),
+        }
+    }
+
+    // Returns the position of the concentrated pool with the fees accrued so far
+    pub fn get_position(&self, pool_id: u64, position_id: u64) -> PositionInfo {
+        self.internal_unwrap_pool(pool_id).as_concentrated().position_info(position_id)
+    }
+
+    // Returns all the positions of the account in the concentrated pool
+    pub fn get_positions(&self, pool_id: u64, account_id: AccountId) -> Vec<PositionInfo> {
+        let pool = self.internal_unwrap_pool(pool_id);
+        let pool = pool.as_concentrated();
+        pool.account_positions
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|position_id| pool.position_info(position_id))
+            .collect()
+    }
+}
@@ -0,0 +1,170 @@
+//! Internal balances of the users in the AMM.
+//!
+//! The tokens are deposited with `ft_transfer_call`, swapped against the pools without any
+//! cross-contract calls and withdrawn with `ft_transfer`.
+
+use std::collections::BTreeMap;
+
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, require, AccountId, Balance, Promise, PromiseResult};
+
+use crate::external::ext_self;
+use crate::router::SwapHop;
+use crate::*;
+
+impl Contract {
+    /// Returns the internal balance of the token of the account.
+    pub(crate) fn internal_deposit_of(&self, account_id: &AccountId, token_id: &AccountId) -> Balance {
+        self.deposits
+            .get(account_id)
+            .and_then(|deposits| deposits.get(token_id).copied())
+            .unwrap_or(0)
+    }
+
+    /// Adds `amount` of the token to the internal balance of the account.
+    pub(crate) fn internal_deposit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let balance = deposits.entry(token_id.clone()).or_insert(0);
+        *balance = balance
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Balance overflow"));
+        self.deposits.insert(account_id, &deposits);
+        self.internal_add_total(token_id, amount);
+    }
+
+    /// Removes `amount` of the token from the internal balance of the account, panics if the balance is not enough.
+    pub(crate) fn internal_withdraw(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let mut deposits = self.deposits.get(account_id).unwrap_or_default();
+        let balance = deposits.get(token_id).copied().unwrap_or(0);
+        require!(
+            balance >= amount,
+            format!("Not enough deposit of {}: {} is less than {}", token_id, balance, amount)
+        );
+
+        if balance == amount {
+            deposits.remove(token_id);
+        } else {
+            deposits.insert(token_id.clone(), balance - amount);
+        }
+        if deposits.is_empty() {
+            self.deposits.remove(account_id);
+        } else {
+            self.deposits.insert(account_id, &deposits);
+        }
+        self.internal_sub_total(token_id, amount);
+    }
+
+    /// Sends `amount` of the token owned by the account with `ft_transfer`, the tokens are added to the deposit
+    /// of the account if the transfer fails.
+    pub(crate) fn internal_send(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance, memo: &str) -> Promise {
+        self.internal_ft_transfer(token_id, account_id, amount, memo.to_string())
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_withdraw(account_id.clone(), token_id.clone(), amount.into()),
+            )
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Exchanges `amount_in` of the deposit of `token_in` to `token_out` in the pool `pool_id`,
+    /// the output is added to the deposit of the caller. `token_out` is required for the pools of more than two tokens.
+    /// The call fails if it pays out less than `min_amount_out` or the block timestamp is beyond `deadline` (in nanoseconds).
+    /// Returns the amount of `token_out`.
+    pub fn swap(
+        &mut self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        token_out: Option<AccountId>,
+        min_amount_out: U128,
+        deadline: Option<U64>,
+    ) -> U128 {
+        require!(amount_in.0 > 0, "The amount should be a positive number");
+        require!(!internal::is_deadline_passed(deadline), "swap: the deadline has passed");
+
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+        let token_out = token_out.unwrap_or_else(|| self.internal_unwrap_pool(pool_id).opposite_token(&token_in));
+
+        self.internal_withdraw(&account_id, &token_in, amount_in.0);
+        let (amount_out, _) = self.internal_swap(pool_id, &account_id, &token_in, amount_in.0, &token_out);
+        require!(
+            amount_out >= min_amount_out.0,
+            format!("swap: amount out {} is less than min amount out {}", amount_out, min_amount_out.0)
+        );
+        self.internal_deposit(&account_id, &token_out, amount_out);
+        self.internal_charge_storage(&account_id, initial_bytes);
+
+        log!("swap: {} swapped {} {} to {} {}", account_id, amount_in.0, token_in, amount_out, token_out);
+        amount_out.into()
+    }
+
+    /// Exchanges `amount_in` of the deposit of the input token of the first hop through the pools of the `hops`,
+    /// the output of the last hop is added to the deposit of the caller.
+    /// The call fails if it pays out less than `min_amount_out` or the block timestamp is beyond `deadline` (in nanoseconds).
+    /// Returns the amount of the output token of the last hop.
+    pub fn swap_route(&mut self, hops: Vec<SwapHop>, amount_in: U128, min_amount_out: U128, deadline: Option<U64>) -> U128 {
+        require!(amount_in.0 > 0, "The amount should be a positive number");
+        require!(!internal::is_deadline_passed(deadline), "swap_route: the deadline has passed");
+        require!(!hops.is_empty(), "The route should have at least one hop");
+
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+        let token_in = hops[0].token_in.clone();
+
+        self.internal_withdraw(&account_id, &token_in, amount_in.0);
+        let results = self.internal_swap_route(&account_id, &token_in, amount_in.0, &hops);
+        let last = results.last().unwrap();
+        require!(
+            last.amount_out.0 >= min_amount_out.0,
+            format!("swap_route: amount out {} is less than min amount out {}", last.amount_out.0, min_amount_out.0)
+        );
+        self.internal_deposit(&account_id, &last.token_out, last.amount_out.0);
+        self.internal_charge_storage(&account_id, initial_bytes);
+
+        log!("swap_route: {} swapped {} {} to {} {}", account_id, amount_in.0, token_in, last.amount_out.0, last.token_out);
+        last.amount_out
+    }
+
+    /// Sends `amount` of the deposit of the token to the caller, the deposit is restored if the transfer fails.
+    /// Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn withdraw(&mut self, token_id: AccountId, amount: U128) -> Promise {
+        assert_one_yocto();
+        require!(amount.0 > 0, "The amount should be a positive number");
+
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+        self.internal_withdraw(&account_id, &token_id, amount.0);
+        self.internal_charge_storage(&account_id, initial_bytes);
+
+        self.internal_send(&account_id, &token_id, amount.0, "AMM withdraw")
+    }
+
+    /// Restores the deposit if the transfer of the withdrawal failed.
+    #[private]
+    pub fn on_withdraw(&mut self, account_id: AccountId, token_id: AccountId, amount: U128) {
+        self.internal_end_transfer(&token_id);
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!("on_withdraw: the transfer of {} {} to {} failed, restoring the deposit", amount.0, token_id, account_id);
+            self.internal_refund_to_deposit("withdraw", &account_id, &token_id, amount.0);
+        }
+    }
+
+    // Returns the internal balance of the token of the account
+    pub fn get_deposit(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        self.internal_deposit_of(&account_id, &token_id).into()
+    }
+
+    // Returns all the internal balances of the account
+    pub fn get_deposits(&self, account_id: AccountId) -> BTreeMap<AccountId, U128> {
+        self.deposits
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+}
@@ -0,0 +1,307 @@
+//! Events of the AMM in the NEP-297 format, logged as `EVENT_JSON:` lines for the indexers.
+//!
+//! The LP shares of the main pool are the NEP-141 token of the contract, their mints, transfers and burns are logged
+//! under the `nep141` standard: [`FtMint`], [`FtTransfer`] and [`FtBurn`]. The actions of the AMM are logged under the
+//! `amm` standard: [`PoolCreated`], [`Swap`], [`AddLiquidity`], [`RemoveLiquidity`], [`FeeChanged`], [`Paused`],
+//! [`ReservesSynced`], [`ReservesRestored`], [`TransferFailed`] and [`FlashLoan`].
+//!
+//! The events of the pools carry the reserves of the pool after the action. The actions are final, a payout that
+//! fails in a later receipt is logged by its callback as [`TransferFailed`]. The reserve lent by a flash loan is
+//! returned by its callback, which logs the reserves after the return as [`ReservesRestored`].
+
+use near_sdk::json_types::U128;
+use near_sdk::AccountId;
+use near_sdk::serde::Serialize;
+
+use near_sdk::env;
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "standard")]
+#[must_use = "don't forget to `.emit()` this event"]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum NearEvent<'a> {
+    Nep141(Nep141Event<'a>),
+    Amm(AmmEvent<'a>),
+}
+
+impl<'a> NearEvent<'a> {
+    fn to_json_string(&self) -> String {
+        // Events cannot fail to serialize so fine to panic on error
+        #[allow(clippy::redundant_closure)]
+        serde_json::to_string(self).ok().unwrap_or_else(|| env::abort())
+    }
+
+    fn to_json_event_string(&self) -> String {
+        format!("EVENT_JSON:{}", self.to_json_string())
+    }
+
+    pub(crate) fn emit(self) {
+        near_sdk::env::log_str(&self.to_json_event_string());
+    }
+}
+
+/// Implements `emit` and `emit_many` of an event, `$new` wraps the variant `$kind` into the event of its standard.
+macro_rules! impl_emit {
+    ($event:ident, $new:ident, $kind:path) => {
+        impl $event<'_> {
+            /// Logs the event to the host with [`env::log_str`](near_sdk::env::log_str). An event that is not
+            /// emitted is not logged, hence `#[must_use]`.
+            pub fn emit(self) {
+                Self::emit_many(&[self])
+            }
+
+            /// Logs the events as a single event whose data lists them.
+            pub fn emit_many(data: &[$event<'_>]) {
+                $new($kind(data)).emit()
+            }
+        }
+    };
+}
+
+
+/// Data to log for an FT mint event.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct FtMint<'a> {
+    pub owner_id: &'a AccountId,
+    pub amount: &'a U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<&'a str>,
+}
+
+impl_emit!(FtMint, new_141_v1, Nep141EventKind::FtMint);
+
+/// Data to log for an FT transfer event.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct FtTransfer<'a> {
+    pub old_owner_id: &'a AccountId,
+    pub new_owner_id: &'a AccountId,
+    pub amount: &'a U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<&'a str>,
+}
+
+impl_emit!(FtTransfer, new_141_v1, Nep141EventKind::FtTransfer);
+
+/// Data to log for an FT burn event.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct FtBurn<'a> {
+    pub owner_id: &'a AccountId,
+    pub amount: &'a U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<&'a str>,
+}
+
+impl_emit!(FtBurn, new_141_v1, Nep141EventKind::FtBurn);
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Nep141Event<'a> {
+    version: &'static str,
+    #[serde(flatten)]
+    event_kind: Nep141EventKind<'a>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum Nep141EventKind<'a> {
+    FtMint(&'a [FtMint<'a>]),
+    FtTransfer(&'a [FtTransfer<'a>]),
+    FtBurn(&'a [FtBurn<'a>]),
+}
+
+fn new_141<'a>(version: &'static str, event_kind: Nep141EventKind<'a>) -> NearEvent<'a> {
+    NearEvent::Nep141(Nep141Event { version, event_kind })
+}
+
+fn new_141_v1(event_kind: Nep141EventKind) -> NearEvent {
+    new_141("1.0.0", event_kind)
+}
+
+/// Data to log when a pool is registered.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct PoolCreated<'a> {
+    pub pool_id: u64,
+    /// The pool creator
+    pub account_id: &'a AccountId,
+    pub pool_kind: &'a str,
+    pub tokens: &'a [AccountId],
+    pub fee_bps: u32,
+}
+
+impl_emit!(PoolCreated, new_amm_v1, AmmEventKind::PoolCreated);
+
+/// Data to log for a swap in a pool.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct Swap<'a> {
+    pub pool_id: u64,
+    /// The account that receives the output
+    pub account_id: &'a AccountId,
+    pub token_in: &'a AccountId,
+    pub amount_in: &'a U128,
+    pub token_out: &'a AccountId,
+    pub amount_out: &'a U128,
+    /// The reserves of the pool after the action, in the order of the tokens of the pool
+    pub reserves: &'a [U128],
+}
+
+impl_emit!(Swap, new_amm_v1, AmmEventKind::Swap);
+
+/// Data to log when liquidity is added to a pool.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct AddLiquidity<'a> {
+    pub pool_id: u64,
+    pub account_id: &'a AccountId,
+    /// The amounts of the tokens taken into the reserves, in the order of the tokens of the pool
+    pub amounts: &'a [U128],
+    /// The minted LP shares, the liquidity of a position of a concentrated pool
+    pub shares: &'a U128,
+    /// The position of a concentrated pool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_id: Option<u64>,
+    /// The reserves of the pool after the action, in the order of the tokens of the pool
+    pub reserves: &'a [U128],
+}
+
+impl_emit!(AddLiquidity, new_amm_v1, AmmEventKind::AddLiquidity);
+
+/// Data to log when liquidity is removed from a pool.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct RemoveLiquidity<'a> {
+    pub pool_id: u64,
+    pub account_id: &'a AccountId,
+    /// The amounts of the tokens paid out, in the order of the tokens of the pool
+    pub amounts: &'a [U128],
+    /// The burned LP shares, the liquidity of a position of a concentrated pool
+    pub shares: &'a U128,
+    /// The position of a concentrated pool
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_id: Option<u64>,
+    /// The reserves of the pool after the action, in the order of the tokens of the pool
+    pub reserves: &'a [U128],
+}
+
+impl_emit!(RemoveLiquidity, new_amm_v1, AmmEventKind::RemoveLiquidity);
+
+/// Data to log when the fee of a pool is changed.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct FeeChanged<'a> {
+    pub pool_id: u64,
+    /// The fee manager
+    pub account_id: &'a AccountId,
+    pub fee_bps: u32,
+    pub protocol_share_bps: u32,
+}
+
+impl_emit!(FeeChanged, new_amm_v1, AmmEventKind::FeeChanged);
+
+/// Data to log when the reserves of a pool are synced with the balances of the AMM.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct ReservesSynced<'a> {
+    pub pool_id: u64,
+    /// The keeper
+    pub account_id: &'a AccountId,
+    /// The reserves of the pool after the action, in the order of the tokens of the pool
+    pub reserves: &'a [U128],
+}
+
+impl_emit!(ReservesSynced, new_amm_v1, AmmEventKind::Sync);
+
+/// Data to log when a callback returns the reserve lent by a flash loan to the pool.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct ReservesRestored<'a> {
+    pub pool_id: u64,
+    /// The method whose reserve is returned
+    pub action: &'a str,
+    /// The reserves of the pool after the action, in the order of the tokens of the pool
+    pub reserves: &'a [U128],
+}
+
+impl_emit!(ReservesRestored, new_amm_v1, AmmEventKind::ReservesRestored);
+
+/// Data to log when a transfer of tokens to or from the AMM fails in a callback.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct TransferFailed<'a> {
+    /// The method that started the transfer
+    pub action: &'a str,
+    pub account_id: &'a AccountId,
+    pub token_id: &'a AccountId,
+    pub amount: &'a U128,
+    /// How the failure is resolved
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<&'a str>,
+}
+
+impl_emit!(TransferFailed, new_amm_v1, AmmEventKind::TransferFailed);
+
+/// Data to log when the trading of a pool or of the whole AMM is paused or resumed.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct Paused<'a> {
+    /// The pool, none for the whole AMM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool_id: Option<u64>,
+    pub paused: bool,
+    /// The pauser, none if the pool is paused by its circuit breaker
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<&'a AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<&'a str>,
+}
+
+impl_emit!(Paused, new_amm_v1, AmmEventKind::Paused);
+
+/// Data to log when a flash loan is resolved.
+#[must_use]
+#[derive(Serialize, Debug, Clone)]
+pub struct FlashLoan<'a> {
+    pub loan_id: u64,
+    pub pool_id: u64,
+    /// The borrower
+    pub account_id: &'a AccountId,
+    pub receiver_id: &'a AccountId,
+    pub token_id: &'a AccountId,
+    pub amount: &'a U128,
+    pub fee: &'a U128,
+    pub repaid: bool,
+}
+
+impl_emit!(FlashLoan, new_amm_v1, AmmEventKind::FlashLoan);
+
+#[derive(Serialize, Debug)]
+pub(crate) struct AmmEvent<'a> {
+    version: &'static str,
+    #[serde(flatten)]
+    event_kind: AmmEventKind<'a>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+enum AmmEventKind<'a> {
+    PoolCreated(&'a [PoolCreated<'a>]),
+    Swap(&'a [Swap<'a>]),
+    AddLiquidity(&'a [AddLiquidity<'a>]),
+    RemoveLiquidity(&'a [RemoveLiquidity<'a>]),
+    FeeChanged(&'a [FeeChanged<'a>]),
+    Paused(&'a [Paused<'a>]),
+    Sync(&'a [ReservesSynced<'a>]),
+    ReservesRestored(&'a [ReservesRestored<'a>]),
+    TransferFailed(&'a [TransferFailed<'a>]),
+    FlashLoan(&'a [FlashLoan<'a>]),
+}
+
+fn new_amm_v1(event_kind: AmmEventKind) -> NearEvent {
+    NearEvent::Amm(AmmEvent { version: "1.0.0", event_kind })
+}
@@ -1,17 +1,99 @@
-use near_sdk::{ext_contract, AccountId};
+use near_sdk::json_types::U128;
+use near_sdk::{ext_contract, AccountId, Balance, PromiseError};
 
+use crate::flash_loan::Loan;
 use crate::metadata::*;
 
 #[ext_contract(ext_token)]
 pub trait ExtToken {
-    fn get_metadata(&self) -> FungibleTokenMetadata;
-    // fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128);
+    fn create_wallet(&mut self, sender_id: AccountId, amount: Balance);
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+    fn ft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>);
+    fn transfer_from(&mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: Balance);
+}
+
+#[ext_contract(ext_flash_loan_receiver)]
+pub trait FlashLoanReceiver {
+    fn on_flash_loan(
+        &mut self,
+        loan: Loan,
+        msg: String);
 }
 
 #[ext_contract(ext_self)]
 pub trait ExtSelf {
     fn on_get_metadata(
         &mut self,
-        contract_id: AccountId, 
-        #[callback] metadata: FungibleTokenMetadata);
+        contract_id: AccountId,
+        #[callback_result] metadata: Result<FungibleTokenMetadata, PromiseError>);
+    fn on_remove_liquidity(
+        &mut self,
+        pool_id: u64,
+        account_id: AccountId,
+        amounts: Vec<U128>,
+    );
+    fn on_swap_payout(
+        &mut self,
+        sender_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    ) -> U128;
+    fn on_withdraw_protocol_fees(
+        &mut self,
+        pool_id: u64,
+        amounts: Vec<U128>,
+    );
+    fn on_remove_position(
+        &mut self,
+        pool_id: u64,
+        account_id: AccountId,
+        amounts: Vec<U128>,
+    );
+    fn on_withdraw(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    );
+    fn on_claim_pending(
+        &mut self,
+        account_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+    );
+    fn on_sync(
+        &mut self,
+        pool_id: u64,
+        account_id: AccountId,
+        totals: Vec<U128>,
+    );
+    fn on_skim(
+        &mut self,
+        token_ids: Vec<AccountId>,
+        totals: Vec<U128>,
+        receiver_id: AccountId,
+    );
+    fn on_skim_sent(
+        &mut self,
+        token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    );
+    fn on_flash_loan_sent(
+        &mut self,
+        loan: Loan,
+        msg: String,
+    );
+    fn on_flash_loan_resolved(
+        &mut self,
+        loan: Loan,
+    ) -> bool;
 }
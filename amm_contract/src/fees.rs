@@ -0,0 +1,111 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, require, PromiseResult};
+
+use crate::events::{FeeChanged, TransferFailed};
+use crate::external::ext_self;
+use crate::*;
+
+/// Swap fee of a new pool, 0.3%
+pub const DEFAULT_FEE_BPS: u32 = 30;
+/// The highest swap fee the owner can set, 10%
+pub const MAX_FEE_BPS: u32 = 1_000;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeInfo {
+    /// Swap fee in basis points
+    pub fee_bps: u32,
+    /// Part of the swap fee in basis points of the fee that goes to the owner
+    pub protocol_share_bps: u32,
+    /// Accrued protocol fees in the order of the tokens of the pool
+    pub protocol_fees: Vec<U128>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the swap fee of the pool and the protocol part of it, requires the role `FeeManager`.
+    pub fn set_fee(&mut self, pool_id: u64, fee_bps: u32, protocol_share_bps: u32) {
+        self.internal_assert_role(Role::FeeManager);
+        require!(fee_bps <= MAX_FEE_BPS, format!("The fee can't exceed {} basis points", MAX_FEE_BPS));
+        require!(protocol_share_bps <= math::FEE_DIVISOR, format!("The protocol share can't exceed {} basis points", math::FEE_DIVISOR));
+
+        log!("set_fee: pool_id: {} fee_bps: {} protocol_share_bps: {}", pool_id, fee_bps, protocol_share_bps);
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        pool.set_fee(fee_bps, protocol_share_bps);
+        self.internal_save_pool(pool_id, &pool);
+        FeeChanged {
+            pool_id,
+            account_id: &env::predecessor_account_id(),
+            fee_bps,
+            protocol_share_bps,
+        }
+        .emit();
+    }
+
+    // Returns the fee settings and the accrued protocol fees of the pool
+    pub fn get_fee_info(&self, pool_id: u64) -> FeeInfo {
+        let pool = self.internal_unwrap_pool(pool_id);
+        FeeInfo {
+            fee_bps: pool.fee_bps(),
+            protocol_share_bps: pool.protocol_share_bps(),
+            protocol_fees: pool.protocol_fees().into_iter().map(U128).collect(),
+        }
+    }
+
+    /// Sends the accrued protocol fees of all the tokens of the pool to the owner, requires the role `FeeManager`.
+    pub fn withdraw_protocol_fees(&mut self, pool_id: u64) {
+        self.internal_assert_role(Role::FeeManager);
+
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        let (tokens, amounts) = (pool.tokens(), pool.protocol_fees());
+        require!(amounts.iter().any(|a| *a > 0), "There are no protocol fees to withdraw");
+        for (token_id, amount) in tokens.iter().zip(&amounts) {
+            pool.sub_protocol_fee(token_id, *amount);
+        }
+        self.internal_save_pool(pool_id, &pool);
+
+        // Transfers of zero amounts are rejected by the token contracts, so they are skipped
+        tokens
+            .iter()
+            .zip(&amounts)
+            .filter(|(_, amount)| **amount > 0)
+            .map(|(token_id, amount)| {
+                let owner_id = self.owner_id.clone();
+                self.internal_ft_transfer(token_id, &owner_id, *amount, "AMM protocol fee".to_string())
+            })
+            .reduce(|payouts, payout| payouts.and(payout))
+            .unwrap()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_withdraw_protocol_fees(pool_id, amounts.into_iter().map(U128).collect()),
+            );
+    }
+
+    /// Restores the accrued protocol fees whose transfer to the owner failed.
+    #[private]
+    pub fn on_withdraw_protocol_fees(&mut self, pool_id: u64, amounts: Vec<U128>) {
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        let mut results = (0..env::promise_results_count()).map(env::promise_result);
+        for (token_id, amount) in pool.tokens().iter().zip(amounts) {
+            if amount.0 == 0 {
+                continue;
+            }
+            self.internal_end_transfer(token_id);
+            if !matches!(results.next(), Some(PromiseResult::Successful(_))) {
+                log!("on_withdraw_protocol_fees: the transfer of {} {} failed, restoring the fee", amount.0, token_id);
+                pool.add_protocol_fee(token_id, amount.0);
+                TransferFailed {
+                    action: "withdraw_protocol_fees",
+                    account_id: &self.owner_id,
+                    token_id,
+                    amount: &amount,
+                    memo: Some("the fee is restored"),
+                }
+                .emit();
+            }
+        }
+        self.internal_save_pool(pool_id, &pool);
+    }
+}
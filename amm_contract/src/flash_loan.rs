@@ -0,0 +1,243 @@
+//! Flash loans of the reserves of the pools.
+//!
+//! The borrowed amount is sent to the receiver, then the receiver is called with `on_flash_loan` and has to return
+//! the promise of the repayment: `ft_transfer_call` of the amount and the fee to the AMM with the message of the
+//! `repay_flash_loan` action that names the loan. Only the tokens received this way count, so the transfers of the
+//! AMM in flight can't pass for a repayment. The borrower escrows the amount and the fee from its deposit as the
+//! collateral of the loan, and the pool is locked while the loan is in flight. The reserve is always restored with
+//! the amount and the fee: the part of the repayment that is not received is taken from the collateral, the rest
+//! of the collateral returns to the deposit of the borrower.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance, Gas, Promise, PromiseOrValue, PromiseResult};
+
+use crate::events::{FlashLoan, ReservesRestored, TransferFailed};
+use crate::external::{ext_flash_loan_receiver, ext_self};
+use crate::pool::Pool;
+use crate::*;
+
+/// Fee of a flash loan, 0.09% of the amount
+pub const FLASH_LOAN_FEE_BPS: u32 = 9;
+
+/// Gas for `on_flash_loan` of the receiver, including the repayment
+const GAS_FOR_ON_FLASH_LOAN: Gas = Gas(50_000_000_000_000);
+/// Gas for `on_flash_loan_sent`: the call of the receiver and the resolve
+const GAS_FOR_FLASH_LOAN_SENT: Gas = Gas(80_000_000_000_000);
+
+/// A flash loan in flight, passed to the receiver and between the callbacks.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Loan {
+    pub loan_id: u64,
+    pub pool_id: u64,
+    /// The borrower
+    pub account_id: AccountId,
+    pub token_id: AccountId,
+    pub amount: U128,
+    pub fee: U128,
+    pub receiver_id: AccountId,
+}
+
+/// The repayment of the flash loan in flight of a pool.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OpenLoan {
+    pub loan_id: u64,
+    pub token_id: AccountId,
+    /// The amount and the fee
+    pub owed: Balance,
+    pub repaid: Balance,
+    /// The tokens escrowed from the deposit of the borrower until the loan is resolved
+    pub collateral: Balance,
+}
+
+/// Returns the fee of the loan of `amount`, rounded up.
+pub fn flash_loan_fee(amount: Balance) -> Balance {
+    let divisor = Balance::from(math::FEE_DIVISOR);
+    (amount * Balance::from(FLASH_LOAN_FEE_BPS)).div_ceil(divisor)
+}
+
+impl Contract {
+    pub(crate) fn internal_assert_no_flash_loan(&self, pool_id: u64) {
+        require!(!self.flash_loans.contains_key(&pool_id), format!("Pool {} has a flash loan in flight", pool_id));
+    }
+
+    /// Accepts `amount` of the token received with `ft_transfer_call` as the repayment of the loan in flight
+    /// of the pool. Returns the amount above what is owed, it is refunded to the sender.
+    pub(crate) fn internal_repay_flash_loan(&mut self, pool_id: u64, loan_id: u64, token_id: &AccountId, amount: Balance) -> Balance {
+        let open = self.flash_loans.get(&pool_id);
+        require!(
+            open.as_ref().is_some_and(|open| open.loan_id == loan_id),
+            format!("Flash loan {} of pool {} is not in flight", loan_id, pool_id)
+        );
+        let mut open = open.unwrap();
+        require!(open.token_id == *token_id, format!("The flash loan is repaid in {}", open.token_id));
+
+        let accepted = amount.min(open.owed - open.repaid);
+        open.repaid += accepted;
+        self.flash_loans.insert(&pool_id, &open);
+        // The repayment is owed to the pool until the loan is resolved
+        self.internal_add_total(token_id, accepted);
+        amount - accepted
+    }
+
+    /// Returns `amount` to the reserve of the token without recording the prices, the reserve was lowered
+    /// by the loan, and unlocks the pool. The reserves are logged, the loan itself changes them without an event.
+    fn internal_restore_reserve(&mut self, pool_id: u64, pool: &mut Pool, token_id: &AccountId, amount: Balance) {
+        let index = pool.tokens().iter().position(|t| t == token_id).unwrap();
+        pool.set_reserve(token_id, pool.reserves()[index] + amount);
+        self.internal_replace_pool(pool_id, pool);
+        self.flash_loans.remove(&pool_id);
+        ReservesRestored { pool_id, action: "flash_loan", reserves: &internal::reserves_of(pool) }.emit();
+    }
+
+    /// Releases the escrowed collateral of the loan and adds `amount` of it to the deposit of the borrower,
+    /// the records added by the callback are tracked.
+    fn internal_return_collateral(&mut self, loan: &Loan, collateral: Balance, amount: Balance) {
+        self.internal_sub_total(&loan.token_id, collateral);
+        if amount > 0 {
+            let initial_bytes = self.internal_records_bytes(&loan.account_id);
+            self.internal_deposit(&loan.account_id, &loan.token_id, amount);
+            self.internal_track_storage(&loan.account_id, initial_bytes);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Lends `amount` of the reserve of the token in the pool to `receiver_id` and calls `on_flash_loan` of it
+    /// with `msg`. The receiver has to return the amount and the fee of `FLASH_LOAN_FEE_BPS` within the call with
+    /// `ft_transfer_call` and the `repay_flash_loan` message of the loan, the fee goes to the reserve.
+    /// The amount and the fee are escrowed from the deposit of the caller and cover the part that is not repaid.
+    /// Returns true if the loan is repaid. The pool is locked until the loan is resolved.
+    pub fn flash_loan(&mut self, pool_id: u64, token_id: AccountId, amount: U128, receiver_id: AccountId, msg: String) -> Promise {
+        self.internal_assert_pool_not_paused(pool_id);
+        self.internal_assert_no_flash_loan(pool_id);
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        require!(
+            !matches!(pool, Pool::ConcentratedPool(_)),
+            "The reserves of a concentrated pool are defined by its positions"
+        );
+        let index = pool
+            .tokens()
+            .iter()
+            .position(|t| *t == token_id)
+            .unwrap_or_else(|| env::panic_str(format!("Unsupported token contract id: {}", token_id).as_str()));
+        let reserve = pool.reserves()[index];
+        require!(
+            amount.0 > 0 && amount.0 < reserve,
+            format!("The amount should be positive and less than the reserve {}", reserve)
+        );
+
+        // The collateral is taken from the deposit, the AMM still owes it to the loan
+        let account_id = env::predecessor_account_id();
+        let owed = amount.0 + flash_loan_fee(amount.0);
+        let initial_bytes = self.internal_records_bytes(&account_id);
+        self.internal_withdraw(&account_id, &token_id, owed);
+        self.internal_add_total(&token_id, owed);
+        self.internal_charge_storage(&account_id, initial_bytes);
+
+        // The prices are not recorded, the reserve is restored once the loan is resolved
+        pool.set_reserve(&token_id, reserve - amount.0);
+        self.internal_replace_pool(pool_id, &pool);
+
+        let loan_id = self.next_loan_id;
+        self.next_loan_id += 1;
+        let fee = owed - amount.0;
+        self.flash_loans.insert(
+            &pool_id,
+            &OpenLoan { loan_id, token_id: token_id.clone(), owed, repaid: 0, collateral: owed },
+        );
+
+        let loan = Loan {
+            loan_id,
+            pool_id,
+            account_id,
+            token_id,
+            amount,
+            fee: fee.into(),
+            receiver_id,
+        };
+        log!(
+            "flash_loan: loan {} of {} of {} from pool {} to {} with fee {}",
+            loan_id, amount.0, loan.token_id, pool_id, loan.receiver_id, fee
+        );
+        self.internal_ft_transfer(&loan.token_id, &loan.receiver_id, amount.0, format!("AMM flash loan of pool {}", pool_id))
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_FLASH_LOAN_SENT)
+                    .on_flash_loan_sent(loan, msg),
+            )
+    }
+
+    #[private]
+    pub fn on_flash_loan_sent(&mut self, loan: Loan, msg: String) -> PromiseOrValue<bool> {
+        self.internal_end_transfer(&loan.token_id);
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            let mut pool = self.internal_unwrap_pool(loan.pool_id);
+            let collateral = self.flash_loans.get(&loan.pool_id).unwrap().collateral;
+            self.internal_restore_reserve(loan.pool_id, &mut pool, &loan.token_id, loan.amount.0);
+            self.internal_return_collateral(&loan, collateral, collateral);
+            TransferFailed {
+                action: "flash_loan",
+                account_id: &loan.receiver_id,
+                token_id: &loan.token_id,
+                amount: &loan.amount,
+                memo: Some("the loan is cancelled"),
+            }
+            .emit();
+            return PromiseOrValue::Value(false);
+        }
+
+        ext_flash_loan_receiver::ext(loan.receiver_id.clone())
+            .with_static_gas(GAS_FOR_ON_FLASH_LOAN)
+            .on_flash_loan(loan.clone(), msg)
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_flash_loan_resolved(loan),
+            )
+            .into()
+    }
+
+    #[private]
+    pub fn on_flash_loan_resolved(&mut self, loan: Loan) -> bool {
+        let pool_id = loan.pool_id;
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        let open = self.flash_loans.get(&pool_id).unwrap();
+        let repaid = open.repaid >= open.owed;
+
+        // The reserve gets the amount and the fee, the received repayment is moved there from the totals
+        // and the collateral covers the rest
+        let shortfall = open.owed - open.repaid;
+        self.internal_sub_total(&loan.token_id, open.repaid);
+        self.internal_restore_reserve(pool_id, &mut pool, &loan.token_id, open.owed);
+        self.internal_save_pool(pool_id, &pool);
+        self.internal_return_collateral(&loan, open.collateral, open.collateral - shortfall);
+        if !repaid {
+            log!(
+                "on_flash_loan_resolved: the loan {} of pool {} is repaid {} of {}, {} is taken from the collateral",
+                loan.loan_id, pool_id, open.repaid, open.owed, shortfall
+            );
+        }
+
+        FlashLoan {
+            loan_id: loan.loan_id,
+            pool_id,
+            account_id: &loan.account_id,
+            receiver_id: &loan.receiver_id,
+            token_id: &loan.token_id,
+            amount: &loan.amount,
+            fee: &loan.fee,
+            repaid,
+        }
+        .emit();
+        repaid
+    }
+
+    // Returns the fee of a flash loan of `amount`
+    pub fn get_flash_loan_fee(&self, amount: U128) -> U128 {
+        flash_loan_fee(amount.0).into()
+    }
+}
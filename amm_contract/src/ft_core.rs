@@ -0,0 +1,210 @@
+//! NEP-141 interface of the LP shares token of the AMM.
+
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, ext_contract, near_bindgen, AccountId, Balance, Gas, PromiseOrValue, PromiseResult};
+
+use crate::token_receiver::ext_ft_receiver;
+use crate::*;
+
+const GAS_FOR_RESOLVE_TRANSFER: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER.0);
+
+#[ext_contract(ext_ft_core)]
+pub trait FungibleTokenCore {
+    /// Transfers positive `amount` of tokens from the `env::predecessor_account_id` to `receiver_id`.
+    /// Both accounts must be registered with the contract for transfer to succeed. (See [NEP-145](https://github.com/near/NEPs/discussions/145))
+    /// This method must to be able to accept attached deposits, and must not panic on attached deposit.
+    /// Exactly 1 yoctoNEAR must be attached.
+    /// See [the Security section](https://github.com/near/NEPs/issues/141#user-content-security) of the standard.
+    ///
+    /// Arguments:
+    /// - `receiver_id` - the account ID of the receiver.
+    /// - `amount` - the amount of tokens to transfer. Must be a positive number in decimal string representation.
+    /// - `memo` - an optional string field in a free form to associate a memo with this transfer.
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+
+    /// Transfers positive `amount` of tokens from the `env::predecessor_account_id` to `receiver_id` account. Then
+    /// calls `ft_on_transfer` method on `receiver_id` contract and attaches a callback to resolve this transfer.
+    /// `ft_on_transfer` method must return the amount of tokens unused by the receiver contract, the remaining tokens
+    /// must be refunded to the `predecessor_account_id` at the resolve transfer callback.
+    ///
+    /// Token contract must pass all the remaining unused gas to the `ft_on_transfer` call.
+    ///
+    /// Malicious or invalid behavior by the receiver's contract:
+    /// - If the receiver contract promise fails or returns invalid value, the full transfer amount must be refunded.
+    /// - If the receiver contract overspent the tokens, and the `receiver_id` balance is lower than the required refund
+    /// amount, the remaining balance must be refunded. See [the Security section](https://github.com/near/NEPs/issues/141#user-content-security) of the standard.
+    ///
+    /// Both accounts must be registered with the contract for transfer to succeed. (See #145)
+    /// This method must to be able to accept attached deposits, and must not panic on attached deposit. Exactly 1 yoctoNEAR must be attached. See [the Security
+    /// section](https://github.com/near/NEPs/issues/141#user-content-security) of the standard.
+    ///
+    /// Arguments:
+    /// - `receiver_id` - the account ID of the receiver contract. This contract will be called.
+    /// - `amount` - the amount of tokens to transfer. Must be a positive number in a decimal string representation.
+    /// - `memo` - an optional string field in a free form to associate a memo with this transfer.
+    /// - `msg` - a string message that will be passed to `ft_on_transfer` contract call.
+    ///
+    /// Returns a promise which will result in the amount of tokens withdrawn from sender's account.
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+
+    /// Returns the total supply of the token in a decimal string representation.
+    fn ft_total_supply(&self) -> U128;
+
+    /// Returns the balance of the account. If the account doesn't exist must returns `"0"`.
+    fn ft_balance_of(&self, account_id: AccountId) -> U128;
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        // Assert that the user attached exactly 1 yoctoNEAR. This is for security and so that the user will be required to sign with a FAK.
+        assert_one_yocto();
+        // The sender is the user who called the method
+        let sender_id = env::predecessor_account_id();
+        // The receiver has to be registered, it pays for its record of the shares
+        self.internal_assert_registered(&receiver_id);
+        let initial_bytes = self.internal_records_bytes(&sender_id);
+        let receiver_initial_bytes = self.internal_records_bytes(&receiver_id);
+        // Transfer the shares
+        self.internal_transfer_shares(MAIN_POOL_ID, &sender_id, &receiver_id, amount.0, memo);
+        self.internal_charge_storage(&sender_id, initial_bytes);
+        self.internal_charge_storage(&receiver_id, receiver_initial_bytes);
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        // Assert that the user attached exactly 1 yoctoNEAR. This is for security and so that the user will be required to sign with a FAK.
+        assert_one_yocto();
+        // The sender is the user who called the method
+        let sender_id = env::predecessor_account_id();
+        // The receiver has to be registered, it pays for its record of the shares
+        self.internal_assert_registered(&receiver_id);
+        let initial_bytes = self.internal_records_bytes(&sender_id);
+        let receiver_initial_bytes = self.internal_records_bytes(&receiver_id);
+        // Transfer the shares
+        self.internal_transfer_shares(MAIN_POOL_ID, &sender_id, &receiver_id, amount.0, memo);
+        self.internal_charge_storage(&sender_id, initial_bytes);
+        self.internal_charge_storage(&receiver_id, receiver_initial_bytes);
+
+        // The receiver gets all the gas except the part kept for this call and the resolve
+        require!(
+            env::prepaid_gas() > env::used_gas() + GAS_FOR_FT_TRANSFER_CALL,
+            "More gas is required"
+        );
+        // Initiating receiver's call and the callback
+        ext_ft_receiver::ext(receiver_id.clone())
+            .with_static_gas(env::prepaid_gas() - env::used_gas() - GAS_FOR_FT_TRANSFER_CALL)
+            .ft_on_transfer(sender_id.clone(), amount, msg)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE_TRANSFER)
+                    .ft_resolve_transfer(&sender_id, receiver_id, amount),
+            )
+            .into()
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.internal_unwrap_pool(MAIN_POOL_ID).shares().total_supply.into()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.internal_shares_of(MAIN_POOL_ID, &account_id).into()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Finalize an `ft_transfer_call` chain of cross-contract calls.
+    //
+    // The `ft_transfer_call` process:
+    //
+    // 1. Sender calls `ft_transfer_call` on FT contract
+    // 2. FT contract transfers `amount` tokens from sender to receiver
+    // 3. FT contract calls `ft_on_transfer` on receiver contract
+    // 4+. [receiver contract may make other cross-contract calls]
+    // N. FT contract resolves promise chain with `ft_resolve_transfer`, and may
+    //    refund sender some or all of original `amount`
+    //
+    // Requirements:
+    // * Contract MUST forbid calls to this function by any account except self
+    // * If promise chain failed, contract MUST revert token transfer
+    // * If promise chain resolves with a non-zero amount given as a string,
+    //   contract MUST return this amount of tokens to `sender_id`
+    //
+    // Arguments:
+    // * `sender_id`: the sender of `ft_transfer_call`
+    // * `receiver_id`: the `receiver_id` argument given to `ft_transfer_call`
+    // * `amount`: the `amount` argument given to `ft_transfer_call`
+    //
+    // Returns a string representing a string version of an unsigned 128-bit
+    // integer of how many total tokens were spent by sender_id. Example: if sender
+    // calls `ft_transfer_call({ "amount": "100" })`, but `receiver_id` only uses
+    // 80, `ft_on_transfer` will resolve with `"20"`, and `ft_resolve_transfer`
+    // will return `"80"`.
+    #[private]
+    pub fn ft_resolve_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let amount: Balance = amount.into();
+
+        // Get the unused amount from the `ft_on_transfer` call result.
+        let unused_amount = match env::promise_result(0) {
+            PromiseResult::NotReady => env::abort(),
+            // If the promise was successful, get the return value and cast it to a U128.
+            PromiseResult::Successful(value) => {
+                // If we can properly parse the value, the unused amount is equal to whatever is smaller - the unused amount or the original amount (to prevent malicious contracts)
+                if let Ok(unused_amount) = near_sdk::serde_json::from_slice::<U128>(&value) {
+                    std::cmp::min(amount, unused_amount.0)
+                // If we can't properly parse the value, the original amount is returned.
+                } else {
+                    amount
+                }
+            }
+            // If the promise wasn't successful, return the original amount.
+            PromiseResult::Failed => amount,
+        };
+
+        // If there is some unused amount, we should refund the sender
+        if unused_amount > 0 {
+            // Get the receiver's balance. We can only refund the sender if the receiver has enough balance.
+            let receiver_balance = self.internal_shares_of(MAIN_POOL_ID, &receiver_id);
+            if receiver_balance > 0 {
+                // The amount to refund is the smaller of the unused amount and the receiver's balance as we can only refund up to what the receiver currently has.
+                let refund_amount = std::cmp::min(receiver_balance, unused_amount);
+                
+                // Refund the sender for the unused amount, the records of both are tracked as the callback can't fail.
+                let initial_bytes = self.internal_records_bytes(sender_id);
+                let receiver_initial_bytes = self.internal_records_bytes(&receiver_id);
+                self.internal_transfer_shares(MAIN_POOL_ID, &receiver_id, sender_id, refund_amount, Some("Refund".to_string()));
+                self.internal_track_storage(sender_id, initial_bytes);
+                self.internal_track_storage(&receiver_id, receiver_initial_bytes);
+                
+                // Return what was actually used (the amount sent - refund)
+                let used_amount = amount
+                    .checked_sub(refund_amount)
+                    .unwrap_or_else(|| env::panic_str("Total supply overflow"));
+                return used_amount.into();
+            }
+        }
+
+        // If the unused amount is 0, return the original amount.
+        amount.into()
+    }
+}
@@ -1,20 +1,352 @@
-use near_sdk::{env};
+use near_sdk::borsh::BorshSerialize;
+use near_sdk::collections::{LookupMap, LookupSet, TreeMap, Vector};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::{env, require, AccountId, Balance, Promise};
 
-use crate::{Contract, external::{ext_token, ext_self}};
+use crate::{Contract, StorageKey, GAS_FOR_RESOLVE, MAIN_POOL_ID, external::{ext_token, ext_self}};
+use crate::events::{AddLiquidity, FtBurn, FtMint, FtTransfer, PoolCreated, RemoveLiquidity, Swap, TransferFailed};
+use crate::oracle::Oracle;
+use crate::pool::Pool;
 
 impl Contract {
-    
-    // TODO
-    #[allow(dead_code)]
-    pub (crate) fn internal_query_external_metadata(&mut self) {
-        ext_token::ext(self.token_a.clone()) // External Contract Token instance
-            .get_metadata() // External Metadata Promise
-                .then(ext_self::ext(env::current_account_id()) // External Contract Self
-                    .on_get_metadata(self.token_a.clone()));
 
-        ext_token::ext(self.token_b.clone()) // External Contract Token instance
-            .get_metadata() // External Metadata Promise
+    /// Returns the state of the contract without any pools.
+    pub(crate) fn internal_new(owner_id: AccountId) -> Self {
+        let mut this = Self {
+            owner_id,
+            proposed_owner_id: None,
+            roles: LookupMap::new(StorageKey::Roles.try_to_vec().unwrap()),
+            tokens: LookupMap::new(b"t".to_vec()),
+            tokens_refreshed_at: LookupMap::new(StorageKey::TokensRefreshedAt.try_to_vec().unwrap()),
+            pools: Vector::new(StorageKey::Pools.try_to_vec().unwrap()),
+            oracles: LookupMap::new(StorageKey::Oracles.try_to_vec().unwrap()),
+            deposits: LookupMap::new(StorageKey::Deposits.try_to_vec().unwrap()),
+            pending: LookupMap::new(StorageKey::Pending.try_to_vec().unwrap()),
+            internal_totals: LookupMap::new(StorageKey::InternalTotals.try_to_vec().unwrap()),
+            paused: false,
+            paused_pools: LookupSet::new(StorageKey::PausedPools.try_to_vec().unwrap()),
+            circuit_breakers: LookupMap::new(StorageKey::CircuitBreakers.try_to_vec().unwrap()),
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts.try_to_vec().unwrap()),
+            bytes_for_account_storage: 0,
+            account_pools: LookupMap::new(StorageKey::AccountPools.try_to_vec().unwrap()),
+            flash_loans: LookupMap::new(StorageKey::FlashLoans.try_to_vec().unwrap()),
+            next_loan_id: 0,
+            orders: LookupMap::new(StorageKey::Orders.try_to_vec().unwrap()),
+            order_book: TreeMap::new(StorageKey::OrderBook.try_to_vec().unwrap()),
+            account_orders: LookupMap::new(StorageKey::AccountOrders.try_to_vec().unwrap()),
+            next_order_id: 0,
+            bytes_for_token_record: 0,
+            transfers_in_flight: LookupMap::new(StorageKey::TransfersInFlight.try_to_vec().unwrap()),
+            unindexed_pools: 0,
+            bytes_for_pool_record: 0,
+            bytes_for_position: 0,
+            bytes_for_order: 0,
+            pool_tokens: LookupSet::new(StorageKey::PoolTokens.try_to_vec().unwrap()),
+        };
+        this.measure_bytes_for_account_storage();
+        this.measure_bytes_for_records();
+        this
+    }
+
+    /// Queries the NEP-148 metadata of the token into the shared cache, the cache is kept if the query fails.
+    pub(crate) fn internal_query_metadata(&self, token_id: &AccountId) -> Promise {
+        ext_token::ext(token_id.clone()) // External Contract Token instance
+            .ft_metadata() // External Metadata Promise
                 .then(ext_self::ext(env::current_account_id()) // External Contract Self
-                    .on_get_metadata(self.token_b.clone()));
-    }    
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_get_metadata(token_id.clone()))
+    }
+
+    /// Queries the metadata of the token into the shared cache
+    /// and creates the wallet of the AMM in the token contract.
+    pub (crate) fn internal_register_token(&mut self, token_id: &AccountId) {
+        self.internal_query_metadata(token_id);
+
+        // Creates wallet for the AMM in the Token contract
+        ext_token::ext(token_id.clone()) // External Contract Token instance
+            .create_wallet(self.owner_id.clone(), 0);
+    }
+
+    /// Registers a new pool and returns its id, the pool should be created with the id `self.pools.len()`.
+    /// The tokens that are seen for the first time are registered as well.
+    pub(crate) fn internal_add_pool(&mut self, pool: Pool) -> u64 {
+        let pool_id = self.pools.len();
+        self.pools.push(&pool);
+        self.internal_update_totals(None, &pool);
+
+        let mut oracle = Oracle::new(pool_id);
+        oracle.update(&pool);
+        self.oracles.insert(&pool_id, &oracle);
+
+        PoolCreated {
+            pool_id,
+            account_id: &env::predecessor_account_id(),
+            pool_kind: &pool.kind(),
+            tokens: &pool.tokens(),
+            fee_bps: pool.fee_bps(),
+        }
+        .emit();
+
+        for token_id in pool.tokens() {
+            self.pool_tokens.insert(&token_id);
+            if !self.tokens.contains_key(&token_id) {
+                self.internal_register_token(&token_id);
+            }
+        }
+
+        pool_id
+    }
+
+    /// Panics until the metadata of all the tokens of the pool is resolved, the decimals of a token are unknown before.
+    pub(crate) fn internal_assert_metadata_known(&self, pool: &Pool) {
+        for token_id in pool.tokens() {
+            require!(
+                self.tokens.contains_key(&token_id),
+                format!("The metadata of {} is not known yet, refresh_token_metadata retries the query", token_id)
+            );
+        }
+    }
+
+    /// Returns the pool by id, panics if the pool doesn't exist.
+    pub(crate) fn internal_unwrap_pool(&self, pool_id: u64) -> Pool {
+        let pool = self.pools.get(pool_id);
+        require!(pool.is_some(), format!("Pool {} doesn't exist", pool_id));
+        pool.unwrap()
+    }
+
+    /// Writes the updated pool back to the registry without recording its prices.
+    pub(crate) fn internal_replace_pool(&mut self, pool_id: u64, pool: &Pool) {
+        let old = self.pools.replace(pool_id, pool);
+        self.internal_update_totals(Some(&old), pool);
+    }
+
+    /// Writes the updated pool back to the registry and records its new prices.
+    pub(crate) fn internal_save_pool(&mut self, pool_id: u64, pool: &Pool) {
+        self.internal_replace_pool(pool_id, pool);
+
+        let mut oracle = self.oracles.get(&pool_id).unwrap();
+        oracle.update(pool);
+        self.oracles.insert(&pool_id, &oracle);
+    }
+
+    /// Returns the pool by id, panics unless it trades: the AMM and the pool are not paused, no flash loan
+    /// of the pool is in flight and the metadata of its tokens is known. The swaps and their quotes check the same.
+    pub(crate) fn internal_unwrap_tradable_pool(&self, pool_id: u64) -> Pool {
+        self.internal_assert_pool_not_paused(pool_id);
+        self.internal_assert_no_flash_loan(pool_id);
+        let pool = self.internal_unwrap_pool(pool_id);
+        self.internal_assert_metadata_known(&pool);
+        pool
+    }
+
+    /// Returns the amount of `token_out` that a swap of `amount_in` of `token_in` in the pool would pay out.
+    /// Panics if the pool doesn't trade.
+    pub(crate) fn internal_get_return(&self, pool_id: u64, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> Balance {
+        self.internal_unwrap_tradable_pool(pool_id).get_return(token_in, amount_in, token_out)
+    }
+
+    /// Exchanges `amount_in` of `token_in` to `token_out` against the pool and updates the reserves.
+    /// Returns the amount of `token_out` that has to be paid out and the accrued protocol fee.
+    /// Panics if the pool doesn't trade, pauses the pool if the swap trips its circuit breaker.
+    /// `account_id` is the receiver of the output, it is only logged.
+    pub(crate) fn internal_swap(
+        &mut self,
+        pool_id: u64,
+        account_id: &AccountId,
+        token_in: &AccountId,
+        amount_in: Balance,
+        token_out: &AccountId,
+    ) -> (Balance, Balance) {
+        let mut pool = self.internal_unwrap_tradable_pool(pool_id);
+        // The prices are only needed by the circuit breaker
+        let price_before = self
+            .circuit_breakers
+            .contains_key(&pool_id)
+            .then(|| pool.spot_price(token_in, token_out));
+        let result = pool.swap(token_in, amount_in, token_out);
+        if let Some(price_before) = price_before {
+            self.internal_check_circuit_breaker(pool_id, price_before, pool.spot_price(token_in, token_out));
+        }
+        self.internal_save_pool(pool_id, &pool);
+
+        Swap {
+            pool_id,
+            account_id,
+            token_in,
+            amount_in: &U128(amount_in),
+            token_out,
+            amount_out: &U128(result.0),
+            reserves: &reserves_of(&pool),
+        }
+        .emit();
+        result
+    }
+
+    /// Records whether the account holds LP shares or positions of the pool, so the records of the account
+    /// are known without reading all the pools.
+    pub(crate) fn internal_update_account_pools(&mut self, pool_id: u64, pool: &Pool, account_id: &AccountId) {
+        let mut account_pools = self.account_pools.get(account_id).unwrap_or_default();
+        let changed = if pool.is_held_by(account_id) { account_pools.insert(pool_id) } else { account_pools.remove(&pool_id) };
+        if !changed {
+            return;
+        }
+        if account_pools.is_empty() {
+            self.account_pools.remove(account_id);
+        } else {
+            self.account_pools.insert(account_id, &account_pools);
+        }
+    }
+
+    /// Returns the amount of LP shares of the pool owned by the account.
+    pub(crate) fn internal_shares_of(&self, pool_id: u64, account_id: &AccountId) -> Balance {
+        self.internal_unwrap_pool(pool_id).shares().balance_of(account_id)
+    }
+
+    /// Mints `amount` of LP shares of the pool to the account.
+    /// Only the shares of the main pool are the NEP-141 token of the contract, so the event is emitted for them only.
+    pub(crate) fn internal_mint_shares(&mut self, pool_id: u64, pool: &mut Pool, account_id: &AccountId, amount: Balance) {
+        pool.shares_mut().mint(account_id, amount);
+        self.internal_update_account_pools(pool_id, pool, account_id);
+
+        if pool_id == MAIN_POOL_ID {
+            FtMint {
+                owner_id: account_id,
+                amount: &U128(amount),
+                memo: None,
+            }
+            .emit();
+        }
+    }
+
+    /// Burns `amount` of LP shares of the pool of the account.
+    pub(crate) fn internal_burn_shares(&mut self, pool_id: u64, pool: &mut Pool, account_id: &AccountId, amount: Balance) {
+        pool.shares_mut().burn(account_id, amount);
+        self.internal_update_account_pools(pool_id, pool, account_id);
+
+        if pool_id == MAIN_POOL_ID {
+            FtBurn {
+                owner_id: account_id,
+                amount: &U128(amount),
+                memo: None,
+            }
+            .emit();
+        }
+    }
+
+    /// Internal method for performing a transfer of LP shares of the pool from one account to another.
+    pub(crate) fn internal_transfer_shares(
+        &mut self,
+        pool_id: u64,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        amount: Balance,
+        memo: Option<String>,
+    ) {
+        // Ensure the sender can't transfer to themselves
+        require!(sender_id != receiver_id, "Sender and receiver should be different");
+        // Ensure the sender can't transfer 0 shares
+        require!(amount > 0, "The amount should be a positive number");
+
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        pool.shares_mut().withdraw(sender_id, amount);
+        pool.shares_mut().deposit(receiver_id, amount);
+        self.internal_update_account_pools(pool_id, &pool, sender_id);
+        self.internal_update_account_pools(pool_id, &pool, receiver_id);
+        self.internal_save_pool(pool_id, &pool);
+
+        if pool_id == MAIN_POOL_ID {
+            FtTransfer {
+                old_owner_id: sender_id,
+                new_owner_id: receiver_id,
+                amount: &U128(amount),
+                memo: memo.as_deref(),
+            }
+            .emit();
+        }
+    }
+
+    /// Adds the tokens to the reserves of the pool and mints LP shares to the account.
+    /// The locked part of the first deposit is minted to the AMM account.
+    /// Returns the amounts of the tokens that were actually used and the minted shares.
+    pub(crate) fn internal_add_liquidity(
+        &mut self,
+        pool_id: u64,
+        account_id: &AccountId,
+        amounts: &[Balance],
+    ) -> (Vec<Balance>, Balance) {
+        self.internal_assert_pool_not_paused(pool_id);
+        self.internal_assert_no_flash_loan(pool_id);
+        let mut pool = self.internal_unwrap_pool(pool_id);
+
+        let (amounts, shares, locked_shares) = pool.add_liquidity(amounts);
+        if locked_shares > 0 {
+            self.internal_mint_shares(pool_id, &mut pool, &env::current_account_id(), locked_shares);
+        }
+        self.internal_mint_shares(pool_id, &mut pool, account_id, shares);
+
+        self.internal_save_pool(pool_id, &pool);
+
+        AddLiquidity {
+            pool_id,
+            account_id,
+            amounts: &amounts.iter().copied().map(U128).collect::<Vec<_>>(),
+            shares: &U128(shares),
+            position_id: None,
+            reserves: &reserves_of(&pool),
+        }
+        .emit();
+        (amounts, shares)
+    }
+
+    /// Burns LP shares of the account and removes the pro rata part of all the reserves of the pool.
+    /// Returns the amounts of the tokens that have to be paid out.
+    pub(crate) fn internal_remove_liquidity(&mut self, pool_id: u64, account_id: &AccountId, shares: Balance) -> Vec<Balance> {
+        self.internal_assert_no_flash_loan(pool_id);
+        let mut pool = self.internal_unwrap_pool(pool_id);
+
+        let amounts = pool.remove_liquidity(shares);
+        self.internal_burn_shares(pool_id, &mut pool, account_id, shares);
+
+        self.internal_save_pool(pool_id, &pool);
+
+        RemoveLiquidity {
+            pool_id,
+            account_id,
+            amounts: &amounts.iter().copied().map(U128).collect::<Vec<_>>(),
+            shares: &U128(shares),
+            position_id: None,
+            reserves: &reserves_of(&pool),
+        }
+        .emit();
+        amounts
+    }
+
+    /// Adds the tokens of a transfer that failed in a callback to the internal deposit of the account.
+    pub(crate) fn internal_refund_to_deposit(&mut self, action: &str, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let initial_bytes = self.internal_records_bytes(account_id);
+        self.internal_deposit(account_id, token_id, amount);
+        self.internal_track_storage(account_id, initial_bytes);
+        TransferFailed {
+            action,
+            account_id,
+            token_id,
+            amount: &U128(amount),
+            memo: Some("the tokens are refunded to the deposit"),
+        }
+        .emit();
+    }
+
+    pub(crate) fn internal_assert_owner(&self) {
+        require!(env::predecessor_account_id() == self.owner_id, "Only the owner can call this method");
+    }
+}
+
+/// Returns the reserves of the pool for the events.
+pub(crate) fn reserves_of(pool: &Pool) -> Vec<U128> {
+    pool.reserves().into_iter().map(U128).collect()
+}
+
+/// Returns true if the block timestamp is beyond the `deadline` (in nanoseconds) given by the user.
+pub(crate) fn is_deadline_passed(deadline: Option<U64>) -> bool {
+    deadline.is_some_and(|deadline| env::block_timestamp() > deadline.0)
 }
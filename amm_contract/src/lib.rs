@@ -1,55 +1,78 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use metadata::FungibleTokenMetadata;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, LookupSet, TreeMap, Vector};
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{near_bindgen, AccountId, env, PanicOnDefault, assert_self, log, ext_contract, Balance, require};
+use near_sdk::{near_bindgen, AccountId, Balance, env, Gas, PanicOnDefault, Promise, PromiseError, StorageUsage, assert_one_yocto, assert_self, log, require};
 
-////////////////////////////////
-/// External
+pub mod concentrated_math;
+pub mod concentrated_pool;
+pub mod deposits;
+pub mod events;
+pub mod external;
+pub mod fees;
+pub mod flash_loan;
+pub mod ft_core;
+pub mod internal;
+pub mod limit_orders;
+pub mod liquidity;
+pub mod math;
+pub mod metadata;
+pub mod oracle;
+pub mod pause;
+pub mod pending;
+pub mod router;
+pub mod pool;
+pub mod quotes;
+pub mod roles;
+pub mod shares;
+pub mod simple_pool;
+pub mod stable_math;
+pub mod stable_swap_pool;
+pub mod storage;
+pub mod sync;
+pub mod token_receiver;
+pub mod upgrade;
+pub mod weighted_math;
+pub mod weighted_pool;
 
-#[ext_contract(ext_token)]
-trait ExtToken {
-    fn create_wallet(&mut self, sender_id: AccountId, amount: Balance);
-    fn get_metadata(&self) -> FungibleTokenMetadata;
-    fn ft_transfer(
-        &mut self, 
-        receiver_id: AccountId, 
-        amount: U128, 
-        memo: Option<String>);
-    fn transfer_from(&mut self,
-        sender_id: AccountId,
-        receiver_id: AccountId,
-        amount: Balance);
-}
+use crate::roles::Role;
 
-#[ext_contract(ext_self)]
-trait ExtSelf {
-    fn on_get_metadata(
-        &mut self,
-        contract_id: AccountId, 
-        #[callback] metadata: FungibleTokenMetadata);
-    fn on_ft_deposit(
-        &mut self,
-        from_balance: Balance,
-        to_balance: Balance,
-        contract_id: AccountId,
-        receiver_id: AccountId,
-        amount: Balance,
-    );
-    fn on_update_balances(
-        &mut self,
-        sender_post_balance: Balance, 
-        receiver_post_balance: Balance
-    );
-}
+pub(crate) const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+pub(crate) const GAS_FOR_RESOLVE: Gas = Gas(5_000_000_000_000);
 
-/// External
-////////////////////////////////
+/// Id of the pool created on initialization, its LP shares are the NEP-141 token of the contract.
+pub const MAIN_POOL_ID: u64 = 0;
 
-pub mod external;
-pub mod internal;
-pub mod metadata;
+/// Helper structure for keys of the persistent collections.
+#[derive(BorshSerialize)]
+pub enum StorageKey {
+    Pools,
+    Shares { pool_id: u64 },
+    Ticks { pool_id: u64 },
+    TickBitmap { pool_id: u64 },
+    Positions { pool_id: u64 },
+    AccountPositions { pool_id: u64 },
+    Oracles,
+    Observations { pool_id: u64 },
+    Deposits,
+    Pending,
+    InternalTotals,
+    Roles,
+    PausedPools,
+    CircuitBreakers,
+    StorageAccounts,
+    TokensRefreshedAt,
+    FlashLoans,
+    Orders,
+    OrderBook,
+    AccountOrders,
+    AccountPools,
+    TransfersInFlight,
+    PoolTokens,
+}
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, Clone, Deserialize, Serialize)]
@@ -61,21 +84,77 @@ pub struct TokenContractInfo {
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, Clone, Deserialize, Serialize)]
 pub struct AmmContractInfo {
-    token_a: TokenContractInfo,
-    token_b: TokenContractInfo,
-    ratio: u128
+    tokens: Vec<TokenContractInfo>,
+    reserves: Vec<U128>,
+    ratio: String,
+    pool_kind: String,
+    shares_total_supply: U128,
+    paused: bool,
 }
 
 #[near_bindgen]
 #[derive(PanicOnDefault, BorshDeserialize, BorshSerialize)]
 pub struct Contract {
     owner_id: AccountId, 
-    token_a: AccountId,
-    token_a_meta: FungibleTokenMetadata,
-    token_b: AccountId,
-    token_b_meta: FungibleTokenMetadata,
-    tokens_ratio: u128,
+    /// Account proposed to become the owner, until it accepts the ownership
+    proposed_owner_id: Option<AccountId>,
+    /// Roles granted by the owner to each account
+    roles: LookupMap<AccountId, BTreeSet<Role>>,
+    /// Metadata of the tokens of all the pools
     pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    /// Block timestamps in nanoseconds of the last resolved metadata of the tokens
+    tokens_refreshed_at: LookupMap<AccountId, u64>,
+    /// Registry of the pools, the id of a pool is its index
+    pools: Vector<pool::Pool>,
+    /// Price history of each pool
+    oracles: LookupMap<u64, oracle::Oracle>,
+    /// Internal balances of the tokens of each account
+    deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    /// Amounts of the tokens whose payout to each account failed
+    pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    /// Sums of everything the AMM owes of each token: the reserves and the protocol fees of the pools,
+    /// the deposits, the pending amounts and the open limit orders
+    internal_totals: LookupMap<AccountId, Balance>,
+    /// Pause of the swaps and the deposits of all the pools
+    paused: bool,
+    /// Pools whose swaps and deposits are paused
+    paused_pools: LookupSet<u64>,
+    /// The largest move of the price of each pool in basis points a single swap can make before the pool is paused
+    circuit_breakers: LookupMap<u64, u32>,
+    /// Storage deposits and used bytes of the registered accounts
+    storage_accounts: LookupMap<AccountId, storage::AccountStorage>,
+    /// Bytes of the storage record of an account with the longest id
+    bytes_for_account_storage: StorageUsage,
+    /// Ids of the pools in which each account holds LP shares or positions
+    account_pools: LookupMap<AccountId, BTreeSet<u64>>,
+    /// The repayments of the flash loans in flight by pool, the pools are locked until the loans are resolved
+    flash_loans: LookupMap<u64, flash_loan::OpenLoan>,
+    /// Id of the next flash loan
+    next_loan_id: u64,
+    /// Open limit orders by id
+    orders: LookupMap<u64, limit_orders::LimitOrder>,
+    /// Keys of the open limit orders sorted by the pool, the token to sell and the limit price
+    order_book: TreeMap<limit_orders::OrderKey, ()>,
+    /// Ids of the open limit orders of each account
+    account_orders: LookupMap<AccountId, BTreeSet<u64>>,
+    /// Id of the next limit order
+    next_order_id: u64,
+    /// Bytes of the deposit record of an account with the longest id holding one token, charged for each token
+    /// of the deposits and the pending amounts and reserved by an order whose output is added to the deposit
+    bytes_for_token_record: StorageUsage,
+    /// Numbers of the transfers of each token sent by the AMM whose callbacks haven't run yet
+    transfers_in_flight: LookupMap<AccountId, u32>,
+    /// Number of the first pools created before `account_pools` was kept, their holders are not indexed
+    /// and are read from the pools
+    unindexed_pools: u64,
+    /// Bytes of the LP shares record of an account with the longest id and of its entry in `account_pools`
+    bytes_for_pool_record: StorageUsage,
+    /// Bytes of a position of an account with the longest id and of its entry in the positions of the account
+    bytes_for_position: StorageUsage,
+    /// Bytes of a limit order of an account with the longest id, of its key in the book and in `account_orders`
+    bytes_for_order: StorageUsage,
+    /// Tokens of all the pools, also those whose metadata is not resolved yet
+    pool_tokens: LookupSet<AccountId>,
 }
 
 #[near_bindgen]
@@ -88,242 +167,2494 @@ impl Contract {
     ) -> Self {
         assert!(!env::state_exists(), "Contract already initialized: {}", owner_id);
 
-        let this = Self {
-            owner_id: owner_id.clone(),
-            token_a: token_a_contract_id.clone(),
-            token_a_meta: FungibleTokenMetadata::default(),
-            token_b: token_b_contract_id.clone(),
-            token_b_meta: FungibleTokenMetadata::default(),
-            tokens_ratio: 0,
-            tokens: LookupMap::new(b"t".to_vec()),
-        };
+        let mut this = Self::internal_new(owner_id);
+        upgrade::write_state_version();
 
-        ext_token::ext(token_a_contract_id.clone()) // External Contract Token instance
-            .get_metadata() // External Metadata Promise
-                .then(ext_self::ext(env::current_account_id()) // External Contract Self
-                    .on_get_metadata(token_a_contract_id.clone()));
-
-        ext_token::ext(token_b_contract_id.clone()) // External Contract Token instance
-            .get_metadata() // External Metadata Promise
-                .then(ext_self::ext(env::current_account_id()) // External Contract Self
-                    .on_get_metadata(token_b_contract_id.clone()));
-
-        // Creates wallet for the AMM in both Token А and Token B contracts
-        ext_token::ext(this.token_a.clone()) // External Contract Token instance
-            .create_wallet(
-                owner_id.clone(), 
-                this.token_a_meta.total_supply
-            );
-        ext_token::ext(this.token_b.clone()) // External Contract Token instance
-            .create_wallet(
-                owner_id.clone(), 
-                this.token_b_meta.total_supply
-            );
+        // The main pool
+        let pool = simple_pool::SimplePool::new(MAIN_POOL_ID, token_a_contract_id, token_b_contract_id, fees::DEFAULT_FEE_BPS);
+        this.internal_add_pool(pool::Pool::SimplePool(pool));
 
         this
     }
 
+    /// Registers a new pool of the pair with the given swap fee in basis points and returns its id,
+    /// requires the role `PoolCreator`.
+    pub fn add_pool(&mut self, token_a: AccountId, token_b: AccountId, fee_bps: u32) -> u64 {
+        self.internal_assert_role(Role::PoolCreator);
+        require!(fee_bps <= fees::MAX_FEE_BPS, format!("The fee can't exceed {} bps", fees::MAX_FEE_BPS));
+
+        let pool_id = self.pools.len();
+        let pool = simple_pool::SimplePool::new(pool_id, token_a.clone(), token_b.clone(), fee_bps);
+        self.internal_add_pool(pool::Pool::SimplePool(pool));
+        log!("add_pool: pool {} of {} and {} with fee {} bps", pool_id, token_a, token_b, fee_bps);
+        pool_id
+    }
+
+    /// Queries the metadata of the tokens into the cache before they are used by a pool, requires the role `PoolCreator`.
+    pub fn register_tokens(&mut self, token_ids: Vec<AccountId>) {
+        self.internal_assert_role(Role::PoolCreator);
+        for token_id in token_ids.iter() {
+            self.internal_register_token(token_id);
+        }
+    }
+
+    /// Queries the metadata of the token again, requires the role `Keeper`. The pools of a token whose first query
+    /// failed don't trade until the metadata is resolved, a failed refresh keeps the previous metadata.
+    pub fn refresh_token_metadata(&mut self, token_id: AccountId) -> Promise {
+        self.internal_assert_role(Role::Keeper);
+        require!(
+            self.pool_tokens.contains(&token_id),
+            format!("Unsupported token contract id: {}", token_id)
+        );
+        self.internal_query_metadata(&token_id)
+    }
+
     #[private]
     pub fn on_get_metadata(
         &mut self, 
         contract_id: AccountId, 
-        #[callback] metadata: FungibleTokenMetadata)
+        #[callback_result] metadata: Result<FungibleTokenMetadata, PromiseError>)
     {
         assert_self();
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                log!("on_get_metadata: the metadata query of {} failed, the metadata is kept", contract_id);
+                return;
+            }
+        };
         log!("on_get_metadata: contract_id: {} metadata {:?}", contract_id, metadata);
-        
-        if self.token_a == contract_id {
-            self.token_a_meta = metadata.clone();
-        } else if self.token_b == contract_id {
-            self.token_b_meta = metadata.clone();
-        } else {
-            env::panic_str(format!("Unsupported contract id: {}", contract_id).as_str());
-        }
 
         self.tokens.insert(&contract_id, &metadata);
+        self.tokens_refreshed_at.insert(&contract_id, &env::block_timestamp());
+    }
 
-        self.update_tokens_ratio();
+    // Returns the block timestamp in nanoseconds of the last resolved metadata of the token,
+    // none if it is not known yet or was resolved before the upgrade that records the timestamps
+    pub fn get_token_last_refreshed(&self, token_id: AccountId) -> Option<U64> {
+        self.tokens_refreshed_at.get(&token_id).map(U64)
     }
 
-    // Update tokens ratio based on metadata of tokens A and B
-    pub fn update_tokens_ratio(&mut self) -> u128 {
-        let minted_units_token_a = self.token_a_meta.total_supply / 10_u128.pow(self.token_a_meta.decimals.into());
-        let minted_units_token_b = self.token_b_meta.total_supply / 10_u128.pow(self.token_b_meta.decimals.into());
-        self.tokens_ratio = minted_units_token_a * minted_units_token_b;
-        self.get_tokens_ratio()
+    // Returns the number of the registered pools
+    pub fn get_number_of_pools(&self) -> u64 {
+        self.pools.len()
     }
 
-    // Returns tokens ratio
-    pub fn get_tokens_ratio(&self) -> u128 {
-        self.tokens_ratio
+    // Returns tokens ratio of the pool, the invariant of the pool: k = reserve_a * reserve_b in base units
+    // for a constant product pool, D of the normalized balances for a StableSwap pool,
+    // prod(reserve_i ^ weight_i) for a weighted pool, liquidity^2 of the current price range for a concentrated pool
+    pub fn get_tokens_ratio(&self, pool_id: u64) -> String {
+        self.internal_unwrap_pool(pool_id).invariant().to_string()
     }
 
-    // Returns all the metadata: Token A + Token B + tokens ratio
-    pub fn tokens_full_info(&self) -> AmmContractInfo {
+    // Returns all the metadata of the pool: tokens + reserves + tokens ratio, in the order of the tokens of the pool,
+    // and whether its trading is paused
+    pub fn tokens_full_info(&self, pool_id: u64) -> AmmContractInfo {
+        let pool = self.internal_unwrap_pool(pool_id);
         AmmContractInfo {
-            token_a: TokenContractInfo {
-                ticker: self.token_a_meta.symbol.clone(),
-                decimals: self.token_a_meta.decimals
-            },
-            token_b: TokenContractInfo {
-                ticker: self.token_b_meta.symbol.clone(),
-                decimals: self.token_b_meta.decimals
-            },
-            ratio: self.get_tokens_ratio()
+            tokens: pool
+                .tokens()
+                .into_iter()
+                .map(|token_id| self.token_info_by_id(pool_id, token_id))
+                .collect(),
+            reserves: pool.reserves().into_iter().map(U128).collect(),
+            ratio: pool.invariant().to_string(),
+            pool_kind: pool.kind(),
+            shares_total_supply: pool.shares_total_supply().into(),
+            paused: self.internal_is_pool_paused(pool_id),
         }
     }
 
-    // Reterns a metadata by the token id
-    pub fn token_info_by_id(&self, token_contract_id: AccountId) -> TokenContractInfo {
-        if self.token_a == token_contract_id {
-            TokenContractInfo {
-                ticker: self.token_a_meta.symbol.clone(),
-                decimals: self.token_a_meta.decimals
-            }
-        } else if self.token_b == token_contract_id {
-            TokenContractInfo {
-                ticker: self.token_b_meta.symbol.clone(),
-                decimals: self.token_b_meta.decimals
-            }
-        } else {
+    // Reterns a metadata by the token id of the pool
+    pub fn token_info_by_id(&self, pool_id: u64, token_contract_id: AccountId) -> TokenContractInfo {
+        let pool = self.internal_unwrap_pool(pool_id);
+        if !pool.contains(&token_contract_id) {
             env::panic_str(format!("Unsupported token contract id: {}", token_contract_id).as_str());
         }
+
+        // The metadata is empty until `on_get_metadata` is resolved
+        let metadata = self.tokens.get(&token_contract_id).unwrap_or_default();
+        TokenContractInfo {
+            ticker: metadata.symbol,
+            decimals: metadata.decimals,
+        }
     }
 
-    // Send tokens A, in return, receives token B...
-    pub fn deposit_contract(&self, amount: Balance) {
-        // The function that returns opposite token contract id
-        let get_return_contract_id = |contract_id: AccountId| -> AccountId {
-            if self.token_a == contract_id {
-                self.token_b.clone()
-            } else if self.token_b == contract_id {
-                self.token_a.clone()
-            } else {
-                env::panic_str(format!("Unsupported token contract id: {}", contract_id).as_str());
-            }
-        };
+    /// Exchanges `amount` of the deposit of tokens A (or B) in base units in the pool, in return, sends token B (or A)
+    /// to the caller with `ft_transfer`. The input is already owned by the AMM, so the swap is final once it is applied;
+    /// the output is added to the deposit if the transfer fails. `token_out` is required for the pools of more than two tokens.
+    /// The call fails if it pays out less than `min_amount_out` or the block timestamp is beyond `deadline` (in nanoseconds).
+    /// Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn deposit_contract(
+        &mut self,
+        pool_id: u64,
+        token_contract_id: AccountId,
+        amount: U128,
+        min_amount_out: U128,
+        deadline: Option<U64>,
+        token_out: Option<AccountId>,
+    ) -> Promise {
+        assert_one_yocto();
+        require!(amount.0 > 0, "The amount should be a positive number");
+        require!(!internal::is_deadline_passed(deadline), "deposit_contract: the deadline has passed");
 
         let sender_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&sender_id);
+        let contract_id_for_the_return = token_out
+            .unwrap_or_else(|| self.internal_unwrap_pool(pool_id).opposite_token(&token_contract_id));
+
+        self.internal_withdraw(&sender_id, &token_contract_id, amount.0);
+        let (amount_for_the_return, _) = self.internal_swap(pool_id, &sender_id, &token_contract_id, amount.0, &contract_id_for_the_return);
+        require!(
+            amount_for_the_return >= min_amount_out.0,
+            format!("deposit_contract: amount out {} is less than min amount out {}", amount_for_the_return, min_amount_out.0)
+        );
+        self.internal_charge_storage(&sender_id, initial_bytes);
 
-        // Evaluate opposite token contract
-        let contract_id_for_the_return = get_return_contract_id(sender_id.clone());
-
-        // Get metadata for token contracts
-        let sender_token_meta = self.tokens
-            .get(&sender_id)
-            .unwrap_or_else(|| env::panic_str(format!("deposit_contract: unknown token contract: {}", &sender_id).as_str()));
-        let return_token_meta = self.tokens
-            .get(&sender_id)
-            .unwrap_or_else(|| env::panic_str(format!("deposit_contract: unknown token contract: {}", &sender_id).as_str()));
-
-        // Total decimal units
-        let sender_decimal = 10_u128.pow(sender_token_meta.decimals as u32);
-        let receiver_decimal = 10_u128.pow(return_token_meta.decimals as u32);
-
-        // Total tokens amount
-        let sender_tokens_amount = amount * sender_decimal; // A or B
-
-        let sender_post_amount = sender_tokens_amount + sender_token_meta.total_supply;
-
-        let receiver_post_amount = self.tokens_ratio / (sender_post_amount / sender_decimal) * receiver_decimal;
-
-        let receiver_tokens_amount = return_token_meta.total_supply - receiver_post_amount; // B or A
-        
-        // ext_token::ext(contract_id_for_the_return.clone())
-        ext_token::ext(self.token_a.clone())
-            .transfer_from(sender_id.clone(), env::current_account_id(), sender_tokens_amount)
-            .then(
-                ext_self::ext(env::current_account_id())
-                    .on_ft_deposit(
-                        sender_post_amount,
-                        receiver_post_amount,
-                        contract_id_for_the_return,
-                        sender_id,
-                        receiver_tokens_amount,
-                    ),
-            );
+        log!("deposit_contract: {} swapped {} {} to {} {}", sender_id, amount.0, token_contract_id, amount_for_the_return, contract_id_for_the_return);
+        self.internal_send(&sender_id, &contract_id_for_the_return, amount_for_the_return, "AMM swap")
     }
 
     pub fn on_fn_transfer(&self) {
         log!("AMM: on_fn_transfer: TODO implementation...");
     }
+}
 
-    pub fn on_ft_deposit(
-        &mut self,
-        from_balance: Balance,
-        to_balance: Balance,
-        contract_id: AccountId,
-        receiver_id: AccountId,
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::json_types::U128;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, PromiseOrValue, PromiseResult, RuntimeFeesConfig, VMConfig};
+
+    use crate::ft_core::FungibleTokenCore;
+    use crate::metadata::FungibleTokenMetadataProvider;
+    use crate::oracle::{PriceOracle, MAX_OBSERVATIONS};
+    use crate::storage::StorageManagement;
+    use crate::token_receiver::FungibleTokenReceiver;
+
+    const DECIMALS: u8 = 18;
+    const ONE_TOKEN: Balance = 1_000_000_000_000_000_000;
+    const TOTAL_SUPPLY: Balance = 1_000_000 * ONE_TOKEN;
+    const RESERVE: Balance = 1_000 * ONE_TOKEN;
+    const STORAGE_DEPOSIT: Balance = 1_000_000_000_000_000_000_000_000;
+
+    use super::*;
+
+    fn amm() -> AccountId { accounts(0) }
+    fn owner() -> AccountId { accounts(1) }
+    fn token_a() -> AccountId { accounts(2) }
+    fn token_b() -> AccountId { accounts(3) }
+    fn user() -> AccountId { accounts(4) }
+    fn token_c() -> AccountId { accounts(5) }
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(amm())
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn token_metadata(symbol: &str, total_supply: Balance) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: "ft-0.0.1".to_string(),
+            name: symbol.to_string(),
+            symbol: symbol.to_string(),
+            total_supply,
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: DECIMALS,
+        }
+    }
+
+    const DEPOSIT_MSG: &str = "{\"action\":\"deposit\"}";
+
+    // Deposits `amount` of the token for the account with `ft_transfer_call`
+    fn deposit_for(context: &mut VMContextBuilder, contract: &mut Contract, account_id: AccountId, token_id: AccountId, amount: Balance) {
+        testing_env!(context.predecessor_account_id(token_id).build());
+        let result = contract.ft_on_transfer(account_id, U128(amount), DEPOSIT_MSG.to_string());
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+    }
+
+    // Deposits `amount` of the token for the user with `ft_transfer_call`
+    fn deposit(context: &mut VMContextBuilder, contract: &mut Contract, token_id: AccountId, amount: Balance) {
+        deposit_for(context, contract, user(), token_id, amount);
+    }
+
+    // Deposits the amounts of the tokens of the pool for the account and adds them as liquidity, returns the shares
+    fn add_liquidity_as(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        account_id: AccountId,
+        pool_id: u64,
+        amounts: &[Balance],
+    ) -> U128 {
+        let tokens = contract.internal_unwrap_pool(pool_id).tokens();
+        for (token_id, amount) in tokens.into_iter().zip(amounts).filter(|(_, amount)| **amount > 0) {
+            deposit_for(context, contract, account_id.clone(), token_id, *amount);
+        }
+        testing_env!(context.predecessor_account_id(account_id).build());
+        contract.add_liquidity(pool_id, amounts.iter().map(|amount| U128(*amount)).collect(), None)
+    }
+
+    // Deposits the amounts of both tokens of the concentrated pool for the account and opens the position with them
+    fn add_position_as(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        account_id: AccountId,
+        pool_id: u64,
+        ticks: (i32, i32),
+        amounts: &[Balance],
+    ) -> u64 {
+        for (token_id, amount) in [token_a(), token_b()].into_iter().zip(amounts) {
+            deposit_for(context, contract, account_id.clone(), token_id, *amount);
+        }
+        testing_env!(context.predecessor_account_id(account_id).build());
+        contract.add_position(pool_id, ticks.0, ticks.1, amounts.iter().map(|amount| U128(*amount)).collect(), None)
+    }
+
+    // Creates the AMM and resolves the metadata callbacks for both tokens
+    fn setup_contract() -> (VMContextBuilder, Contract) {
+        let mut context = get_context(owner());
+        testing_env!(context.build());
+        let mut contract = Contract::new(owner(), token_a(), token_b());
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        contract.on_get_metadata(token_a(), Ok(token_metadata("tkn_A", TOTAL_SUPPLY)));
+        contract.on_get_metadata(token_b(), Ok(token_metadata("tkn_B", TOTAL_SUPPLY)));
+
+        // The owner and the user pay for their storage
+        for account_id in [owner(), user()] {
+            testing_env!(context.predecessor_account_id(account_id).attached_deposit(STORAGE_DEPOSIT).build());
+            contract.storage_deposit(None, None);
+        }
+
+        // The owner funds both reserves
+        testing_env!(context.attached_deposit(0).build());
+        add_liquidity_as(&mut context, &mut contract, owner(), MAIN_POOL_ID, &[RESERVE, RESERVE]);
+
+        (context, contract)
+    }
+
+    fn swap_msg(pool_id: u64, min_amount_out: Balance) -> String {
+        format!("{{\"action\":\"swap\",\"pool_id\":{},\"min_amount_out\":\"{}\"}}", pool_id, min_amount_out)
+    }
+
+    fn swap_msg_with_deadline(pool_id: u64, min_amount_out: Balance, deadline: u64) -> String {
+        format!(
+            "{{\"action\":\"swap\",\"pool_id\":{},\"min_amount_out\":\"{}\",\"deadline\":\"{}\"}}",
+            pool_id, min_amount_out, deadline
+        )
+    }
+
+    fn route_msg(hops: &[router::SwapHop], min_amount_out: Balance) -> String {
+        format!(
+            "{{\"action\":\"swap_route\",\"hops\":{},\"min_amount_out\":\"{}\"}}",
+            near_sdk::serde_json::to_string(hops).unwrap(), min_amount_out
+        )
+    }
+
+    fn hop(pool_id: u64, token_in: AccountId, token_out: AccountId) -> router::SwapHop {
+        router::SwapHop { pool_id, token_in, token_out }
+    }
+
+    // Resolves the metadata of token C with 18 decimals
+    fn resolve_metadata_c(context: &mut VMContextBuilder, contract: &mut Contract) {
+        testing_env!(context.predecessor_account_id(amm()).build());
+        contract.on_get_metadata(token_c(), Ok(token_metadata("tkn_C", TOTAL_SUPPLY)));
+    }
+
+    // Adds the pool B-C with the same reserves as the main pool and returns its id
+    fn add_pool_b_c(context: &mut VMContextBuilder, contract: &mut Contract) -> u64 {
+        resolve_metadata_c(context, contract);
+        testing_env!(context.predecessor_account_id(owner()).build());
+        let pool_id = contract.add_pool(token_b(), token_c(), fees::DEFAULT_FEE_BPS);
+        add_liquidity_as(context, contract, owner(), pool_id, &[RESERVE, RESERVE]);
+        pool_id
+    }
+
+    const STABLE_DECIMALS: u8 = 6;
+    const ONE_STABLE: Balance = 1_000_000;
+
+    // Adds the StableSwap pool A-C where C has 6 decimals, funded with 1000 of both tokens, and returns its id
+    fn add_stable_pool_a_c(context: &mut VMContextBuilder, contract: &mut Contract, amp: u64) -> u64 {
+        testing_env!(context.predecessor_account_id(amm()).build());
+        let metadata = FungibleTokenMetadata { decimals: STABLE_DECIMALS, ..token_metadata("tkn_C", TOTAL_SUPPLY) };
+        contract.on_get_metadata(token_c(), Ok(metadata));
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        let pool_id = contract.add_stable_pool(vec![token_a(), token_c()], 4, amp);
+        add_liquidity_as(context, contract, owner(), pool_id, &[RESERVE, 1_000 * ONE_STABLE]);
+        pool_id
+    }
+
+    // Adds the weighted pool A-B-C with the weights 50/30/20, funded with 1000 of all the tokens, and returns its id
+    fn add_weighted_pool_a_b_c(context: &mut VMContextBuilder, contract: &mut Contract) -> u64 {
+        resolve_metadata_c(context, contract);
+        testing_env!(context.predecessor_account_id(owner()).build());
+        let pool_id = contract.add_weighted_pool(vec![token_a(), token_b(), token_c()], vec![5_000, 3_000, 2_000], fees::DEFAULT_FEE_BPS);
+        add_liquidity_as(context, contract, owner(), pool_id, &[RESERVE, RESERVE, RESERVE]);
+        pool_id
+    }
+
+    fn swap_msg_to(pool_id: u64, token_out: AccountId, min_amount_out: Balance) -> String {
+        format!(
+            "{{\"action\":\"swap\",\"pool_id\":{},\"token_out\":\"{}\",\"min_amount_out\":\"{}\"}}",
+            pool_id, token_out, min_amount_out
+        )
+    }
+
+    // Adds the concentrated pool A-B at the price of 1 with the tick spacing of 10 and the position
+    // of the owner in [-1000, 1000) funded with 1000 of both tokens, and returns its id
+    fn add_concentrated_pool_a_b(context: &mut VMContextBuilder, contract: &mut Contract) -> u64 {
+        testing_env!(context.predecessor_account_id(owner()).build());
+        let pool_id = contract.add_concentrated_pool(token_a(), token_b(), fees::DEFAULT_FEE_BPS, 10, 0);
+        add_position_as(context, contract, owner(), pool_id, (-1_000, 1_000), &[RESERVE, RESERVE]);
+        pool_id
+    }
+
+    fn reserve_of(contract: &Contract, token_id: &AccountId) -> Balance {
+        let pool = contract.internal_unwrap_pool(MAIN_POOL_ID);
+        let index = pool.tokens().iter().position(|t| t == token_id).unwrap();
+        pool.reserves()[index]
+    }
+
+    // Deposits `amount` of the token for the user and swaps it with `deposit_contract`
+    fn deposit_and_send(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        pool_id: u64,
+        token_id: AccountId,
         amount: Balance,
+        min_amount_out: Balance,
     ) {
-        require!(env::predecessor_account_id() == env::current_account_id(), "Calling from another context is not allowed");
-        ext_token::ext(contract_id)
-            .transfer_from(env::current_account_id(), receiver_id, amount)
-            .then(
-                ext_self::ext(env::current_account_id())
-                    .on_update_balances(from_balance, to_balance),
-            );
+        deposit(context, contract, token_id.clone(), amount);
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        contract.deposit_contract(pool_id, token_id, U128(amount), U128(min_amount_out), None, None);
+        testing_env!(context.attached_deposit(0).build());
     }
 
-    // pub fn callback_update_tickers(&mut self, a_ticker_after: Balance, b_ticker_after: Balance) {
-    pub fn on_update_balances(
-        &mut self,
-        sender_post_balance: Balance, 
-        receiver_post_balance: Balance
-    ) {
-        require!(env::predecessor_account_id() == env::current_account_id(), "Calling from another context is not allowed");
-        self.token_a_meta.total_supply = sender_post_balance;
-        self.token_b_meta.total_supply = receiver_post_balance;
-        self.update_tokens_ratio();
+    #[test]
+    fn test_ft_on_transfer_swap() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        let result = contract.ft_on_transfer(user(), U128(10 * ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + 10 * ONE_TOKEN);
+        assert!(reserve_of(&contract, &token_b()) < RESERVE);
     }
 
-    /// The owner of the contract can transfer a certain amount of tokens A or B to the contract account, thereby changing the ratio K.
-    #[payable]
-    pub fn deposit_token_contract(
-        &mut self, 
-        token_contract_id: AccountId,
-        amount: Balance)
-    {
-        require!(env::predecessor_account_id() == self.owner_id, "Calling from another context is not allowed");
-
-        let d = if token_contract_id == self.token_a {
-            10_u128.pow(self.token_a_meta.decimals as u32)
-        } else if token_contract_id == self.token_b {
-            10_u128.pow(self.token_b_meta.decimals as u32)
-        } else {
-            env::panic_str(format!("Unsupported token contract: {}", token_contract_id).as_str());
-        };
+    #[test]
+    fn test_ft_on_transfer_returns_amount_below_min_amount_out() {
+        let (mut context, mut contract) = setup_contract();
 
-        let token_supply = if token_contract_id == self.token_a {
-            self.token_a_meta.total_supply
-        } else if token_contract_id == self.token_b {
-            self.token_b_meta.total_supply
-        } else {
-            env::panic_str(format!("Unsupported token contract: {}", token_contract_id).as_str());
-        };
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        let amount = U128(10 * ONE_TOKEN);
+        let result = contract.ft_on_transfer(user(), amount, swap_msg(MAIN_POOL_ID, 10 * ONE_TOKEN));
 
-        let final_amout_for_transfer = amount * d;
+        match result {
+            PromiseOrValue::Value(unused) => assert_eq!(unused, amount),
+            PromiseOrValue::Promise(_) => panic!("Expected the whole amount to be returned"),
+        }
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+        assert_eq!(reserve_of(&contract, &token_b()), RESERVE);
+    }
 
-        let post_amount = amount * token_supply;
+    #[test]
+    #[should_panic(expected = "Unsupported token contract id")]
+    fn test_ft_on_transfer_unknown_token() {
+        let (mut context, mut contract) = setup_contract();
 
-        let opposite_post_amount = if token_contract_id == self.token_a {
-            self.token_b_meta.total_supply
-        } else if token_contract_id == self.token_b {
-            self.token_a_meta.total_supply
-        } else {
-            env::panic_str(format!("Unsupported token contract: {}", token_contract_id).as_str());
-        };
-        
-        ext_token::ext(token_contract_id.clone())
-            .transfer_from(
-                self.owner_id.clone(), 
-                env::current_account_id(), 
-                final_amout_for_transfer)
-            .then(
-                ext_self::ext(env::current_account_id())
-                    .on_update_balances(
-                        post_amount, 
-                        opposite_post_amount),
-            );
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.ft_on_transfer(user(), U128(ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+    }
+
+    #[test]
+    fn test_on_swap_payout_failed_keeps_swap() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(10 * ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+        let reserves = contract.tokens_full_info(MAIN_POOL_ID).reserves;
+        let amount_out = RESERVE - reserves[1].0;
+
+        // Another swap moves the pool before the payout is resolved
+        testing_env!(context.predecessor_account_id(token_b()).build());
+        contract.ft_on_transfer(owner(), U128(20 * ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+        let reserves = contract.tokens_full_info(MAIN_POOL_ID).reserves;
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let unused = contract.on_swap_payout(user(), token_b(), U128(amount_out));
+
+        // The swap stays applied and the output is owed to the sender
+        assert_eq!(unused.0, 0);
+        assert_eq!(contract.tokens_full_info(MAIN_POOL_ID).reserves, reserves);
+        assert_eq!(contract.get_pending(user()).get(&token_b()), Some(&U128(amount_out)));
+        assert_eq!(contract.internal_accounted_balance(&token_b()), reserves[1].0 + amount_out);
+    }
+
+    #[test]
+    fn test_deposit_contract_keeps_invariant() {
+        let (mut context, mut contract) = setup_contract();
+        let ratio_before = math::invariant(RESERVE, RESERVE);
+
+        deposit_and_send(&mut context, &mut contract, MAIN_POOL_ID, token_b(), ONE_TOKEN / 3, 1);
+
+        let reserve_a = reserve_of(&contract, &token_a());
+        let reserve_b = reserve_of(&contract, &token_b());
+        assert_eq!(reserve_b, RESERVE + ONE_TOKEN / 3);
+        assert_eq!(RESERVE - reserve_a, math::get_amount_out(ONE_TOKEN / 3, RESERVE, RESERVE, fees::DEFAULT_FEE_BPS));
+        assert!(math::invariant(reserve_a, reserve_b) >= ratio_before);
+
+        let info = contract.tokens_full_info(MAIN_POOL_ID);
+        assert_eq!(info.reserves[0].0, reserve_a);
+        assert_eq!(info.ratio, math::invariant(reserve_a, reserve_b).to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool has no liquidity")]
+    fn test_swap_without_liquidity() {
+        let mut context = get_context(owner());
+        testing_env!(context.build());
+        let mut contract = Contract::new(owner(), token_a(), token_b());
+        testing_env!(context.predecessor_account_id(amm()).build());
+        contract.on_get_metadata(token_a(), Ok(token_metadata("tkn_A", TOTAL_SUPPLY)));
+        contract.on_get_metadata(token_b(), Ok(token_metadata("tkn_B", TOTAL_SUPPLY)));
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+    }
+
+    #[test]
+    fn test_ft_on_transfer_returns_amount_after_deadline() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(token_a()).block_timestamp(2_000).build());
+        let amount = U128(10 * ONE_TOKEN);
+        let result = contract.ft_on_transfer(user(), amount, swap_msg_with_deadline(MAIN_POOL_ID, 1, 1_000));
+
+        match result {
+            PromiseOrValue::Value(unused) => assert_eq!(unused, amount),
+            PromiseOrValue::Promise(_) => panic!("Expected the whole amount to be returned"),
+        }
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+    }
+
+    #[test]
+    #[should_panic(expected = "is less than min amount out")]
+    fn test_deposit_contract_slippage() {
+        let (mut context, mut contract) = setup_contract();
+
+        deposit_and_send(&mut context, &mut contract, MAIN_POOL_ID, token_a(), ONE_TOKEN, ONE_TOKEN);
+    }
+
+    #[test]
+    #[should_panic(expected = "the deadline has passed")]
+    fn test_deposit_contract_deadline() {
+        let (mut context, mut contract) = setup_contract();
+
+        deposit(&mut context, &mut contract, token_a(), ONE_TOKEN);
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).block_timestamp(2_000).build());
+        contract.deposit_contract(MAIN_POOL_ID, token_a(), U128(ONE_TOKEN), U128(1), Some(U64(1_000)), None);
+    }
+
+    #[test]
+    fn test_add_liquidity_first_deposit_locks_minimum() {
+        let (_, contract) = setup_contract();
+
+        assert_eq!(contract.get_shares_total_supply(MAIN_POOL_ID).0, RESERVE);
+        assert_eq!(contract.get_shares(MAIN_POOL_ID, owner()).0, RESERVE - simple_pool::MINIMUM_LIQUIDITY);
+        assert_eq!(contract.ft_balance_of(amm()).0, simple_pool::MINIMUM_LIQUIDITY);
+    }
+
+    #[test]
+    fn test_add_liquidity_proportional() {
+        let (mut context, mut contract) = setup_contract();
+
+        // Only the half of token B is needed to keep the ratio 1:1, the rest stays in the deposit
+        let shares = add_liquidity_as(&mut context, &mut contract, user(), MAIN_POOL_ID, &[10 * ONE_TOKEN, 20 * ONE_TOKEN]);
+
+        assert_eq!(shares.0, 10 * ONE_TOKEN);
+        assert_eq!(contract.get_shares(MAIN_POOL_ID, user()), shares);
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + 10 * ONE_TOKEN);
+        assert_eq!(reserve_of(&contract, &token_b()), RESERVE + 10 * ONE_TOKEN);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 0);
+        assert_eq!(contract.get_deposit(user(), token_b()).0, 10 * ONE_TOKEN);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough deposit of charlie")]
+    fn test_add_liquidity_requires_deposit() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_b(), ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.add_liquidity(MAIN_POOL_ID, vec![U128(ONE_TOKEN), U128(ONE_TOKEN)], None);
+    }
+
+    #[test]
+    fn test_remove_liquidity_pro_rata() {
+        let (mut context, mut contract) = setup_contract();
+
+        // The swap changes the ratio of the reserves
+        deposit_and_send(&mut context, &mut contract, MAIN_POOL_ID, token_a(), 100 * ONE_TOKEN, 1);
+        let reserve_a = reserve_of(&contract, &token_a());
+        let reserve_b = reserve_of(&contract, &token_b());
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        let shares = contract.get_shares(MAIN_POOL_ID, owner()).0 / 2;
+        contract.remove_liquidity(MAIN_POOL_ID, U128(shares), None);
+
+        assert_eq!(reserve_of(&contract, &token_a()), reserve_a - math::mul_div(shares, reserve_a, RESERVE));
+        assert_eq!(reserve_of(&contract, &token_b()), reserve_b - math::mul_div(shares, reserve_b, RESERVE));
+        assert_eq!(contract.get_shares_total_supply(MAIN_POOL_ID).0, RESERVE - shares);
+    }
+
+    #[test]
+    #[should_panic(expected = "The account doesn't have enough shares")]
+    fn test_remove_liquidity_more_than_owned() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.remove_liquidity(MAIN_POOL_ID, U128(ONE_TOKEN), None);
+    }
+
+    #[test]
+    fn test_shares_ft_transfer() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).attached_deposit(1).build());
+        let owner_shares = contract.ft_balance_of(owner()).0;
+        contract.ft_transfer(user(), U128(ONE_TOKEN), None);
+
+        assert_eq!(contract.ft_balance_of(owner()).0, owner_shares - ONE_TOKEN);
+        assert_eq!(contract.ft_balance_of(user()).0, ONE_TOKEN);
+        assert_eq!(contract.ft_total_supply().0, RESERVE);
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                r#"EVENT_JSON:{{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{{"old_owner_id":"{}","new_owner_id":"{}","amount":"{}"}}]}}"#,
+                owner(), user(), ONE_TOKEN
+            )]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "More gas is required")]
+    fn test_shares_ft_transfer_call_not_enough_gas() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).attached_deposit(1).prepaid_gas(Gas(30_000_000_000_000)).build());
+        contract.ft_transfer_call(user(), U128(ONE_TOKEN), None, "".to_string());
+    }
+
+    #[test]
+    fn test_shares_mint_and_burn_events() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.remove_liquidity(MAIN_POOL_ID, U128(ONE_TOKEN), None);
+
+        assert_eq!(
+            get_logs(),
+            vec![
+                format!(
+                    r#"EVENT_JSON:{{"standard":"nep141","version":"1.0.0","event":"ft_burn","data":[{{"owner_id":"{}","amount":"{}"}}]}}"#,
+                    owner(), ONE_TOKEN
+                ),
+                format!(
+                    r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"remove_liquidity","data":[{{"pool_id":0,"account_id":"{}","amounts":["{}","{}"],"shares":"{}","reserves":["{}","{}"]}}]}}"#,
+                    owner(), ONE_TOKEN, ONE_TOKEN, ONE_TOKEN, RESERVE - ONE_TOKEN, RESERVE - ONE_TOKEN
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shares_ft_metadata() {
+        let (_, contract) = setup_contract();
+
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.symbol, "LP-tkn_A-tkn_B");
+        assert_eq!(metadata.decimals, DECIMALS);
+        assert_eq!(metadata.total_supply, RESERVE);
+    }
+
+    #[test]
+    fn test_swap_fee_split() {
+        let (mut context, mut contract) = setup_contract();
+        // A half of the fee goes to the owner
+        contract.set_fee(MAIN_POOL_ID, 100, 5_000);
+
+        let amount_in = 10 * ONE_TOKEN;
+        deposit_and_send(&mut context, &mut contract, MAIN_POOL_ID, token_a(), amount_in, 1);
+
+        let protocol_fee = amount_in / 100 / 2;
+        let fee_info = contract.get_fee_info(MAIN_POOL_ID);
+        assert_eq!(fee_info.protocol_fees[0].0, protocol_fee);
+        assert_eq!(fee_info.protocol_fees[1].0, 0);
+        // The LP part of the fee stays in the reserves
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + amount_in - protocol_fee);
+        assert_eq!(
+            reserve_of(&contract, &token_b()),
+            RESERVE - math::get_amount_out(amount_in, RESERVE, RESERVE, 100)
+        );
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.withdraw_protocol_fees(MAIN_POOL_ID);
+        assert_eq!(contract.get_fee_info(MAIN_POOL_ID).protocol_fees[0].0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The method requires the role FeeManager")]
+    fn test_set_fee_not_owner() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.set_fee(MAIN_POOL_ID, 100, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The fee can't exceed")]
+    fn test_set_fee_too_high() {
+        let (_, mut contract) = setup_contract();
+
+        contract.set_fee(MAIN_POOL_ID, fees::MAX_FEE_BPS + 1, 0);
+    }
+
+    #[test]
+    fn test_add_pool_is_independent() {
+        let (mut context, mut contract) = setup_contract();
+        resolve_metadata_c(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        let pool_id = contract.add_pool(token_a(), token_c(), 100);
+        assert_eq!(pool_id, 1);
+        assert_eq!(contract.get_number_of_pools(), 2);
+        assert_eq!(contract.get_fee_info(pool_id).fee_bps, 100);
+
+        add_liquidity_as(&mut context, &mut contract, owner(), pool_id, &[RESERVE, 2 * RESERVE]);
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(10 * ONE_TOKEN), swap_msg(pool_id, 1));
+
+        let info = contract.tokens_full_info(pool_id);
+        assert_eq!(info.reserves[0].0, RESERVE + 10 * ONE_TOKEN);
+        assert_eq!(info.reserves[1].0, 2 * RESERVE - math::get_amount_out(10 * ONE_TOKEN, RESERVE, 2 * RESERVE, 100));
+        // Neither the reserves nor the shares of the main pool are affected
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+        assert_eq!(contract.ft_total_supply().0, RESERVE);
+        assert_eq!(contract.get_shares(pool_id, owner()).0, contract.get_shares_total_supply(pool_id).0 - simple_pool::MINIMUM_LIQUIDITY);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported token contract id")]
+    fn test_ft_on_transfer_token_not_in_pool() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = contract.add_pool(token_a(), token_c(), fees::DEFAULT_FEE_BPS);
+
+        testing_env!(context.predecessor_account_id(token_b()).build());
+        contract.ft_on_transfer(user(), U128(ONE_TOKEN), swap_msg(pool_id, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool 1 doesn't exist")]
+    fn test_unknown_pool() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        contract.deposit_contract(1, token_a(), U128(ONE_TOKEN), U128(1), None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The method requires the role PoolCreator")]
+    fn test_add_pool_not_owner() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.add_pool(token_a(), token_c(), fees::DEFAULT_FEE_BPS);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_swap_route() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_pool_b_c(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        let amount_in = 10 * ONE_TOKEN;
+        let hops = [hop(MAIN_POOL_ID, token_a(), token_b()), hop(pool_id, token_b(), token_c())];
+        let result = contract.ft_on_transfer(user(), U128(amount_in), route_msg(&hops, 1));
+
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        let amount_b = math::get_amount_out(amount_in, RESERVE, RESERVE, fees::DEFAULT_FEE_BPS);
+        let amount_c = math::get_amount_out(amount_b, RESERVE, RESERVE, fees::DEFAULT_FEE_BPS);
+        let info = contract.tokens_full_info(pool_id);
+        assert_eq!(info.reserves[0].0, RESERVE + amount_b);
+        assert_eq!(info.reserves[1].0, RESERVE - amount_c);
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + amount_in);
+    }
+
+    #[test]
+    #[should_panic(expected = "ft_on_transfer: amount out")]
+    fn test_ft_on_transfer_swap_route_below_min_amount_out() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_pool_b_c(&mut context, &mut contract);
+
+        // The panic makes the token contract refund the whole amount
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        let hops = [hop(MAIN_POOL_ID, token_a(), token_b()), hop(pool_id, token_b(), token_c())];
+        contract.ft_on_transfer(user(), U128(10 * ONE_TOKEN), route_msg(&hops, 10 * ONE_TOKEN));
+    }
+
+    #[test]
+    fn test_deposit_route() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_pool_b_c(&mut context, &mut contract);
+
+        deposit(&mut context, &mut contract, token_a(), ONE_TOKEN);
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        let hops = vec![hop(MAIN_POOL_ID, token_a(), token_b()), hop(pool_id, token_b(), token_c())];
+        contract.deposit_route(hops, U128(ONE_TOKEN), U128(1), None);
+
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + ONE_TOKEN);
+        assert!(contract.tokens_full_info(pool_id).reserves[1].0 < RESERVE);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Hop 1 doesn't continue the route")]
+    fn test_swap_route_broken() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_pool_b_c(&mut context, &mut contract);
+
+        deposit(&mut context, &mut contract, token_a(), ONE_TOKEN);
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        let hops = vec![hop(MAIN_POOL_ID, token_a(), token_b()), hop(pool_id, token_c(), token_b())];
+        contract.deposit_route(hops, U128(ONE_TOKEN), U128(1), None);
+    }
+
+    #[test]
+    fn test_stable_swap_normalizes_decimals() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_stable_pool_a_c(&mut context, &mut contract, 100);
+        assert_eq!(contract.tokens_full_info(pool_id).pool_kind, "STABLE_SWAP");
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        let amount_in = 10 * ONE_TOKEN;
+        let msg = format!("{{\"action\":\"swap\",\"pool_id\":{},\"min_amount_out\":\"1\"}}", pool_id);
+        contract.ft_on_transfer(user(), U128(amount_in), msg);
+
+        let info = contract.tokens_full_info(pool_id);
+        let amount_out = 1_000 * ONE_STABLE - info.reserves[1].0;
+        // The price stays close to 1:1, much better than the constant product
+        assert!(amount_out > 10 * ONE_STABLE * 9_990 / 10_000 && amount_out < 10 * ONE_STABLE);
+        assert!(amount_out > math::get_amount_out(10 * ONE_STABLE, 1_000 * ONE_STABLE, 1_000 * ONE_STABLE, 4));
+        assert_eq!(info.reserves[0].0, RESERVE + amount_in);
+    }
+
+    #[test]
+    fn test_stable_add_liquidity_imbalanced_pays_fee() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_stable_pool_a_c(&mut context, &mut contract, 100);
+        let total_supply = contract.get_shares_total_supply(pool_id).0;
+
+        let balanced = add_liquidity_as(&mut context, &mut contract, user(), pool_id, &[10 * ONE_TOKEN, 10 * ONE_STABLE]).0;
+        assert!(balanced.abs_diff(total_supply / 100) <= 1);
+
+        let imbalanced = add_liquidity_as(&mut context, &mut contract, user(), pool_id, &[19 * ONE_TOKEN, ONE_STABLE]).0;
+        assert!(imbalanced < balanced);
+    }
+
+    #[test]
+    fn test_stable_ramp_amp() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_stable_pool_a_c(&mut context, &mut contract, 100);
+
+        let duration = 2 * stable_swap_pool::MIN_RAMP_DURATION;
+        contract.ramp_amp(pool_id, 1_000, U64(duration));
+
+        testing_env!(context.block_timestamp(duration / 2).build());
+        assert_eq!(contract.get_amp(pool_id), 550);
+
+        contract.stop_ramp_amp(pool_id);
+        testing_env!(context.block_timestamp(duration).build());
+        assert_eq!(contract.get_amp(pool_id), 550);
+    }
+
+    #[test]
+    #[should_panic(expected = "The amplification can change at most 10 times")]
+    fn test_stable_ramp_amp_too_big_change() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_stable_pool_a_c(&mut context, &mut contract, 100);
+
+        contract.ramp_amp(pool_id, 1_001, U64(stable_swap_pool::MIN_RAMP_DURATION));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not cached, register the token first")]
+    fn test_add_stable_pool_without_metadata() {
+        let (_, mut contract) = setup_contract();
+
+        contract.add_stable_pool(vec![token_a(), token_c()], 4, 100);
+    }
+
+    #[test]
+    fn test_weighted_swap() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_weighted_pool_a_b_c(&mut context, &mut contract);
+        assert_eq!(contract.tokens_full_info(pool_id).pool_kind, "WEIGHTED_POOL");
+
+        // At the equal reserves the token with the weight of 50% is worth 2.5 times more than the one of 20%
+        let amount_in = ONE_TOKEN;
+        let quote = contract.get_return(pool_id, token_a(), U128(amount_in), Some(token_c())).0;
+        let amount_in_with_fee = amount_in - math::fee_of(amount_in, fees::DEFAULT_FEE_BPS);
+        assert!(quote < amount_in_with_fee * 5 / 2 && quote > amount_in_with_fee * 249 / 100);
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(amount_in), swap_msg_to(pool_id, token_c(), quote));
+
+        let info = contract.tokens_full_info(pool_id);
+        assert_eq!(info.reserves[0].0, RESERVE + amount_in);
+        assert_eq!(info.reserves[1].0, RESERVE);
+        assert_eq!(info.reserves[2].0, RESERVE - quote);
+    }
+
+    #[test]
+    #[should_panic(expected = "The output token should be given for a pool of more than two tokens")]
+    fn test_weighted_swap_without_token_out() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_weighted_pool_a_b_c(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(ONE_TOKEN), swap_msg(pool_id, 1));
+    }
+
+    #[test]
+    fn test_weighted_add_and_remove_liquidity() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_weighted_pool_a_b_c(&mut context, &mut contract);
+        let total_supply = contract.get_shares_total_supply(pool_id).0;
+        // The invariant of the equal reserves is the reserve itself
+        assert!(total_supply.abs_diff(RESERVE) < RESERVE / 1_000_000_000);
+
+        let shares =
+            add_liquidity_as(&mut context, &mut contract, user(), pool_id, &[10 * ONE_TOKEN, 20 * ONE_TOKEN, 10 * ONE_TOKEN]).0;
+        assert_eq!(shares, math::mul_div(10 * ONE_TOKEN, total_supply, RESERVE));
+        let reserves: Vec<Balance> = contract.tokens_full_info(pool_id).reserves.iter().map(|r| r.0).collect();
+        // Only the proportional part of the amount of B is taken
+        assert!(reserves.iter().all(|r| *r == reserves[0]));
+        assert!(reserves[0] >= RESERVE + 10 * ONE_TOKEN);
+
+        contract.remove_liquidity(pool_id, U128(shares), None);
+        assert_eq!(contract.get_shares(pool_id, user()).0, 0);
+        let info = contract.tokens_full_info(pool_id);
+        assert!(info.reserves.iter().all(|r| r.0 >= RESERVE && r.0 - RESERVE <= 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "The weights should sum up to 10000 bps")]
+    fn test_add_weighted_pool_invalid_weights() {
+        let (_, mut contract) = setup_contract();
+
+        contract.add_weighted_pool(vec![token_a(), token_b()], vec![8_000, 1_000], fees::DEFAULT_FEE_BPS);
+    }
+
+    #[test]
+    fn test_concentrated_add_position_and_swap() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+
+        let info = contract.tokens_full_info(pool_id);
+        assert_eq!(info.pool_kind, "CONCENTRATED_POOL");
+        let (reserve_a, reserve_b) = (info.reserves[0].0, info.reserves[1].0);
+        assert!(reserve_a <= RESERVE && reserve_a.abs_diff(reserve_b) <= 1);
+
+        // The liquidity of the range is deeper than the one of the same reserves over the full range
+        let amount_in = 10 * ONE_TOKEN;
+        let quote = contract.get_return(pool_id, token_a(), U128(amount_in), Some(token_b())).0;
+        assert!(quote > math::get_amount_out(amount_in, reserve_a, reserve_b, fees::DEFAULT_FEE_BPS));
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(amount_in), swap_msg(pool_id, quote));
+
+        let info = contract.tokens_full_info(pool_id);
+        assert_eq!(info.reserves[0].0, reserve_a + amount_in);
+        assert_eq!(info.reserves[1].0, reserve_b - quote);
+        assert!(contract.get_concentrated_pool_info(pool_id).tick < 0);
+    }
+
+    #[test]
+    fn test_concentrated_swap_crosses_ticks() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+        let wide_liquidity = contract.get_position(pool_id, 0).liquidity.0;
+
+        add_position_as(&mut context, &mut contract, user(), pool_id, (-100, 100), &[10 * ONE_TOKEN, 10 * ONE_TOKEN]);
+        let narrow_liquidity = contract.get_position(pool_id, 1).liquidity.0;
+        assert_eq!(contract.get_concentrated_pool_info(pool_id).liquidity.0, wide_liquidity + narrow_liquidity);
+
+        // The swap moves the price below the narrow range
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(150 * ONE_TOKEN), swap_msg(pool_id, 1));
+        let info = contract.get_concentrated_pool_info(pool_id);
+        assert!(info.tick < -100);
+        assert_eq!(info.liquidity.0, wide_liquidity);
+
+        // And the swap back enters it again
+        testing_env!(context.predecessor_account_id(token_b()).build());
+        contract.ft_on_transfer(user(), U128(150 * ONE_TOKEN), swap_msg(pool_id, 1));
+        let info = contract.get_concentrated_pool_info(pool_id);
+        assert!(info.tick >= -100 && info.tick < 100);
+        assert_eq!(info.liquidity.0, wide_liquidity + narrow_liquidity);
+    }
+
+    #[test]
+    fn test_concentrated_position_fees() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(10 * ONE_TOKEN), swap_msg(pool_id, 1));
+        testing_env!(context.predecessor_account_id(token_b()).build());
+        contract.ft_on_transfer(user(), U128(10 * ONE_TOKEN), swap_msg(pool_id, 1));
+
+        // The only position earns the whole fee of both swaps, less the rounding of the fee growth
+        let position = contract.get_position(pool_id, 0);
+        let fee = math::fee_of(10 * ONE_TOKEN, fees::DEFAULT_FEE_BPS);
+        assert!(position.tokens_owed.iter().all(|owed| owed.0 <= fee && fee - owed.0 < fee / 1_000_000_000));
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.remove_position(pool_id, 0, position.liquidity, None);
+        assert!(contract.get_positions(pool_id, owner()).is_empty());
+        // Only the rounding dust stays in the pool
+        let info = contract.tokens_full_info(pool_id);
+        assert!(info.reserves.iter().all(|reserve| reserve.0 < fee / 1_000_000_000));
+        assert_eq!(contract.get_concentrated_pool_info(pool_id).liquidity.0, 0);
+    }
+
+    #[test]
+    fn test_concentrated_positions_per_owner() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+        // Out of range positions take only one of the tokens, the other one stays in the deposit
+        add_position_as(&mut context, &mut contract, owner(), pool_id, (1_000, 2_000), &[ONE_TOKEN, ONE_TOKEN]);
+        assert_eq!(contract.get_deposit(owner(), token_a()).0, 0);
+        assert_eq!(contract.get_deposit(owner(), token_b()).0, ONE_TOKEN);
+        add_position_as(&mut context, &mut contract, user(), pool_id, (-2_000, -1_000), &[ONE_TOKEN, ONE_TOKEN]);
+
+        let positions = contract.get_positions(pool_id, owner());
+        assert_eq!(positions.iter().map(|p| p.position_id).collect::<Vec<_>>(), vec![0, 1]);
+        let positions = contract.get_positions(pool_id, user());
+        assert_eq!(positions.len(), 1);
+        assert_eq!((positions[0].tick_lower, positions[0].tick_upper), (-2_000, -1_000));
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        let liquidity = contract.get_position(pool_id, 1).liquidity;
+        contract.remove_position(pool_id, 1, liquidity, None);
+        assert_eq!(contract.get_positions(pool_id, owner()).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "The ticks should be divisible by the tick spacing 10")]
+    fn test_add_position_invalid_ticks() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+        add_position_as(&mut context, &mut contract, owner(), pool_id, (-15, 15), &[ONE_TOKEN, ONE_TOKEN]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The position doesn't belong to the account")]
+    fn test_remove_position_not_owner() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.remove_position(pool_id, 0, U128(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough deposit of charlie")]
+    fn test_add_position_requires_deposit() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.add_position(pool_id, -100, 100, vec![U128(ONE_TOKEN), U128(ONE_TOKEN)], None);
+    }
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn assert_price_close(price: Balance, expected: Balance) {
+        assert!(price.abs_diff(expected) <= expected / 1_000_000_000, "{} != {}", price, expected);
+    }
+
+    #[test]
+    fn test_twap_of_constant_price() {
+        let (mut context, contract) = setup_contract();
+
+        testing_env!(context.block_timestamp(100 * SECOND).build());
+        let twap = contract.get_twap(MAIN_POOL_ID, 100);
+        assert_eq!(twap, vec![U128(math::PRICE_PRECISION), U128(math::PRICE_PRECISION)]);
+    }
+
+    #[test]
+    fn test_twap_after_swap() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(token_a()).block_timestamp(100 * SECOND).build());
+        contract.ft_on_transfer(user(), U128(100 * ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+        let price = math::mul_div(reserve_of(&contract, &token_a()), math::PRICE_PRECISION, reserve_of(&contract, &token_b()));
+
+        // The new price for the last 50 seconds, the geometric mean of the old and the new price for 200 seconds
+        testing_env!(context.block_timestamp(200 * SECOND).build());
+        assert_price_close(contract.get_twap(MAIN_POOL_ID, 50)[1].0, price);
+        let mean = math::sqrt_of_product(price, math::PRICE_PRECISION);
+        assert_price_close(contract.get_twap(MAIN_POOL_ID, 200)[1].0, mean);
+
+        // The window that starts between the observations is interpolated
+        let twap = contract.get_twap(MAIN_POOL_ID, 150)[1].0;
+        assert!(twap > mean && twap < price);
+    }
+
+    #[test]
+    fn test_twap_of_weighted_pool() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_weighted_pool_a_b_c(&mut context, &mut contract);
+
+        testing_env!(context.block_timestamp(10 * SECOND).build());
+        let twap = contract.get_twap(pool_id, 10);
+        assert_eq!(twap.len(), 3);
+        assert_eq!(twap[0].0, math::PRICE_PRECISION);
+        let pool = contract.internal_unwrap_pool(pool_id);
+        for (i, token_id) in [token_b(), token_c()].iter().enumerate() {
+            assert_price_close(twap[i + 1].0, pool.spot_price(token_id, &token_a()).as_u128());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "The window is longer than the observations history")]
+    fn test_twap_window_longer_than_history() {
+        let (mut context, mut contract) = setup_contract();
+
+        // The swaps in different blocks overwrite the oldest observations
+        for i in 1..=MAX_OBSERVATIONS as u64 {
+            testing_env!(context.predecessor_account_id(token_a()).block_timestamp(i * SECOND).build());
+            contract.ft_on_transfer(user(), U128(ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+        }
+
+        contract.get_twap(MAIN_POOL_ID, MAX_OBSERVATIONS as u64 - 1);
+        contract.get_twap(MAIN_POOL_ID, MAX_OBSERVATIONS as u64);
+    }
+
+    #[test]
+    fn test_deposit_and_swap() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 10 * ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        let quote = contract.get_return(MAIN_POOL_ID, token_a(), U128(4 * ONE_TOKEN), Some(token_b()));
+        let amount_out = contract.swap(MAIN_POOL_ID, token_a(), U128(4 * ONE_TOKEN), None, quote, None);
+
+        assert_eq!(amount_out, quote);
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + 4 * ONE_TOKEN);
+        assert_eq!(reserve_of(&contract, &token_b()), RESERVE - quote.0);
+        let deposits = contract.get_deposits(user());
+        assert_eq!(deposits.get(&token_a()), Some(&U128(6 * ONE_TOKEN)));
+        assert_eq!(deposits.get(&token_b()), Some(&quote));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough deposit of")]
+    fn test_swap_more_than_deposit() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.swap(MAIN_POOL_ID, token_a(), U128(2 * ONE_TOKEN), None, U128(1), None);
+    }
+
+    #[test]
+    fn test_deposit_swap_route() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_pool_b_c(&mut context, &mut contract);
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        let hops = vec![hop(MAIN_POOL_ID, token_a(), token_b()), hop(pool_id, token_b(), token_c())];
+        let amount_out = contract.swap_route(hops, U128(10 * ONE_TOKEN), U128(1), None);
+
+        let amount_b = math::get_amount_out(10 * ONE_TOKEN, RESERVE, RESERVE, fees::DEFAULT_FEE_BPS);
+        assert_eq!(amount_out.0, math::get_amount_out(amount_b, RESERVE, RESERVE, fees::DEFAULT_FEE_BPS));
+        let deposits = contract.get_deposits(user());
+        assert_eq!(deposits.len(), 1);
+        assert_eq!(deposits.get(&token_c()), Some(&amount_out));
+    }
+
+    #[test]
+    fn test_withdraw_failed_restores_deposit() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        contract.withdraw(token_a(), U128(4 * ONE_TOKEN));
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 6 * ONE_TOKEN);
+
+        testing_env!(
+            context.predecessor_account_id(amm()).attached_deposit(0).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.on_withdraw(user(), token_a(), U128(4 * ONE_TOKEN));
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 10 * ONE_TOKEN);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough deposit of")]
+    fn test_deposit_contract_without_deposit() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        contract.deposit_contract(MAIN_POOL_ID, token_a(), U128(ONE_TOKEN), U128(1), None, None);
+    }
+
+    #[test]
+    fn test_deposit_contract_payout_failed_refunds_to_deposit() {
+        let (mut context, mut contract) = setup_contract();
+        deposit_and_send(&mut context, &mut contract, MAIN_POOL_ID, token_a(), 10 * ONE_TOKEN, 1);
+        let amount_out = RESERVE - reserve_of(&contract, &token_b());
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 0);
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.on_withdraw(user(), token_b(), U128(amount_out));
+
+        // The swap is final, the output is kept in the deposit
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + 10 * ONE_TOKEN);
+        assert_eq!(contract.get_deposit(user(), token_b()).0, amount_out);
+        assert!(get_logs().iter().any(|log| log.contains("\"transfer_failed\"")));
+    }
+
+    #[test]
+    fn test_on_remove_liquidity_failed_records_pending() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.remove_liquidity(MAIN_POOL_ID, U128(ONE_TOKEN), None);
+        let (reserve_a, reserve_b) = (reserve_of(&contract, &token_a()), reserve_of(&contract, &token_b()));
+        let amounts = vec![U128(RESERVE - reserve_a), U128(RESERVE - reserve_b)];
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed],
+        );
+        contract.on_remove_liquidity(MAIN_POOL_ID, owner(), amounts.clone());
+
+        let pending = contract.get_pending(owner());
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending.get(&token_b()), Some(&amounts[1]));
+        assert_eq!((reserve_of(&contract, &token_a()), reserve_of(&contract, &token_b())), (reserve_a, reserve_b));
+    }
+
+    #[test]
+    fn test_claim_pending() {
+        let (mut context, mut contract) = setup_contract();
+        contract.internal_add_pending("remove_liquidity", &owner(), &token_b(), ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(owner()).attached_deposit(1).build());
+        contract.claim_pending(token_b());
+        assert!(contract.get_pending(owner()).is_empty());
+
+        // The claim that fails again stays pending
+        testing_env!(
+            context.predecessor_account_id(amm()).attached_deposit(0).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.on_claim_pending(owner(), token_b(), U128(ONE_TOKEN));
+        assert_eq!(contract.get_pending(owner()).get(&token_b()), Some(&U128(ONE_TOKEN)));
+    }
+
+    #[test]
+    #[should_panic(expected = "There is no pending amount of")]
+    fn test_claim_pending_nothing() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).attached_deposit(1).build());
+        contract.claim_pending(token_a());
+    }
+
+    #[test]
+    fn test_deposit_contract_concentrated_pool() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+        let reserves = contract.tokens_full_info(pool_id).reserves;
+
+        deposit_and_send(&mut context, &mut contract, pool_id, token_a(), ONE_TOKEN, 1);
+        let info = contract.tokens_full_info(pool_id);
+        assert_eq!(info.reserves[0].0, reserves[0].0 + ONE_TOKEN);
+        assert!(info.reserves[1].0 < reserves[1].0);
+    }
+
+    fn balance_result(balance: Balance) -> PromiseResult {
+        PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(balance)).unwrap())
+    }
+
+    // Returns the totals of the tokens that `sync` and `skim` pass to their callbacks
+    fn totals_of(contract: &Contract, token_ids: &[AccountId]) -> Vec<U128> {
+        token_ids.iter().map(|token_id| U128(contract.internal_accounted_balance(token_id))).collect()
+    }
+
+    #[test]
+    fn test_on_sync_updates_reserves() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+
+        // A donation of A is added to the reserve, a loss of B is taken from it, the deposit is kept
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![balance_result(RESERVE + 15 * ONE_TOKEN), balance_result(RESERVE - ONE_TOKEN)],
+        );
+        contract.on_sync(MAIN_POOL_ID, owner(), totals_of(&contract, &[token_a(), token_b()]));
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + 5 * ONE_TOKEN);
+        assert_eq!(reserve_of(&contract, &token_b()), RESERVE - ONE_TOKEN);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 10 * ONE_TOKEN);
+        assert_eq!(contract.internal_accounted_balance(&token_a()), RESERVE + 15 * ONE_TOKEN);
+    }
+
+    #[test]
+    fn test_accounted_balance_follows_pools() {
+        let (mut context, mut contract) = setup_contract();
+        contract.set_fee(MAIN_POOL_ID, fees::DEFAULT_FEE_BPS, 5_000);
+        let pool_id = add_pool_b_c(&mut context, &mut contract);
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        let hops = vec![hop(MAIN_POOL_ID, token_a(), token_b()), hop(pool_id, token_b(), token_c())];
+        contract.swap_route(hops, U128(4 * ONE_TOKEN), U128(1), None);
+
+        // Every token the AMM holds is owed to the pools or the deposits
+        let held = |contract: &Contract, pool_id: u64, index: usize| {
+            contract.tokens_full_info(pool_id).reserves[index].0 + contract.get_fee_info(pool_id).protocol_fees[index].0
+        };
+        assert_eq!(contract.internal_accounted_balance(&token_a()), held(&contract, MAIN_POOL_ID, 0) + 6 * ONE_TOKEN);
+        assert_eq!(
+            contract.internal_accounted_balance(&token_b()),
+            held(&contract, MAIN_POOL_ID, 1) + held(&contract, pool_id, 0)
+        );
+        assert_eq!(
+            contract.internal_accounted_balance(&token_c()),
+            held(&contract, pool_id, 1) + contract.get_deposit(user(), token_c()).0
+        );
+    }
+
+    #[test]
+    fn test_on_sync_keeps_reserve_of_unknown_balance() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed, balance_result(RESERVE + ONE_TOKEN)],
+        );
+        contract.on_sync(MAIN_POOL_ID, owner(), totals_of(&contract, &[token_a(), token_b()]));
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+        assert_eq!(reserve_of(&contract, &token_b()), RESERVE + ONE_TOKEN);
+    }
+
+    #[test]
+    #[should_panic(expected = "The method requires the role Keeper")]
+    fn test_sync_without_keeper_role() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.sync(MAIN_POOL_ID);
+    }
+
+    #[test]
+    fn test_skim_surplus() {
+        let (mut context, mut contract) = setup_contract();
+        contract.internal_add_pending("remove_liquidity", &user(), &token_b(), ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.skim(vec![token_b(), token_a(), token_a()], user());
+        let totals = totals_of(&contract, &[token_a(), token_b()]);
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![balance_result(RESERVE + 3 * ONE_TOKEN), balance_result(RESERVE + ONE_TOKEN)],
+        );
+        contract.on_skim(vec![token_a(), token_b()], totals, user());
+
+        // Only the surplus of A is sent, the balance of B is owed to the pool and the pending amount
+        let receipts = near_sdk::test_utils::get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].receiver_id, token_a());
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+        assert_eq!(contract.get_transfers_in_flight(token_a()), 1);
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        contract.on_skim_sent(token_a(), user(), U128(3 * ONE_TOKEN));
+        assert_eq!(contract.get_transfers_in_flight(token_a()), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The transfers of charlie are in flight")]
+    fn test_sync_with_transfer_in_flight() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        contract.withdraw(token_a(), U128(ONE_TOKEN));
+        assert_eq!(contract.get_transfers_in_flight(token_a()), 1);
+
+        testing_env!(context.predecessor_account_id(owner()).attached_deposit(0).build());
+        contract.sync(MAIN_POOL_ID);
+    }
+
+    #[test]
+    fn test_on_skim_skips_changed_accounting() {
+        let (mut context, mut contract) = setup_contract();
+        let totals = totals_of(&contract, &[token_a()]);
+
+        // The deposit lands between the query of the balance and the callback
+        deposit(&mut context, &mut contract, token_a(), ONE_TOKEN);
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![balance_result(RESERVE + ONE_TOKEN)],
+        );
+        contract.on_skim(vec![token_a()], totals, user());
+        assert!(near_sdk::test_utils::get_created_receipts().is_empty());
+    }
+
+    #[test]
+    fn test_granted_role() {
+        let (mut context, mut contract) = setup_contract();
+        assert!(contract.has_role(owner(), Role::Keeper));
+        assert!(!contract.has_role(user(), Role::FeeManager));
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.grant_role(user(), Role::FeeManager);
+        assert!(contract.has_role(user(), Role::FeeManager));
+        assert!(!contract.has_role(user(), Role::PoolCreator));
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.set_fee(MAIN_POOL_ID, 100, 0);
+        assert_eq!(contract.get_fee_info(MAIN_POOL_ID).fee_bps, 100);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.revoke_role(user(), Role::FeeManager);
+        assert!(!contract.has_role(user(), Role::FeeManager));
+        assert!(contract.get_roles(user()).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The method requires the role FeeManager")]
+    fn test_revoked_role() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.grant_role(user(), Role::FeeManager);
+        contract.revoke_role(user(), Role::FeeManager);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.set_fee(MAIN_POOL_ID, 100, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_grant_role_not_owner() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.grant_role(user(), Role::PoolCreator);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.grant_role(user(), Role::FeeManager);
+    }
+
+    #[test]
+    #[should_panic(expected = "The ownership is transferred with propose_owner and accept_owner")]
+    fn test_grant_owner_role() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.grant_role(user(), Role::Owner);
+    }
+
+    #[test]
+    fn test_transfer_ownership() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.propose_owner(user());
+        assert_eq!(contract.get_owner(), owner());
+        assert_eq!(contract.get_proposed_owner(), Some(user()));
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.accept_owner();
+        assert_eq!(contract.get_owner(), user());
+        assert_eq!(contract.get_proposed_owner(), None);
+        assert!(contract.has_role(user(), Role::Owner));
+        assert!(!contract.has_role(owner(), Role::Owner));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the proposed owner can accept the ownership")]
+    fn test_accept_owner_not_proposed() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.propose_owner(user());
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.accept_owner();
+    }
+
+    #[test]
+    #[should_panic(expected = "The AMM is paused")]
+    fn test_pause_blocks_swaps() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.pause();
+        assert!(contract.is_paused());
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.swap(MAIN_POOL_ID, token_a(), U128(ONE_TOKEN), None, U128(1), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The AMM is paused")]
+    fn test_pause_blocks_deposits() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.pause();
+
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+    }
+
+    #[test]
+    fn test_paused_pool_allows_remove_liquidity() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.pause_pool(MAIN_POOL_ID);
+        assert_eq!(
+            get_logs(),
+            vec![format!(
+                r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"paused","data":[{{"pool_id":{},"paused":true,"account_id":"{}"}}]}}"#,
+                MAIN_POOL_ID, owner()
+            )]
+        );
+        assert!(contract.tokens_full_info(MAIN_POOL_ID).paused);
+        assert!(!contract.is_paused());
+
+        contract.remove_liquidity(MAIN_POOL_ID, U128(100 * ONE_TOKEN), None);
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE - 100 * ONE_TOKEN);
+
+        contract.unpause_pool(MAIN_POOL_ID);
+        assert!(!contract.tokens_full_info(MAIN_POOL_ID).paused);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool 0 is paused")]
+    fn test_paused_pool_blocks_add_liquidity() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.pause_pool(MAIN_POOL_ID);
+        add_liquidity_as(&mut context, &mut contract, owner(), MAIN_POOL_ID, &[ONE_TOKEN, ONE_TOKEN]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The method requires the role Pauser")]
+    fn test_pause_without_pauser_role() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.pause_pool(MAIN_POOL_ID);
+    }
+
+    #[test]
+    fn test_circuit_breaker_pauses_pool() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), 20 * ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.set_circuit_breaker(MAIN_POOL_ID, Some(100));
+
+        // A move of the price below 1% keeps the pool open
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.swap(MAIN_POOL_ID, token_a(), U128(ONE_TOKEN), None, U128(1), None);
+        assert!(!contract.is_pool_paused(MAIN_POOL_ID));
+
+        // A move of about 2% is executed and pauses the pool
+        contract.swap(MAIN_POOL_ID, token_a(), U128(10 * ONE_TOKEN), None, U128(1), None);
+        assert!(contract.is_pool_paused(MAIN_POOL_ID));
+        assert!(get_logs().iter().any(|log| log.contains(r#""event":"paused""#) && log.contains("the circuit breaker tripped")));
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 9 * ONE_TOKEN);
+    }
+
+    #[test]
+    fn test_migrate_from_v0() {
+        let mut context = get_context(amm());
+        testing_env!(context.build());
+
+        // The state of the first deployment: the metadata of token A is resolved, of token B is not
+        let mut tokens = LookupMap::new(b"t".to_vec());
+        tokens.insert(&token_a(), &token_metadata("tkn_A", TOTAL_SUPPLY));
+        let old = upgrade::ContractV0 {
+            owner_id: owner(),
+            token_a: token_a(),
+            token_a_meta: token_metadata("tkn_A", TOTAL_SUPPLY),
+            token_b: token_b(),
+            token_b_meta: FungibleTokenMetadata::default(),
+            tokens_ratio: 1_000_000,
+            tokens,
+        };
+        env::state_write(&old);
+
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_owner(), owner());
+        assert_eq!(contract.get_number_of_pools(), 1);
+        let info = contract.tokens_full_info(MAIN_POOL_ID);
+        assert_eq!(info.pool_kind, "SIMPLE_POOL");
+        assert_eq!(info.reserves, vec![U128(0), U128(0)]);
+        assert_eq!(info.tokens[0].ticker, "tkn_A");
+        assert_eq!(info.tokens[1].ticker, "");
+        assert_eq!(
+            env::storage_read(upgrade::STATE_VERSION_KEY),
+            Some(upgrade::CURRENT_STATE_VERSION.try_to_vec().unwrap())
+        );
+
+        // The metadata of token B is queried again
+        testing_env!(context.predecessor_account_id(amm()).build());
+        let mut contract = contract;
+        contract.on_get_metadata(token_b(), Ok(token_metadata("tkn_B", TOTAL_SUPPLY)));
+        assert_eq!(contract.tokens_full_info(MAIN_POOL_ID).tokens[1].ticker, "tkn_B");
+    }
+
+    #[test]
+    fn test_migrate_current_state() {
+        let (mut context, contract) = setup_contract();
+        env::state_write(&contract);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        let contract = Contract::migrate();
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+        assert_eq!(contract.get_shares(MAIN_POOL_ID, owner()).0, RESERVE - simple_pool::MINIMUM_LIQUIDITY);
+    }
+
+    // Undoes what the old layouts didn't keep: the owed totals of the pools and the indexes of the LP holders
+    // and of the tokens of the pools
+    fn drop_to_legacy_accounting(contract: &mut Contract) {
+        for pool in contract.pools.iter() {
+            for ((token_id, reserve), protocol_fee) in pool.tokens().iter().zip(pool.reserves()).zip(pool.protocol_fees()) {
+                let total = contract.internal_totals.get(token_id).unwrap();
+                contract.internal_totals.insert(token_id, &(total - reserve - protocol_fee));
+            }
+        }
+        contract.account_pools.remove(&owner());
+        for token_id in [token_a(), token_b()] {
+            contract.pool_tokens.remove(&token_id);
+        }
+    }
+
+    fn write_state_v1(mut contract: Contract) {
+        drop_to_legacy_accounting(&mut contract);
+        let old = upgrade::ContractV1 {
+            owner_id: contract.owner_id,
+            proposed_owner_id: contract.proposed_owner_id,
+            roles: contract.roles,
+            tokens: contract.tokens,
+            pools: contract.pools,
+            oracles: contract.oracles,
+            deposits: contract.deposits,
+            pending: contract.pending,
+            internal_totals: contract.internal_totals,
+            paused: contract.paused,
+            paused_pools: contract.paused_pools,
+            circuit_breakers: contract.circuit_breakers,
+        };
+        env::state_write(&old);
+        env::storage_write(upgrade::STATE_VERSION_KEY, &1u32.try_to_vec().unwrap());
+    }
+
+    // Writes the state in the layout with a book of the limit orders per pool, the orders reserved no storage
+    fn write_state_v5(mut contract: Contract, loan_in_flight: bool) {
+        drop_to_legacy_accounting(&mut contract);
+        let mut order_book = LookupMap::new(StorageKey::OrderBook.try_to_vec().unwrap());
+        for ((pool_id, token_in, min_price, order_id), _) in contract.order_book.to_vec() {
+            let order = contract.orders.get(&order_id).unwrap();
+            if !order.withdraw_on_fill {
+                let mut storage = contract.storage_accounts.get(&order.account_id).unwrap();
+                storage.usage -= contract.bytes_for_token_record;
+                contract.storage_accounts.insert(&order.account_id, &storage);
+            }
+            let mut book: BTreeSet<upgrade::OrderKeyV5> = order_book.get(&pool_id).unwrap_or_default();
+            book.insert((token_in, min_price, order_id));
+            order_book.insert(&pool_id, &book);
+        }
+        contract.order_book.clear();
+        let mut flash_loans = LookupSet::new(StorageKey::FlashLoans.try_to_vec().unwrap());
+        if loan_in_flight {
+            flash_loans.insert(&MAIN_POOL_ID);
+        }
+
+        let old = upgrade::ContractV5 {
+            owner_id: contract.owner_id,
+            proposed_owner_id: contract.proposed_owner_id,
+            roles: contract.roles,
+            tokens: contract.tokens,
+            tokens_refreshed_at: contract.tokens_refreshed_at,
+            pools: contract.pools,
+            oracles: contract.oracles,
+            deposits: contract.deposits,
+            pending: contract.pending,
+            internal_totals: contract.internal_totals,
+            paused: contract.paused,
+            paused_pools: contract.paused_pools,
+            circuit_breakers: contract.circuit_breakers,
+            storage_accounts: contract.storage_accounts,
+            bytes_for_account_storage: contract.bytes_for_account_storage,
+            flash_loans,
+            orders: contract.orders,
+            order_book,
+            account_orders: contract.account_orders,
+            next_order_id: contract.next_order_id,
+        };
+        env::state_write(&old);
+        env::storage_write(upgrade::STATE_VERSION_KEY, &5u32.try_to_vec().unwrap());
+    }
+
+    // Writes the state in the layout whose flash loans had no collateral
+    fn write_state_v8(contract: Contract, loan_in_flight: bool) {
+        let mut flash_loans = LookupMap::new(StorageKey::FlashLoans.try_to_vec().unwrap());
+        if loan_in_flight {
+            let open = upgrade::OpenLoanV8 { loan_id: 0, token_id: token_a(), owed: ONE_TOKEN, repaid: 0 };
+            flash_loans.insert(&MAIN_POOL_ID, &open);
+        }
+        let old = upgrade::ContractV8 {
+            owner_id: contract.owner_id,
+            proposed_owner_id: contract.proposed_owner_id,
+            roles: contract.roles,
+            tokens: contract.tokens,
+            tokens_refreshed_at: contract.tokens_refreshed_at,
+            pools: contract.pools,
+            oracles: contract.oracles,
+            deposits: contract.deposits,
+            pending: contract.pending,
+            internal_totals: contract.internal_totals,
+            paused: contract.paused,
+            paused_pools: contract.paused_pools,
+            circuit_breakers: contract.circuit_breakers,
+            storage_accounts: contract.storage_accounts,
+            bytes_for_account_storage: contract.bytes_for_account_storage,
+            account_pools: contract.account_pools,
+            flash_loans,
+            next_loan_id: contract.next_loan_id,
+            orders: contract.orders,
+            order_book: contract.order_book,
+            account_orders: contract.account_orders,
+            next_order_id: contract.next_order_id,
+            bytes_for_token_record: contract.bytes_for_token_record,
+            transfers_in_flight: contract.transfers_in_flight,
+            unindexed_pools: contract.unindexed_pools,
+            bytes_for_pool_record: contract.bytes_for_pool_record,
+            bytes_for_position: contract.bytes_for_position,
+            bytes_for_order: contract.bytes_for_order,
+            pool_tokens: contract.pool_tokens,
+        };
+        env::state_write(&old);
+        env::storage_write(upgrade::STATE_VERSION_KEY, &8u32.try_to_vec().unwrap());
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        let (mut context, contract) = setup_contract();
+        write_state_v1(contract);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_state_version(), upgrade::CURRENT_STATE_VERSION);
+        assert_eq!(contract.get_shares(MAIN_POOL_ID, owner()).0, RESERVE - simple_pool::MINIMUM_LIQUIDITY);
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+        assert!(contract.storage_balance_bounds().min.0 > 0);
+        // The reserves are owed again and the holders of the main pool are read from it
+        assert_eq!(contract.internal_accounted_balance(&token_a()), RESERVE);
+        assert_eq!(contract.unindexed_pools, 1);
+        assert!(contract.pool_tokens.contains(&token_a()) && contract.pool_tokens.contains(&token_b()));
+    }
+
+    #[test]
+    #[should_panic(expected = "The account still has LP shares, positions, deposits, pending amounts or limit orders")]
+    fn test_migrate_from_v1_keeps_unindexed_holders() {
+        let (mut context, contract) = setup_contract();
+        write_state_v1(contract);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        let mut contract = Contract::migrate();
+        testing_env!(context.predecessor_account_id(owner()).attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_migrate_from_v5() {
+        let (mut context, mut contract) = setup_contract();
+        let order_id = place_order_a(&mut context, &mut contract, 2 * math::PRICE_PRECISION, false);
+        let usage = contract.storage_accounts.get(&user()).unwrap().usage;
+        write_state_v5(contract, false);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_state_version(), upgrade::CURRENT_STATE_VERSION);
+        // The book of the main pool is moved into the tree and the order reserves its output record again
+        assert_eq!(contract.get_orders(MAIN_POOL_ID)[0].order_id, order_id);
+        let old_key = [StorageKey::OrderBook.try_to_vec().unwrap(), MAIN_POOL_ID.try_to_vec().unwrap()].concat();
+        assert!(env::storage_read(&old_key).is_none());
+        assert_eq!(contract.storage_accounts.get(&user()).unwrap().usage, usage);
+        assert_eq!(contract.internal_accounted_balance(&token_a()), RESERVE + 10 * ONE_TOKEN);
+    }
+
+    #[test]
+    #[should_panic(expected = "The flash loans in flight should be resolved before the migration")]
+    fn test_migrate_from_v5_with_loan_in_flight() {
+        let (mut context, contract) = setup_contract();
+        write_state_v5(contract, true);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        Contract::migrate();
+    }
+
+    #[test]
+    fn test_migrate_from_v8() {
+        let (mut context, contract) = setup_contract();
+        write_state_v8(contract, false);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_state_version(), upgrade::CURRENT_STATE_VERSION);
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+        assert_eq!(contract.unindexed_pools, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The flash loans in flight should be resolved before the migration")]
+    fn test_migrate_from_v8_with_loan_in_flight() {
+        let (mut context, contract) = setup_contract();
+        write_state_v8(contract, true);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        Contract::migrate();
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_upgrade_not_owner() {
+        let (mut context, contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.upgrade(vec![0u8; 8].into());
+    }
+
+    #[test]
+    fn test_swap_event() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        let amount_out = contract.swap(MAIN_POOL_ID, token_a(), U128(10 * ONE_TOKEN), None, U128(1), None).0;
+        assert_eq!(
+            get_logs()[0],
+            format!(
+                r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"swap","data":[{{"pool_id":0,"account_id":"{}","token_in":"{}","amount_in":"{}","token_out":"{}","amount_out":"{}","reserves":["{}","{}"]}}]}}"#,
+                user(), token_a(), 10 * ONE_TOKEN, token_b(), amount_out,
+                reserve_of(&contract, &token_a()), reserve_of(&contract, &token_b())
+            )
+        );
+    }
+
+    #[test]
+    fn test_pool_created_and_fee_changed_events() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        let pool_id = contract.add_pool(token_b(), token_c(), fees::DEFAULT_FEE_BPS);
+        let created = format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"pool_created","data":[{{"pool_id":{},"account_id":"{}","pool_kind":"SIMPLE_POOL","tokens":["{}","{}"],"fee_bps":{}}}]}}"#,
+            pool_id, owner(), token_b(), token_c(), fees::DEFAULT_FEE_BPS
+        );
+        assert!(get_logs().contains(&created));
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.set_fee(pool_id, 100, 2_000);
+        assert!(get_logs().contains(&format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"fee_changed","data":[{{"pool_id":{},"account_id":"{}","fee_bps":100,"protocol_share_bps":2000}}]}}"#,
+            pool_id, owner()
+        )));
+    }
+
+    #[test]
+    fn test_add_liquidity_and_sync_events() {
+        let (mut context, mut contract) = setup_contract();
+
+        let shares = add_liquidity_as(&mut context, &mut contract, user(), MAIN_POOL_ID, &[ONE_TOKEN, ONE_TOKEN]);
+        assert!(get_logs().contains(&format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"add_liquidity","data":[{{"pool_id":0,"account_id":"{}","amounts":["{}","{}"],"shares":"{}","reserves":["{}","{}"]}}]}}"#,
+            user(), ONE_TOKEN, ONE_TOKEN, shares.0, RESERVE + ONE_TOKEN, RESERVE + ONE_TOKEN
+        )));
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![balance_result(RESERVE), balance_result(RESERVE)],
+        );
+        contract.on_sync(MAIN_POOL_ID, owner(), totals_of(&contract, &[token_a(), token_b()]));
+        assert!(get_logs().contains(&format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"sync","data":[{{"pool_id":0,"account_id":"{}","reserves":["{}","{}"]}}]}}"#,
+            owner(), RESERVE, RESERVE
+        )));
+    }
+
+    fn carol() -> AccountId { "carol.near".parse().unwrap() }
+
+    #[test]
+    fn test_storage_deposit_registration_only() {
+        let (mut context, mut contract) = setup_contract();
+        assert!(contract.storage_balance_of(carol()).is_none());
+
+        let min = contract.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(carol()).attached_deposit(2 * min).build());
+        let balance = contract.storage_deposit(None, Some(true));
+        assert_eq!(balance.total.0, min);
+        assert_eq!(balance.available.0, 0);
+        assert_eq!(contract.storage_balance_of(carol()).unwrap().total.0, min);
+    }
+
+    #[test]
+    fn test_storage_charged_by_usage() {
+        let (mut context, mut contract) = setup_contract();
+        let available = contract.storage_balance_of(user()).unwrap().available.0;
+
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+        let charged = available - contract.storage_balance_of(user()).unwrap().available.0;
+        assert_eq!(charged, Balance::from(contract.bytes_for_token_record) * env::storage_byte_cost());
+
+        // Withdrawing the whole deposit releases its bytes
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        contract.withdraw(token_a(), U128(10 * ONE_TOKEN));
+        assert_eq!(contract.storage_balance_of(user()).unwrap().available.0, available);
+    }
+
+    #[test]
+    fn test_storage_not_charged_for_shared_writes() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+        deposit(&mut context, &mut contract, token_b(), 10 * ONE_TOKEN);
+        let usage = contract.storage_accounts.get(&user()).unwrap().usage;
+
+        // The swap writes the pool and its oracle, the records of the user keep their tokens
+        testing_env!(context.predecessor_account_id(user()).block_timestamp(1_000_000_000).build());
+        contract.swap(MAIN_POOL_ID, token_a(), U128(ONE_TOKEN), None, U128(1), None);
+        assert_eq!(contract.storage_accounts.get(&user()).unwrap().usage, usage);
+    }
+
+    #[test]
+    fn test_storage_charged_to_receiver_of_shares() {
+        let (mut context, mut contract) = setup_contract();
+        let owner_usage = contract.storage_accounts.get(&owner()).unwrap().usage;
+        let user_usage = contract.storage_accounts.get(&user()).unwrap().usage;
+
+        testing_env!(context.predecessor_account_id(owner()).attached_deposit(1).build());
+        contract.ft_transfer(user(), U128(ONE_TOKEN), None);
+        assert_eq!(contract.storage_accounts.get(&owner()).unwrap().usage, owner_usage);
+        assert_eq!(contract.storage_accounts.get(&user()).unwrap().usage, user_usage + contract.bytes_for_pool_record);
+    }
+
+    #[test]
+    fn test_storage_reserved_for_position() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+        let usage = contract.storage_accounts.get(&user()).unwrap().usage;
+
+        let position_id = add_position_as(&mut context, &mut contract, user(), pool_id, (-100, 100), &[ONE_TOKEN, ONE_TOKEN]);
+        assert!(contract.storage_accounts.get(&user()).unwrap().usage >= usage + contract.bytes_for_position);
+
+        // Closing the position releases it with the pool record, the rest of the deposit keeps its records
+        let position = contract.get_position(pool_id, position_id);
+        let records_bytes = contract.internal_records_bytes(&user());
+        contract.remove_position(pool_id, position_id, position.liquidity, None);
+        assert_eq!(
+            contract.storage_accounts.get(&user()).unwrap().usage,
+            usage + records_bytes - contract.bytes_for_pool_record
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough storage deposit of carol.near")]
+    fn test_storage_not_enough_deposit() {
+        let (mut context, mut contract) = setup_contract();
+        let min = contract.storage_balance_bounds().min.0;
+        testing_env!(context.predecessor_account_id(carol()).attached_deposit(min).build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context.predecessor_account_id(token_a()).attached_deposit(0).build());
+        contract.ft_on_transfer(carol(), U128(ONE_TOKEN), DEPOSIT_MSG.to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "The account carol.near is not registered")]
+    fn test_storage_not_registered() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(carol(), U128(ONE_TOKEN), DEPOSIT_MSG.to_string());
+    }
+
+    #[test]
+    fn test_storage_withdraw_and_unregister() {
+        let (mut context, mut contract) = setup_contract();
+        let balance = contract.storage_balance_of(user()).unwrap();
+
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        let withdrawn = contract.storage_withdraw(Some(U128(ONE_TOKEN)));
+        assert_eq!(withdrawn.total.0, balance.total.0 - ONE_TOKEN);
+        assert_eq!(withdrawn.available.0, balance.available.0 - ONE_TOKEN);
+
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(user()).is_none());
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    fn test_storage_unregister_with_concentrated_pool() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+        assert_eq!(contract.account_pools.get(&owner()), Some(BTreeSet::from([MAIN_POOL_ID, pool_id])));
+
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        assert!(contract.storage_unregister(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "The account still has LP shares, positions, deposits, pending amounts or limit orders")]
+    fn test_storage_unregister_with_position() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+
+        add_position_as(&mut context, &mut contract, user(), pool_id, (-100, 100), &[ONE_TOKEN, ONE_TOKEN]);
+        testing_env!(context.attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The account still has LP shares, positions, deposits, pending amounts or limit orders")]
+    fn test_storage_unregister_with_records() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), ONE_TOKEN);
+
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The metadata of fargo is not known yet")]
+    fn test_swap_blocked_until_metadata_known() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = contract.add_pool(token_a(), token_c(), fees::DEFAULT_FEE_BPS);
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        contract.ft_on_transfer(user(), U128(ONE_TOKEN), swap_msg(pool_id, 1));
+    }
+
+    #[test]
+    fn test_refresh_token_metadata() {
+        let (mut context, mut contract) = setup_contract();
+        assert_eq!(contract.get_token_last_refreshed(token_a()), Some(U64(0)));
+        assert_eq!(contract.get_token_last_refreshed(token_c()), None);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.refresh_token_metadata(token_a());
+
+        // A failed query keeps the metadata and its timestamp
+        testing_env!(context.predecessor_account_id(amm()).block_timestamp(1_000).build());
+        contract.on_get_metadata(token_a(), Err(PromiseError::Failed));
+        assert_eq!(contract.token_info_by_id(MAIN_POOL_ID, token_a()).ticker, "tkn_A");
+        assert_eq!(contract.get_token_last_refreshed(token_a()), Some(U64(0)));
+
+        contract.on_get_metadata(token_a(), Ok(token_metadata("tkn_A2", TOTAL_SUPPLY)));
+        assert_eq!(contract.token_info_by_id(MAIN_POOL_ID, token_a()).ticker, "tkn_A2");
+        assert_eq!(contract.get_token_last_refreshed(token_a()), Some(U64(1_000)));
+    }
+
+    #[test]
+    #[should_panic(expected = "The method requires the role Keeper")]
+    fn test_refresh_token_metadata_without_keeper_role() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.refresh_token_metadata(token_a());
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported token contract id: fargo")]
+    fn test_refresh_unknown_token_metadata() {
+        let (_, mut contract) = setup_contract();
+        contract.refresh_token_metadata(token_c());
+    }
+
+    #[test]
+    fn test_refresh_token_metadata_of_pool_token_not_resolved() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.add_pool(token_a(), token_c(), fees::DEFAULT_FEE_BPS);
+        assert!(!contract.tokens.contains_key(&token_c()));
+
+        // The first query failed, the token of the pool is still indexed for the refresh
+        contract.refresh_token_metadata(token_c());
+        testing_env!(context.predecessor_account_id(amm()).build());
+        contract.on_get_metadata(token_c(), Ok(token_metadata("tkn_C", TOTAL_SUPPLY)));
+        assert!(contract.tokens.contains_key(&token_c()));
+    }
+
+    const LOAN: Balance = 100 * ONE_TOKEN;
+
+    // Lends LOAN of token A of the main pool to the user against the collateral of its deposit and resolves
+    // the transfer to the user
+    fn flash_loan_sent(context: &mut VMContextBuilder, contract: &mut Contract) -> Balance {
+        deposit(context, contract, token_a(), LOAN + flash_loan::flash_loan_fee(LOAN));
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.flash_loan(MAIN_POOL_ID, token_a(), U128(LOAN), user(), "arbitrage".to_string());
+        assert_eq!(reserve_of(contract, &token_a()), RESERVE - LOAN);
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let result = contract.on_flash_loan_sent(loan(flash_loan::flash_loan_fee(LOAN)), "arbitrage".to_string());
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        flash_loan::flash_loan_fee(LOAN)
+    }
+
+    fn loan(fee: Balance) -> flash_loan::Loan {
+        flash_loan::Loan {
+            loan_id: 0,
+            pool_id: MAIN_POOL_ID,
+            account_id: user(),
+            token_id: token_a(),
+            amount: U128(LOAN),
+            fee: U128(fee),
+            receiver_id: user(),
+        }
+    }
+
+    // Repays `amount` of the flash loan 0 of the main pool with `ft_transfer_call`, returns the unused amount
+    fn repay_flash_loan(context: &mut VMContextBuilder, contract: &mut Contract, amount: Balance) -> Balance {
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        let msg = format!("{{\"action\":\"repay_flash_loan\",\"pool_id\":{},\"loan_id\":0}}", MAIN_POOL_ID);
+        match contract.ft_on_transfer(user(), U128(amount), msg) {
+            PromiseOrValue::Value(unused) => unused.0,
+            PromiseOrValue::Promise(_) => panic!("Expected the repayment to be resolved"),
+        }
+    }
+
+    #[test]
+    fn test_flash_loan_repaid() {
+        let (mut context, mut contract) = setup_contract();
+        let fee = flash_loan_sent(&mut context, &mut contract);
+        assert_eq!(fee, LOAN * 9 / 10_000);
+        // The part above the amount and the fee is refunded
+        assert_eq!(repay_flash_loan(&mut context, &mut contract, LOAN), 0);
+        assert_eq!(repay_flash_loan(&mut context, &mut contract, fee + 5), 5);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        assert!(contract.on_flash_loan_resolved(loan(fee)));
+        // The fee goes to the reserve, the collateral returns to the deposit and the pool is unlocked
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + fee);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, LOAN + fee);
+        assert_eq!(contract.internal_accounted_balance(&token_a()), RESERVE + fee + LOAN + fee);
+        assert!(!contract.is_pool_paused(MAIN_POOL_ID));
+        assert!(get_logs().contains(&format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"flash_loan","data":[{{"loan_id":0,"pool_id":0,"account_id":"{}","receiver_id":"{}","token_id":"{}","amount":"{}","fee":"{}","repaid":true}}]}}"#,
+            user(), user(), token_a(), LOAN, fee
+        )));
+
+        add_liquidity_as(&mut context, &mut contract, owner(), MAIN_POOL_ID, &[ONE_TOKEN, ONE_TOKEN]);
+    }
+
+    #[test]
+    fn test_flash_loan_not_repaid_takes_collateral() {
+        let (mut context, mut contract) = setup_contract();
+        let fee = flash_loan_sent(&mut context, &mut contract);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 0);
+        assert_eq!(repay_flash_loan(&mut context, &mut contract, LOAN / 2), 0);
+
+        testing_env!(context.predecessor_account_id(amm()).build());
+        assert!(!contract.on_flash_loan_resolved(loan(fee)));
+        // The reserve is restored with the amount and the fee, the collateral covers the unpaid part
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE + fee);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, LOAN / 2);
+        assert_eq!(contract.internal_accounted_balance(&token_a()), RESERVE + fee + LOAN / 2);
+        assert!(!contract.is_pool_paused(MAIN_POOL_ID));
+        assert!(get_logs().contains(&format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"reserves_restored","data":[{{"pool_id":0,"action":"flash_loan","reserves":["{}","{}"]}}]}}"#,
+            RESERVE + fee, RESERVE
+        )));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough deposit of charlie")]
+    fn test_flash_loan_requires_collateral() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), LOAN);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.flash_loan(MAIN_POOL_ID, token_a(), U128(LOAN), user(), String::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Flash loan 1 of pool 0 is not in flight")]
+    fn test_flash_loan_repay_unknown_loan() {
+        let (mut context, mut contract) = setup_contract();
+        flash_loan_sent(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(token_a()).build());
+        let msg = "{\"action\":\"repay_flash_loan\",\"pool_id\":0,\"loan_id\":1}";
+        contract.ft_on_transfer(user(), U128(LOAN), msg.to_string());
+    }
+
+    #[test]
+    fn test_flash_loan_transfer_failed() {
+        let (mut context, mut contract) = setup_contract();
+        let fee = flash_loan::flash_loan_fee(LOAN);
+        deposit(&mut context, &mut contract, token_a(), LOAN + fee);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.flash_loan(MAIN_POOL_ID, token_a(), U128(LOAN), user(), String::new());
+
+        testing_env!(
+            context.predecessor_account_id(amm()).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let result = contract.on_flash_loan_sent(loan(fee), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(false)));
+        assert_eq!(reserve_of(&contract, &token_a()), RESERVE);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, LOAN + fee);
+
+        // The pool is unlocked
+        add_liquidity_as(&mut context, &mut contract, owner(), MAIN_POOL_ID, &[ONE_TOKEN, ONE_TOKEN]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool 0 has a flash loan in flight")]
+    fn test_flash_loan_locks_pool() {
+        let (mut context, mut contract) = setup_contract();
+        flash_loan_sent(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(token_b()).build());
+        contract.ft_on_transfer(user(), U128(ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "The AMM is paused")]
+    fn test_flash_loan_paused() {
+        let (mut context, mut contract) = setup_contract();
+        contract.pause();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.flash_loan(MAIN_POOL_ID, token_a(), U128(LOAN), user(), String::new());
+    }
+
+    // Places an order of the user to sell 10 of token A of the main pool at `min_price` of token B
+    fn place_order_a(context: &mut VMContextBuilder, contract: &mut Contract, min_price: Balance, withdraw_on_fill: bool) -> u64 {
+        deposit(context, contract, token_a(), 10 * ONE_TOKEN);
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.place_limit_order(MAIN_POOL_ID, token_a(), U128(10 * ONE_TOKEN), U128(min_price), Some(withdraw_on_fill))
+    }
+
+    // Raises the price of token A by buying it with 100 of token B
+    fn buy_a(context: &mut VMContextBuilder, contract: &mut Contract) {
+        testing_env!(context.predecessor_account_id(token_b()).build());
+        contract.ft_on_transfer(owner(), U128(100 * ONE_TOKEN), swap_msg(MAIN_POOL_ID, 1));
+    }
+
+    #[test]
+    fn test_limit_order_place_and_cancel() {
+        let (mut context, mut contract) = setup_contract();
+        let order_id = place_order_a(&mut context, &mut contract, 2 * math::PRICE_PRECISION, false);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 0);
+        assert_eq!(contract.get_account_orders(user()).into_iter().collect::<Vec<_>>(), vec![order_id]);
+        assert_eq!(contract.get_orders(MAIN_POOL_ID)[0].token_out, token_b());
+
+        // The price of A is 1 B, the order stays in the book
+        testing_env!(context.predecessor_account_id(owner()).build());
+        assert_eq!(contract.execute_orders(MAIN_POOL_ID, 10), 0);
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.cancel_order(order_id);
+        assert_eq!(contract.get_deposit(user(), token_a()).0, 10 * ONE_TOKEN);
+        assert!(contract.get_order(order_id).is_none());
+        assert!(contract.get_orders(MAIN_POOL_ID).is_empty());
+        assert!(contract.get_account_orders(user()).is_empty());
+    }
+
+    #[test]
+    fn test_limit_order_filled_to_deposit() {
+        let (mut context, mut contract) = setup_contract();
+        let min_price = math::PRICE_PRECISION * 105 / 100;
+        let order_id = place_order_a(&mut context, &mut contract, min_price, false);
+        buy_a(&mut context, &mut contract);
+
+        let expected = contract.get_return(MAIN_POOL_ID, token_a(), U128(10 * ONE_TOKEN), Some(token_b())).0;
+        // 10 of token A with 18 decimals are worth at least 10.5 of token B
+        assert!(expected >= 10 * min_price);
+        testing_env!(context.predecessor_account_id(owner()).build());
+        assert_eq!(contract.execute_orders(MAIN_POOL_ID, 10), 1);
+        assert_eq!(contract.get_deposit(user(), token_b()).0, expected);
+        assert!(contract.get_order(order_id).is_none());
+        assert!(contract.get_account_orders(user()).is_empty());
+    }
+
+    #[test]
+    fn test_limit_order_filled_with_transfer() {
+        let (mut context, mut contract) = setup_contract();
+        place_order_a(&mut context, &mut contract, math::PRICE_PRECISION * 105 / 100, true);
+        buy_a(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        assert_eq!(contract.execute_orders(MAIN_POOL_ID, 1), 1);
+        // The output is sent, only the accounting of the open orders is released
+        assert_eq!(contract.get_deposit(user(), token_b()).0, 0);
+        let protocol_fee = contract.get_fee_info(MAIN_POOL_ID).protocol_fees[0].0;
+        assert_eq!(contract.internal_accounted_balance(&token_a()), reserve_of(&contract, &token_a()) + protocol_fee);
+    }
+
+    #[test]
+    fn test_limit_order_reserves_output_storage() {
+        let (mut context, mut contract) = setup_contract();
+        deposit(&mut context, &mut contract, token_a(), 10 * ONE_TOKEN);
+        let usage = contract.storage_accounts.get(&user()).unwrap().usage;
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        let order_id =
+            contract.place_limit_order(MAIN_POOL_ID, token_a(), U128(10 * ONE_TOKEN), U128(math::PRICE_PRECISION), None);
+        assert!(contract.storage_accounts.get(&user()).unwrap().usage >= usage + contract.bytes_for_token_record);
+
+        // The reservation is released with the order
+        contract.cancel_order(order_id);
+        assert_eq!(contract.storage_accounts.get(&user()).unwrap().usage, usage);
+    }
+
+    #[test]
+    fn test_limit_order_fill_uses_reserved_storage() {
+        let (mut context, mut contract) = setup_contract();
+        let min_price = math::PRICE_PRECISION * 105 / 100;
+        place_order_a(&mut context, &mut contract, min_price, false);
+        buy_a(&mut context, &mut contract);
+
+        // The user withdraws everything the storage deposit doesn't cover, the output record is reserved
+        testing_env!(context.predecessor_account_id(user()).attached_deposit(1).build());
+        contract.storage_withdraw(None);
+        testing_env!(context.predecessor_account_id(owner()).attached_deposit(0).build());
+        let expected = contract.get_return(MAIN_POOL_ID, token_a(), U128(10 * ONE_TOKEN), Some(token_b())).0;
+        assert_eq!(contract.execute_orders(MAIN_POOL_ID, 1), 1);
+        assert_eq!(contract.get_deposit(user(), token_b()).0, expected);
+    }
+
+    #[test]
+    fn test_execute_orders_counts_examined_orders() {
+        let (mut context, mut contract) = setup_contract();
+        // The spot price crosses the limit but the order is too large to be filled at it
+        deposit(&mut context, &mut contract, token_a(), RESERVE);
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.place_limit_order(MAIN_POOL_ID, token_a(), U128(RESERVE), U128(math::PRICE_PRECISION), None);
+        place_order_a(&mut context, &mut contract, math::PRICE_PRECISION * 105 / 100, false);
+        buy_a(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        assert_eq!(contract.execute_orders(MAIN_POOL_ID, 1), 0);
+        assert_eq!(contract.execute_orders(MAIN_POOL_ID, 2), 1);
+        assert_eq!(contract.get_orders(MAIN_POOL_ID).len(), 1);
+    }
+
+    #[test]
+    fn test_execute_orders_stops_at_limit_above_spot() {
+        let (mut context, mut contract) = setup_contract();
+        place_order_a(&mut context, &mut contract, 2 * math::PRICE_PRECISION, false);
+        place_order_a(&mut context, &mut contract, 3 * math::PRICE_PRECISION, false);
+        deposit(&mut context, &mut contract, token_b(), 10 * ONE_TOKEN);
+        testing_env!(context.predecessor_account_id(user()).build());
+        let order_id =
+            contract.place_limit_order(MAIN_POOL_ID, token_b(), U128(10 * ONE_TOKEN), U128(math::PRICE_PRECISION / 2), None);
+
+        // The orders of token A above the spot price after the first are not examined
+        testing_env!(context.predecessor_account_id(owner()).build());
+        assert_eq!(contract.execute_orders(MAIN_POOL_ID, 2), 1);
+        assert!(contract.get_order(order_id).is_none());
+        assert_eq!(contract.get_orders(MAIN_POOL_ID).len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner of the order can cancel it")]
+    fn test_cancel_order_not_owner() {
+        let (mut context, mut contract) = setup_contract();
+        let order_id = place_order_a(&mut context, &mut contract, math::PRICE_PRECISION, false);
+
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.cancel_order(order_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "The method requires the role Keeper")]
+    fn test_execute_orders_without_keeper_role() {
+        let (mut context, mut contract) = setup_contract();
+
+        testing_env!(context.predecessor_account_id(user()).build());
+        contract.execute_orders(MAIN_POOL_ID, 1);
+    }
+
+    #[test]
+    fn test_get_amount_in_matches_swap() {
+        let (mut context, mut contract) = setup_contract();
+        let amount_out = 5 * ONE_TOKEN;
+        let amount_in = contract.get_amount_in(MAIN_POOL_ID, token_b(), U128(amount_out), None).0;
+        assert!(contract.get_return(MAIN_POOL_ID, token_a(), U128(amount_in), None).0 >= amount_out);
+        assert!(contract.get_return(MAIN_POOL_ID, token_a(), U128(amount_in - 1), None).0 < amount_out);
+
+        deposit(&mut context, &mut contract, token_a(), amount_in);
+        testing_env!(context.predecessor_account_id(user()).build());
+        let swapped = contract.swap(MAIN_POOL_ID, token_a(), U128(amount_in), None, U128(amount_out), None);
+        assert!(swapped.0 >= amount_out);
+    }
+
+    #[test]
+    fn test_get_amount_in_stable_pool() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_stable_pool_a_c(&mut context, &mut contract, 100);
+
+        let amount_out = 10 * ONE_STABLE;
+        let amount_in = contract.get_amount_in(pool_id, token_c(), U128(amount_out), Some(token_a())).0;
+        assert!(contract.get_return(pool_id, token_a(), U128(amount_in), Some(token_c())).0 >= amount_out);
+        assert!(contract.get_return(pool_id, token_a(), U128(amount_in - 1), Some(token_c())).0 < amount_out);
+    }
+
+    #[test]
+    #[should_panic(expected = "The pool can't pay out")]
+    fn test_get_amount_in_more_than_reserve() {
+        let (_, contract) = setup_contract();
+        contract.get_amount_in(MAIN_POOL_ID, token_b(), U128(RESERVE), None);
+    }
+
+    #[test]
+    fn test_get_amount_in_weighted_pool() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_weighted_pool_a_b_c(&mut context, &mut contract);
+
+        let amount_out = 10 * ONE_TOKEN;
+        let amount_in = contract.get_amount_in(pool_id, token_b(), U128(amount_out), Some(token_a())).0;
+        assert!(contract.get_return(pool_id, token_a(), U128(amount_in), Some(token_b())).0 >= amount_out);
+        assert!(contract.get_return(pool_id, token_a(), U128(amount_in - 1), Some(token_b())).0 < amount_out);
+    }
+
+    #[test]
+    fn test_get_amount_in_concentrated_pool() {
+        let (mut context, mut contract) = setup_contract();
+        let pool_id = add_concentrated_pool_a_b(&mut context, &mut contract);
+
+        let amount_out = 10 * ONE_TOKEN;
+        let amount_in = contract.get_amount_in(pool_id, token_b(), U128(amount_out), None).0;
+        assert!(contract.get_return(pool_id, token_a(), U128(amount_in), None).0 >= amount_out);
+        assert!(contract.get_return(pool_id, token_a(), U128(amount_in - 1), None).0 < amount_out);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool 0 is paused")]
+    fn test_get_return_of_paused_pool() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(owner()).build());
+        contract.pause_pool(MAIN_POOL_ID);
+
+        contract.get_return(MAIN_POOL_ID, token_a(), U128(ONE_TOKEN), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Pool 0 has a flash loan in flight")]
+    fn test_get_amount_in_with_flash_loan_in_flight() {
+        let (mut context, mut contract) = setup_contract();
+        flash_loan_sent(&mut context, &mut contract);
+
+        contract.get_amount_in(MAIN_POOL_ID, token_b(), U128(ONE_TOKEN), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The metadata of fargo is not known yet")]
+    fn test_price_impact_until_metadata_known() {
+        let (_, mut contract) = setup_contract();
+        let pool_id = contract.add_pool(token_a(), token_c(), fees::DEFAULT_FEE_BPS);
+
+        contract.price_impact(pool_id, token_a(), U128(ONE_TOKEN), None);
+    }
+
+    #[test]
+    fn test_spot_price_and_price_impact() {
+        let (_, contract) = setup_contract();
+        assert_eq!(contract.spot_price(MAIN_POOL_ID, token_a(), None).0, math::PRICE_PRECISION);
+        assert_eq!(contract.spot_price(MAIN_POOL_ID, token_b(), Some(token_a())).0, math::PRICE_PRECISION);
+
+        // At the equal reserves the swap pays out less than 1:1 by the fee and the move of the price
+        let amount_out = math::get_amount_out(ONE_TOKEN, RESERVE, RESERVE, fees::DEFAULT_FEE_BPS);
+        let impact = contract.price_impact(MAIN_POOL_ID, token_a(), U128(ONE_TOKEN), None).0;
+        assert_eq!(impact, (ONE_TOKEN - amount_out) * 10_000 / ONE_TOKEN);
+        assert!(impact > u128::from(fees::DEFAULT_FEE_BPS));
+        assert!(contract.price_impact(MAIN_POOL_ID, token_a(), U128(100 * ONE_TOKEN), None).0 > impact);
     }
 }
@@ -0,0 +1,237 @@
+//! Limit orders that rest in the AMM and fill against the constant product pools.
+//!
+//! An order sells `amount_in` of a token of the pool from the deposit of its owner once the spot price of the token
+//! reaches `min_price`, the amount of the other token per one unit with `PRICE_PRECISION`. The book keeps the orders
+//! sorted by the pool, the token and the price, a keeper fills the orders whose limit is crossed with
+//! `execute_orders`. An order is filled as a whole and only if the swap pays out at least its limit price.
+//! The storage of the output in the deposit is reserved at the placement, so a fill never charges the owner more.
+
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance};
+
+use crate::math::U256;
+use crate::pool::Pool;
+use crate::*;
+
+/// Key of an order in the book: the pool, the token to sell, the limit price and the id of the order
+pub type OrderKey = (u64, AccountId, Balance, u64);
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LimitOrder {
+    pub order_id: u64,
+    pub account_id: AccountId,
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: U128,
+    pub token_out: AccountId,
+    /// The lowest price of `token_in` in `token_out` with `PRICE_PRECISION`
+    pub min_price: U128,
+    /// Sends the output to the owner with `ft_transfer` instead of adding it to the deposit
+    pub withdraw_on_fill: bool,
+}
+
+impl LimitOrder {
+    /// Returns the least amount of `token_out` the fill has to pay out.
+    fn min_amount_out(&self) -> Balance {
+        (U256::from(self.amount_in.0) * U256::from(self.min_price.0) / U256::from(math::PRICE_PRECISION)).as_u128()
+    }
+
+    fn key(&self) -> OrderKey {
+        (self.pool_id, self.token_in.clone(), self.min_price.0, self.order_id)
+    }
+}
+
+impl Contract {
+    /// Removes the order from the registry, the book of its pool and the orders of its owner.
+    fn internal_remove_order(&mut self, order: &LimitOrder) {
+        self.orders.remove(&order.order_id);
+
+        self.order_book.remove(&order.key());
+
+        let mut account_orders = self.account_orders.get(&order.account_id).unwrap_or_default();
+        account_orders.remove(&order.order_id);
+        if account_orders.is_empty() {
+            self.account_orders.remove(&order.account_id);
+        } else {
+            self.account_orders.insert(&order.account_id, &account_orders);
+        }
+    }
+
+    /// Fills the order against the pool and pays out the output. The input is owed by the AMM to the order
+    /// until the fill, so it is moved from the totals to the reserve.
+    fn internal_fill_order(&mut self, order: &LimitOrder) -> Balance {
+        self.internal_sub_total(&order.token_in, order.amount_in.0);
+        let (amount_out, _) =
+            self.internal_swap(order.pool_id, &order.account_id, &order.token_in, order.amount_in.0, &order.token_out);
+
+        // The released reservation covers the output in the deposit
+        let initial_bytes = self.internal_records_bytes(&order.account_id);
+        self.internal_remove_order(order);
+        if !order.withdraw_on_fill {
+            self.internal_deposit(&order.account_id, &order.token_out, amount_out);
+            self.internal_release_storage(&order.account_id, self.bytes_for_token_record);
+        }
+        self.internal_charge_storage(&order.account_id, initial_bytes);
+        if order.withdraw_on_fill {
+            // A failed transfer is added to the deposit
+            self.internal_send(&order.account_id, &order.token_out, amount_out, "AMM limit order");
+        }
+
+        log!(
+            "execute_orders: order {} of {} sold {} {} for {} {}",
+            order.order_id, order.account_id, order.amount_in.0, order.token_in, amount_out, order.token_out
+        );
+        amount_out
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Places an order to sell `amount_in` of the deposit of `token_in` for the other token of the pool once
+    /// the spot price of `token_in` is at least `min_price` (with `PRICE_PRECISION`). The output is added to
+    /// the deposit, or sent to the caller if `withdraw_on_fill` is set. The storage deposit of the caller has to
+    /// cover the order and the output in the deposit. Returns the id of the order.
+    pub fn place_limit_order(
+        &mut self,
+        pool_id: u64,
+        token_in: AccountId,
+        amount_in: U128,
+        min_price: U128,
+        withdraw_on_fill: Option<bool>,
+    ) -> u64 {
+        require!(amount_in.0 > 0, "The amount should be a positive number");
+        require!(min_price.0 > 0, "The limit price should be a positive number");
+        self.internal_assert_pool_not_paused(pool_id);
+        let pool = self.internal_unwrap_pool(pool_id);
+        require!(
+            matches!(pool, Pool::SimplePool(_)),
+            "Limit orders are only supported by the constant product pools"
+        );
+        require!(pool.contains(&token_in), format!("Unsupported token contract id: {}", token_in));
+
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+        // The input is taken from the deposit, the AMM still owes it to the order
+        self.internal_withdraw(&account_id, &token_in, amount_in.0);
+        self.internal_add_total(&token_in, amount_in.0);
+
+        let order_id = self.next_order_id;
+        self.next_order_id += 1;
+        let order = LimitOrder {
+            order_id,
+            account_id: account_id.clone(),
+            pool_id,
+            token_out: pool.opposite_token(&token_in),
+            token_in,
+            amount_in,
+            min_price,
+            withdraw_on_fill: withdraw_on_fill.unwrap_or(false),
+        };
+        self.orders.insert(&order_id, &order);
+
+        self.order_book.insert(&order.key(), &());
+
+        let mut account_orders = self.account_orders.get(&account_id).unwrap_or_default();
+        account_orders.insert(order_id);
+        self.account_orders.insert(&account_id, &account_orders);
+        self.internal_charge_storage(&account_id, initial_bytes);
+        if !order.withdraw_on_fill {
+            self.internal_reserve_storage(&account_id, self.bytes_for_token_record);
+        }
+
+        log!(
+            "place_limit_order: order {} of {} sells {} {} at {}",
+            order_id, account_id, amount_in.0, order.token_in, min_price.0
+        );
+        order_id
+    }
+
+    /// Cancels the order of the caller and returns its input to the deposit.
+    pub fn cancel_order(&mut self, order_id: u64) {
+        let account_id = env::predecessor_account_id();
+        let order = self
+            .orders
+            .get(&order_id)
+            .unwrap_or_else(|| env::panic_str(format!("Order {} doesn't exist", order_id).as_str()));
+        require!(order.account_id == account_id, "Only the owner of the order can cancel it");
+
+        let initial_bytes = self.internal_records_bytes(&account_id);
+        self.internal_remove_order(&order);
+        self.internal_sub_total(&order.token_in, order.amount_in.0);
+        self.internal_deposit(&account_id, &order.token_in, order.amount_in.0);
+        if !order.withdraw_on_fill {
+            self.internal_release_storage(&account_id, self.bytes_for_token_record);
+        }
+        self.internal_charge_storage(&account_id, initial_bytes);
+        log!("cancel_order: order {} of {}", order_id, account_id);
+    }
+
+    /// Examines up to `max_orders` orders of the pool and fills those whose limit price is crossed by the spot
+    /// price, requires the role `Keeper`. The orders of each token are examined from the lowest limit until the
+    /// first limit above the spot price, an order whose fill would pay out less than its limit stays in the book.
+    /// Stops if the circuit breaker pauses the pool. Returns the number of the filled orders.
+    pub fn execute_orders(&mut self, pool_id: u64, max_orders: u32) -> u32 {
+        self.internal_assert_role(Role::Keeper);
+        self.internal_assert_pool_not_paused(pool_id);
+        self.internal_assert_no_flash_loan(pool_id);
+
+        let mut examined = 0;
+        let mut filled = 0;
+        for token_in in self.internal_unwrap_pool(pool_id).tokens() {
+            let mut next = self.order_book.ceil_key(&(pool_id, token_in.clone(), 0, 0));
+            while let Some(key) = next.filter(|key| key.0 == pool_id && key.1 == token_in) {
+                if examined >= max_orders || self.internal_is_pool_paused(pool_id) {
+                    return filled;
+                }
+                examined += 1;
+                next = self.order_book.higher(&key);
+
+                let (.., min_price, order_id) = key;
+                let order = self.orders.get(&order_id).unwrap();
+                let pool = self.internal_unwrap_pool(pool_id);
+                // The limits of the following orders of the token are not crossed either
+                if pool.spot_price(&token_in, &order.token_out) < U256::from(min_price) {
+                    break;
+                }
+                if pool.get_return(&token_in, order.amount_in.0, &order.token_out) < order.min_amount_out() {
+                    continue;
+                }
+
+                self.internal_fill_order(&order);
+                filled += 1;
+            }
+        }
+        filled
+    }
+
+    // Returns the order by id
+    pub fn get_order(&self, order_id: u64) -> Option<LimitOrder> {
+        self.orders.get(&order_id)
+    }
+
+    // Returns the orders of the pool sorted by the token to sell and the limit price
+    pub fn get_orders(&self, pool_id: u64) -> Vec<LimitOrder> {
+        let tokens = self.pools.get(pool_id).map(|pool| pool.tokens()).unwrap_or_default();
+        tokens
+            .into_iter()
+            .flat_map(|token_id| {
+                self.order_book.range((
+                    Bound::Included((pool_id, token_id.clone(), 0, 0)),
+                    Bound::Included((pool_id, token_id, Balance::MAX, u64::MAX)),
+                ))
+            })
+            .map(|((.., order_id), _)| self.orders.get(&order_id).unwrap())
+            .collect()
+    }
+
+    // Returns the ids of the open orders of the account
+    pub fn get_account_orders(&self, account_id: AccountId) -> BTreeSet<u64> {
+        self.account_orders.get(&account_id).unwrap_or_default()
+    }
+}
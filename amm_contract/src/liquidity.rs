@@ -0,0 +1,91 @@
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance, PromiseResult};
+
+use crate::external::ext_self;
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// Adds liquidity to all the tokens of the pool `pool_id` and mints LP shares to the caller,
+    /// the amounts are given in the order of the tokens of the pool.
+    /// The first deposit defines the prices of the pool, `MINIMUM_LIQUIDITY` of its shares are locked.
+    /// The following deposits are taken in proportion to the reserves, the part of the amounts above
+    /// the proportion stays in the deposit. A StableSwap pool takes the amounts in any proportion,
+    /// the imbalanced part pays the swap fee.
+    /// The amounts are taken from the internal deposit of the caller, so the shares are only minted
+    /// for tokens the AMM already owns.
+    /// Returns the amount of minted shares.
+    pub fn add_liquidity(&mut self, pool_id: u64, amounts: Vec<U128>, min_shares: Option<U128>) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+
+        let amounts: Vec<Balance> = amounts.iter().map(|a| a.0).collect();
+        let (amounts, shares) = self.internal_add_liquidity(pool_id, &account_id, &amounts);
+        let min_shares = min_shares.map_or(1, |v| v.0);
+        require!(
+            shares >= min_shares,
+            format!("add_liquidity: minted shares {} are less than min shares {}", shares, min_shares)
+        );
+        let tokens = self.internal_unwrap_pool(pool_id).tokens();
+        for (token_id, amount) in tokens.iter().zip(amounts).filter(|(_, amount)| *amount > 0) {
+            self.internal_withdraw(&account_id, token_id, amount);
+        }
+        self.internal_charge_storage(&account_id, initial_bytes);
+
+        shares.into()
+    }
+
+    /// Burns `shares` of the pool `pool_id` of the caller and pays out all the tokens pro rata to the reserves.
+    /// The call fails if the payout is less than `min_amounts`, given in the order of the tokens of the pool.
+    pub fn remove_liquidity(&mut self, pool_id: u64, shares: U128, min_amounts: Option<Vec<U128>>) {
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+
+        let amounts = self.internal_remove_liquidity(pool_id, &account_id, shares.0);
+        if let Some(min_amounts) = min_amounts {
+            require!(min_amounts.len() == amounts.len(), "The min amounts should be given for all the tokens of the pool");
+            require!(
+                amounts.iter().zip(&min_amounts).all(|(amount, min_amount)| *amount >= min_amount.0),
+                "remove_liquidity: the amounts out are less than the min amounts"
+            );
+        }
+        require!(amounts.iter().all(|a| *a > 0), "remove_liquidity: the amount of shares is too small");
+        self.internal_charge_storage(&account_id, initial_bytes);
+
+        let tokens = self.internal_unwrap_pool(pool_id).tokens();
+        tokens
+            .iter()
+            .zip(&amounts)
+            .map(|(token_id, amount)| self.internal_ft_transfer(token_id, &account_id, *amount, "AMM remove liquidity".to_string()))
+            .reduce(|payouts, payout| payouts.and(payout))
+            .unwrap()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_remove_liquidity(pool_id, account_id, amounts.into_iter().map(U128).collect()),
+            );
+    }
+
+    /// Records the payouts that failed as pending for the provider.
+    #[private]
+    pub fn on_remove_liquidity(&mut self, pool_id: u64, account_id: AccountId, amounts: Vec<U128>) {
+        let tokens = self.internal_unwrap_pool(pool_id).tokens();
+        for (i, (token_id, amount)) in tokens.iter().zip(amounts).enumerate() {
+            self.internal_end_transfer(token_id);
+            if !matches!(env::promise_result(i as u64), PromiseResult::Successful(_)) {
+                log!("on_remove_liquidity: the payout of {} {} to {} failed", amount.0, token_id, account_id);
+                self.internal_add_pending("remove_liquidity", &account_id, token_id, amount.0);
+            }
+        }
+    }
+
+    // Returns the amount of LP shares of the pool owned by the account
+    pub fn get_shares(&self, pool_id: u64, account_id: AccountId) -> U128 {
+        self.internal_shares_of(pool_id, &account_id).into()
+    }
+
+    // Returns the total amount of LP shares of the pool
+    pub fn get_shares_total_supply(&self, pool_id: u64) -> U128 {
+        self.internal_unwrap_pool(pool_id).shares().total_supply.into()
+    }
+}
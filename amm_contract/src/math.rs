@@ -0,0 +1,179 @@
+//! Overflow-safe arithmetic of the constant product (x * y = k) pool.
+//!
+//! All the amounts are in the base units of the tokens, the intermediate
+//! products are evaluated with 256-bit integers.
+
+use near_sdk::{require, Balance};
+
+pub use uint_types::U256;
+
+// Lints of the code generated by the macro are out of our control
+#[allow(clippy::all)]
+mod uint_types {
+    use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+    use uint::construct_uint;
+
+    construct_uint! {
+        /// 256-bit unsigned integer.
+        pub struct U256(4);
+    }
+
+    // Stored as its little-endian 64-bit words
+    impl BorshSerialize for U256 {
+        fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+            self.0.serialize(writer)
+        }
+    }
+
+    impl BorshDeserialize for U256 {
+        fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+            Ok(U256(<[u64; 4]>::deserialize(buf)?))
+        }
+    }
+}
+
+/// Denominator of the fees that are given in basis points.
+pub const FEE_DIVISOR: u32 = 10_000;
+
+/// Denominator of the prices, they have 18 decimals.
+pub const PRICE_PRECISION: u128 = 1_000_000_000_000_000_000;
+
+/// Returns the invariant `k = reserve_a * reserve_b`.
+pub fn invariant(reserve_a: Balance, reserve_b: Balance) -> U256 {
+    U256::from(reserve_a) * U256::from(reserve_b)
+}
+
+/// Returns `a * b / c` rounded down, panics if the result doesn't fit into `Balance`.
+pub fn mul_div(a: Balance, b: Balance, c: Balance) -> Balance {
+    require!(c != 0, "Division by zero");
+    let result = U256::from(a) * U256::from(b) / U256::from(c);
+    require!(result <= U256::from(Balance::MAX), "Balance overflow");
+    result.as_u128()
+}
+
+/// Returns `a * b / c` rounded up, panics if the result doesn't fit into `Balance`.
+pub fn mul_div_ceil(a: Balance, b: Balance, c: Balance) -> Balance {
+    require!(c != 0, "Division by zero");
+    let c = U256::from(c);
+    let result = (U256::from(a) * U256::from(b) + c - 1) / c;
+    require!(result <= U256::from(Balance::MAX), "Balance overflow");
+    result.as_u128()
+}
+
+/// Returns the integer square root of `a * b` rounded down.
+pub fn sqrt_of_product(a: Balance, b: Balance) -> Balance {
+    // sqrt(u128::MAX * u128::MAX) always fits into u128
+    invariant(a, b).integer_sqrt().as_u128()
+}
+
+/// Returns the amount of the output token per one unit of the input token with `PRICE_PRECISION`,
+/// zero if the pool has no liquidity.
+pub fn spot_price(reserve_in: Balance, reserve_out: Balance) -> U256 {
+    if reserve_in == 0 {
+        return U256::zero();
+    }
+    U256::from(reserve_out) * U256::from(PRICE_PRECISION) / U256::from(reserve_in)
+}
+
+/// Returns the amount of the output token for `amount_in` of the input token,
+/// the fee of `fee_bps` basis points is taken from `amount_in` and stays in the reserves:
+/// `amount_out = reserve_out * amount_in_with_fee / (reserve_in + amount_in_with_fee)`.
+pub fn get_amount_out(amount_in: Balance, reserve_in: Balance, reserve_out: Balance, fee_bps: u32) -> Balance {
+    require!(reserve_in > 0 && reserve_out > 0, "The pool has no liquidity");
+    require!(fee_bps < FEE_DIVISOR, "The fee is too high");
+    let amount_in_with_fee = U256::from(amount_in) * U256::from(FEE_DIVISOR - fee_bps);
+    let numerator = amount_in_with_fee * U256::from(reserve_out);
+    let denominator = U256::from(reserve_in) * U256::from(FEE_DIVISOR) + amount_in_with_fee;
+    // Never exceeds reserve_out
+    (numerator / denominator).as_u128()
+}
+
+/// Returns the least amount of the input token whose swap pays out `amount_out` of the output token,
+/// the inverse of `get_amount_out`:
+/// `amount_in = reserve_in * amount_out / ((reserve_out - amount_out) * (1 - fee))` rounded up.
+pub fn get_amount_in(amount_out: Balance, reserve_in: Balance, reserve_out: Balance, fee_bps: u32) -> Balance {
+    require!(reserve_in > 0 && reserve_out > 0, "The pool has no liquidity");
+    require!(fee_bps < FEE_DIVISOR, "The fee is too high");
+    require!(amount_out < reserve_out, "The pool can't pay out the amount");
+    let numerator = U256::from(reserve_in) * U256::from(amount_out) * U256::from(FEE_DIVISOR);
+    let denominator = U256::from(reserve_out - amount_out) * U256::from(FEE_DIVISOR - fee_bps);
+    let result = (numerator + denominator - 1) / denominator;
+    require!(result <= U256::from(Balance::MAX), "Balance overflow");
+    result.as_u128()
+}
+
+/// Returns the amount whose part after the fee of `fee_bps` basis points is at least `amount`.
+pub fn add_fee(amount: Balance, fee_bps: u32) -> Balance {
+    require!(fee_bps < FEE_DIVISOR, "The fee is too high");
+    mul_div_ceil(amount, FEE_DIVISOR as Balance, (FEE_DIVISOR - fee_bps) as Balance)
+}
+
+/// Returns `fee_bps` basis points of `amount` rounded down.
+pub fn fee_of(amount: Balance, fee_bps: u32) -> Balance {
+    mul_div(amount, fee_bps as Balance, FEE_DIVISOR as Balance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_amount_out() {
+        assert_eq!(get_amount_out(1_000, 1_000_000, 1_000_000, 0), 999);
+        assert_eq!(get_amount_out(1, 10, 10, 0), 0);
+        assert_eq!(get_amount_out(10, 10, 10, 0), 5);
+    }
+
+    #[test]
+    fn test_get_amount_out_with_fee() {
+        // 0.3% of 1_000_000 stays in the pool
+        assert_eq!(get_amount_out(1_000_000, 1_000_000_000, 1_000_000_000, 30), 996_006);
+        assert_eq!(fee_of(1_000_000, 30), 3_000);
+    }
+
+    #[test]
+    fn test_get_amount_out_large_reserves() {
+        // The product of the reserves exceeds u128
+        let reserve = 10_u128.pow(30);
+        assert_eq!(get_amount_out(reserve, reserve, reserve, 0), reserve / 2);
+        assert!(invariant(reserve, reserve) > U256::from(Balance::MAX));
+    }
+
+    #[test]
+    fn test_get_amount_in_is_least() {
+        let (reserve_in, reserve_out) = (123_456_789_u128, 987_654_321_u128);
+        for fee_bps in [0, 30, 100] {
+            for amount_out in [1, 7, 1_000, 55_555_555, 900_000_000] {
+                let amount_in = get_amount_in(amount_out, reserve_in, reserve_out, fee_bps);
+                assert!(get_amount_out(amount_in, reserve_in, reserve_out, fee_bps) >= amount_out);
+                assert!(get_amount_out(amount_in - 1, reserve_in, reserve_out, fee_bps) < amount_out);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mul_div_rounding() {
+        assert_eq!(mul_div(10, 10, 3), 33);
+        assert_eq!(mul_div_ceil(10, 10, 3), 34);
+        assert_eq!(mul_div_ceil(10, 9, 3), 30);
+    }
+
+    #[test]
+    fn test_sqrt_of_product() {
+        assert_eq!(sqrt_of_product(4, 9), 6);
+        assert_eq!(sqrt_of_product(10, 10), 10);
+        assert_eq!(sqrt_of_product(2, 5), 3);
+        assert_eq!(sqrt_of_product(Balance::MAX, Balance::MAX), Balance::MAX);
+    }
+
+    #[test]
+    fn test_invariant_is_non_decreasing() {
+        let (reserve_in, reserve_out) = (123_456_789_u128, 987_654_321_u128);
+        for fee_bps in [0, 30, 100] {
+            for amount_in in [1, 7, 1_000, 55_555_555, 10_u128.pow(12)] {
+                let amount_out = get_amount_out(amount_in, reserve_in, reserve_out, fee_bps);
+                assert!(invariant(reserve_in + amount_in, reserve_out - amount_out) >= invariant(reserve_in, reserve_out));
+            }
+        }
+    }
+}
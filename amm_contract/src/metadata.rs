@@ -1,15 +1,21 @@
-use near_sdk::Balance;
+use near_sdk::{near_bindgen, Balance};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::Base64VecU8;
 use near_sdk::serde::{Deserialize, Serialize};
 
+use crate::*;
+
+/// The specific version of the standard we're using
+pub const FT_METADATA_SPEC: &str = "ft-1.0.0";
+
 #[derive(Debug, Default, BorshDeserialize, BorshSerialize, Clone, Deserialize, Serialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct FungibleTokenMetadata {
     pub spec: String, // Should be ft-1.0.0 to indicate that a Fungible Token contract adheres to the current versions of this Metadata and the Fungible Token Core specs. This will allow consumers of the Fungible Token to know if they support the features of a given contract.
     pub name: String, // The human-readable name of the token.
     pub symbol: String, // The abbreviation, like wETH or AMPL.
-    pub total_supply: Balance, // The total number of tokens in the contract.
+    #[serde(default)]
+    pub total_supply: Balance, // The total number of tokens in the contract, not a part of NEP-148.
     pub icon: Option<String>, // Icon of the fungible token.
     pub reference: Option<String>, // A link to a valid JSON file containing various keys offering supplementary details on the token 
     pub reference_hash: Option<Base64VecU8>, // The base64-encoded sha256 hash of the JSON file contained in the reference field. This is to guard against off-chain tampering.
@@ -21,3 +27,31 @@ impl FungibleTokenMetadata {
         Default::default()
     }
 }
+
+pub trait FungibleTokenMetadataProvider {
+    // View call for returning the metadata of the LP shares token
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+}
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        // The LP shares of the main pool are the token of the contract
+        let pool = self.internal_unwrap_pool(MAIN_POOL_ID);
+        let tokens = pool.tokens();
+        let token_a_meta = self.tokens.get(&tokens[0]).unwrap_or_default();
+        let token_b_meta = self.tokens.get(&tokens[1]).unwrap_or_default();
+
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: format!("AMM {}-{} LP shares", token_a_meta.symbol, token_b_meta.symbol),
+            symbol: format!("LP-{}-{}", token_a_meta.symbol, token_b_meta.symbol),
+            total_supply: pool.shares().total_supply,
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            // The first deposit mints sqrt(amount_a * amount_b) shares
+            decimals: ((token_a_meta.decimals as u16 + token_b_meta.decimals as u16) / 2) as u8,
+        }
+    }
+}
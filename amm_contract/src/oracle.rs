@@ -0,0 +1,201 @@
+//! Time-weighted average prices of the pools.
+//!
+//! Each pool accumulates `ln` of the prices of its tokens in the first token multiplied by the time
+//! they lasted, like the tick accumulator of Uniswap v3. The accumulators are recorded at most once per
+//! block into a ring buffer of the latest observations, the geometric mean price over a window is
+//! `exp` of the difference of the accumulators divided by the length of the window.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::{env, ext_contract, near_bindgen, require, Balance};
+
+use crate::math::U256;
+use crate::pool::Pool;
+use crate::weighted_math::{self, Signed};
+use crate::*;
+
+/// The number of the latest observations kept for each pool.
+pub const MAX_OBSERVATIONS: u32 = 64;
+
+const NANOSECONDS: u64 = 1_000_000_000;
+
+#[ext_contract(ext_oracle)]
+pub trait PriceOracle {
+    /// Returns the time-weighted average prices of the tokens of the pool `pool_id` in its first token
+    /// over the last `window_seconds`, in the order of the tokens of the pool.
+    /// The prices are the amounts of the first token in base units per one base unit of each token
+    /// with 18 decimals, the price of the first token is always `10^18`.
+    /// Panics if the window starts before the oldest observation of the pool.
+    fn get_twap(&self, pool_id: u64, window_seconds: u64) -> Vec<U128>;
+}
+
+/// Accumulated prices of the pool at the moment of a block.
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct Observation {
+    /// Block timestamp in nanoseconds
+    pub timestamp: u64,
+    /// Sums of `ln` of the prices multiplied by the nanoseconds they lasted, they wrap around on overflow
+    pub ln_price_cumulatives: Vec<i128>,
+}
+
+impl Observation {
+    /// Returns the observation at `timestamp` if the prices stay `ln_prices` since this one.
+    fn advance(&self, ln_prices: &[i128], timestamp: u64) -> Observation {
+        let elapsed = (timestamp - self.timestamp) as i128;
+        Observation {
+            timestamp,
+            ln_price_cumulatives: self
+                .ln_price_cumulatives
+                .iter()
+                .zip(ln_prices)
+                .map(|(cumulative, ln_price)| cumulative.wrapping_add(ln_price.wrapping_mul(elapsed)))
+                .collect(),
+        }
+    }
+}
+
+/// Price history of a pool.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Oracle {
+    /// `ln` of the prices of the tokens in the first token since the last update, with 18 decimals.
+    /// Empty until the pool has a price.
+    pub ln_prices: Vec<i128>,
+    /// Ring buffer of the latest observations
+    pub observations: LookupMap<u32, Observation>,
+    /// Index of the latest observation
+    pub index: u32,
+    /// Number of the recorded observations, up to `MAX_OBSERVATIONS`
+    pub cardinality: u32,
+}
+
+impl Oracle {
+    pub fn new(pool_id: u64) -> Self {
+        Self {
+            ln_prices: vec![],
+            observations: LookupMap::new(StorageKey::Observations { pool_id }.try_to_vec().unwrap()),
+            index: 0,
+            cardinality: 0,
+        }
+    }
+
+    /// Returns `ln` of the prices of the tokens of the pool in its first token, none if the pool has no price.
+    fn ln_prices_of(pool: &Pool) -> Option<Vec<i128>> {
+        let tokens = pool.tokens();
+        let mut ln_prices = vec![0];
+        for token_id in &tokens[1..] {
+            let price = pool.spot_price(token_id, &tokens[0]);
+            if price.is_zero() {
+                return None;
+            }
+            let ln_price = weighted_math::ln(price);
+            let value = ln_price.value.as_u128() as i128;
+            ln_prices.push(if ln_price.negative { -value } else { value });
+        }
+        Some(ln_prices)
+    }
+
+    /// Accumulates the prices up to the current block and takes the new prices of the pool.
+    /// Called after every change of the reserves, records at most one observation per block.
+    pub fn update(&mut self, pool: &Pool) {
+        let now = env::block_timestamp();
+        let ln_prices = Self::ln_prices_of(pool);
+
+        if self.cardinality == 0 {
+            // The history starts with the first price of the pool
+            if let Some(ln_prices) = ln_prices {
+                let observation = Observation { timestamp: now, ln_price_cumulatives: vec![0; ln_prices.len()] };
+                self.observations.insert(&0, &observation);
+                self.cardinality = 1;
+                self.ln_prices = ln_prices;
+            }
+            return;
+        }
+
+        let latest = self.observation(self.index);
+        if now > latest.timestamp {
+            self.index = (self.index + 1) % MAX_OBSERVATIONS;
+            self.cardinality = (self.cardinality + 1).min(MAX_OBSERVATIONS);
+            self.observations.insert(&self.index, &latest.advance(&self.ln_prices, now));
+        }
+        // An emptied pool keeps its last price
+        if let Some(ln_prices) = ln_prices {
+            self.ln_prices = ln_prices;
+        }
+    }
+
+    /// Returns the time-weighted geometric mean prices over the last `window_seconds` with 18 decimals.
+    pub fn twap(&self, window_seconds: u64) -> Vec<Balance> {
+        require!(self.cardinality > 0, "The pool has no price observations");
+        require!(window_seconds > 0, "The window should be positive");
+
+        let now = env::block_timestamp();
+        let window = window_seconds.saturating_mul(NANOSECONDS);
+        require!(window <= now, "The window is longer than the observations history");
+        let start = now - window;
+
+        self.cumulatives_at(now)
+            .iter()
+            .zip(self.cumulatives_at(start))
+            .map(|(end, start)| {
+                let mean = end.wrapping_sub(start) / window as i128;
+                let price = weighted_math::exp(Signed { negative: mean < 0, value: U256::from(mean.unsigned_abs()) });
+                require!(price <= U256::from(Balance::MAX), "Balance overflow");
+                price.as_u128()
+            })
+            .collect()
+    }
+
+    fn observation(&self, index: u32) -> Observation {
+        self.observations.get(&index).unwrap()
+    }
+
+    /// Returns the `i`-th observation from the oldest one.
+    fn observation_from_oldest(&self, i: u32) -> Observation {
+        let oldest = if self.cardinality < MAX_OBSERVATIONS { 0 } else { (self.index + 1) % MAX_OBSERVATIONS };
+        self.observation((oldest + i) % MAX_OBSERVATIONS)
+    }
+
+    /// Returns the accumulated prices at `timestamp`, interpolated between the observations around it.
+    fn cumulatives_at(&self, timestamp: u64) -> Vec<i128> {
+        let latest = self.observation(self.index);
+        if timestamp >= latest.timestamp {
+            return latest.advance(&self.ln_prices, timestamp).ln_price_cumulatives;
+        }
+        require!(
+            timestamp >= self.observation_from_oldest(0).timestamp,
+            "The window is longer than the observations history"
+        );
+
+        // The observation `low` is at or before the timestamp, the observation `high` is after it
+        let (mut low, mut high) = (0, self.cardinality - 1);
+        while high - low > 1 {
+            let middle = (low + high) / 2;
+            if self.observation_from_oldest(middle).timestamp <= timestamp {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        let (before, after) = (self.observation_from_oldest(low), self.observation_from_oldest(high));
+
+        // The prices are constant between two observations
+        let ln_prices: Vec<i128> = before
+            .ln_price_cumulatives
+            .iter()
+            .zip(&after.ln_price_cumulatives)
+            .map(|(b, a)| a.wrapping_sub(*b) / (after.timestamp - before.timestamp) as i128)
+            .collect();
+        before.advance(&ln_prices, timestamp).ln_price_cumulatives
+    }
+}
+
+#[near_bindgen]
+impl PriceOracle for Contract {
+    // Returns the time-weighted average prices of the tokens of the pool in its first token over the last window
+    fn get_twap(&self, pool_id: u64, window_seconds: u64) -> Vec<U128> {
+        self.internal_unwrap_pool(pool_id);
+        let oracle = self.oracles.get(&pool_id).unwrap();
+        oracle.twap(window_seconds).into_iter().map(U128).collect()
+    }
+}
@@ -0,0 +1,121 @@
+//! Emergency stop of the trading.
+//!
+//! A pauser can pause the whole AMM or a single pool. A paused pool takes no swaps and no liquidity,
+//! the liquidity, the deposits and the pending amounts can still be withdrawn. The circuit breaker of a pool
+//! pauses it after a single swap moves the price of the pool more than the configured limit.
+
+use near_sdk::{log, near_bindgen, require};
+
+use crate::events::Paused;
+use crate::math::U256;
+use crate::*;
+
+impl Contract {
+    /// Returns true if the whole AMM or the pool is paused.
+    pub(crate) fn internal_is_pool_paused(&self, pool_id: u64) -> bool {
+        self.paused || self.paused_pools.contains(&pool_id)
+    }
+
+    pub(crate) fn internal_assert_not_paused(&self) {
+        require!(!self.paused, "The AMM is paused");
+    }
+
+    pub(crate) fn internal_assert_pool_not_paused(&self, pool_id: u64) {
+        self.internal_assert_not_paused();
+        require!(!self.paused_pools.contains(&pool_id), format!("Pool {} is paused", pool_id));
+    }
+
+    /// Pauses the pool if the swap moved its price from `price_before` to `price_after` (both of `token_in`
+    /// in `token_out`) more than the circuit breaker of the pool allows.
+    pub(crate) fn internal_check_circuit_breaker(&mut self, pool_id: u64, price_before: U256, price_after: U256) {
+        let max_price_change_bps = match self.circuit_breakers.get(&pool_id) {
+            Some(max_price_change_bps) => max_price_change_bps,
+            None => return,
+        };
+        if price_before.is_zero() {
+            return;
+        }
+
+        // A swap always lowers the price of the input token
+        let price_change_bps = (price_before - price_after.min(price_before)) * U256::from(math::FEE_DIVISOR) / price_before;
+        if price_change_bps > U256::from(max_price_change_bps) {
+            log!("circuit breaker: the price of pool {} moved by {} bps, pausing the pool", pool_id, price_change_bps);
+            self.paused_pools.insert(&pool_id);
+            Paused {
+                pool_id: Some(pool_id),
+                paused: true,
+                account_id: None,
+                memo: Some("the circuit breaker tripped"),
+            }
+            .emit();
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Pauses the swaps and the deposits of all the pools, requires the role `Pauser`.
+    pub fn pause(&mut self) {
+        self.internal_assert_role(Role::Pauser);
+        self.paused = true;
+        Paused { pool_id: None, paused: true, account_id: Some(&env::predecessor_account_id()), memo: None }.emit();
+    }
+
+    /// Resumes the trading of all the pools that are not paused on their own, requires the role `Pauser`.
+    pub fn unpause(&mut self) {
+        self.internal_assert_role(Role::Pauser);
+        self.paused = false;
+        Paused { pool_id: None, paused: false, account_id: Some(&env::predecessor_account_id()), memo: None }.emit();
+    }
+
+    /// Pauses the swaps and the deposits of the pool, requires the role `Pauser`.
+    pub fn pause_pool(&mut self, pool_id: u64) {
+        self.internal_assert_role(Role::Pauser);
+        self.internal_unwrap_pool(pool_id);
+        self.paused_pools.insert(&pool_id);
+        Paused { pool_id: Some(pool_id), paused: true, account_id: Some(&env::predecessor_account_id()), memo: None }.emit();
+    }
+
+    /// Resumes the trading of the pool paused by a pauser or by its circuit breaker, requires the role `Pauser`.
+    pub fn unpause_pool(&mut self, pool_id: u64) {
+        self.internal_assert_role(Role::Pauser);
+        require!(self.paused_pools.remove(&pool_id), format!("Pool {} is not paused", pool_id));
+        Paused { pool_id: Some(pool_id), paused: false, account_id: Some(&env::predecessor_account_id()), memo: None }.emit();
+    }
+
+    /// Sets the largest move of the price of the pool in basis points a single swap can make before the pool
+    /// is paused, `None` disables the circuit breaker. The swap that trips it is executed. Requires the role `Pauser`.
+    pub fn set_circuit_breaker(&mut self, pool_id: u64, max_price_change_bps: Option<u32>) {
+        self.internal_assert_role(Role::Pauser);
+        self.internal_unwrap_pool(pool_id);
+
+        match max_price_change_bps {
+            Some(max_price_change_bps) => {
+                require!(
+                    max_price_change_bps > 0 && max_price_change_bps < math::FEE_DIVISOR,
+                    format!("The price change should be between 0 and {} basis points", math::FEE_DIVISOR)
+                );
+                self.circuit_breakers.insert(&pool_id, &max_price_change_bps);
+            }
+            None => {
+                self.circuit_breakers.remove(&pool_id);
+            }
+        }
+        log!("set_circuit_breaker: pool_id: {} max_price_change_bps: {:?}", pool_id, max_price_change_bps);
+    }
+
+    // Returns true if the whole AMM is paused
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Returns true if the pool is paused on its own or together with the whole AMM
+    pub fn is_pool_paused(&self, pool_id: u64) -> bool {
+        self.internal_is_pool_paused(pool_id)
+    }
+
+    // Returns the largest price move in basis points of a single swap of the pool, if the circuit breaker is set
+    pub fn get_circuit_breaker(&self, pool_id: u64) -> Option<u32> {
+        self.circuit_breakers.get(&pool_id)
+    }
+}
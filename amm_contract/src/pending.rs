@@ -0,0 +1,93 @@
+//! Payouts of the AMM that could not be delivered.
+//!
+//! A payout fails when the receiver is not registered with the token contract or the transfer runs out
+//! of gas. The tokens stay in the AMM as pending amounts of the receiver until it claims them.
+
+use std::collections::BTreeMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, require, AccountId, Balance, Promise, PromiseResult};
+
+use crate::events::TransferFailed;
+use crate::external::ext_self;
+use crate::*;
+
+impl Contract {
+    /// Records the payout of the token to the account that failed in a callback of `action`.
+    pub(crate) fn internal_add_pending(&mut self, action: &str, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let initial_bytes = self.internal_records_bytes(account_id);
+        let mut pending = self.pending.get(account_id).unwrap_or_default();
+        let balance = pending.entry(token_id.clone()).or_insert(0);
+        *balance = balance
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Balance overflow"));
+        self.pending.insert(account_id, &pending);
+        self.internal_track_storage(account_id, initial_bytes);
+        self.internal_add_total(token_id, amount);
+
+        TransferFailed {
+            action,
+            account_id,
+            token_id,
+            amount: &U128(amount),
+            memo: Some("the tokens are pending for a claim"),
+        }
+        .emit();
+    }
+
+    /// Removes the whole pending amount of the token of the account and returns it.
+    fn internal_take_pending(&mut self, account_id: &AccountId, token_id: &AccountId) -> Balance {
+        let mut pending = self.pending.get(account_id).unwrap_or_default();
+        let amount = pending.remove(token_id).unwrap_or(0);
+        if pending.is_empty() {
+            self.pending.remove(account_id);
+        } else {
+            self.pending.insert(account_id, &pending);
+        }
+        self.internal_sub_total(token_id, amount);
+        amount
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Retries the transfer of the whole pending amount of the token to the caller,
+    /// the amount stays pending if the transfer fails again. Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn claim_pending(&mut self, token_id: AccountId) -> Promise {
+        assert_one_yocto();
+
+        let account_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&account_id);
+        let amount = self.internal_take_pending(&account_id, &token_id);
+        require!(amount > 0, format!("There is no pending amount of {}", token_id));
+        self.internal_charge_storage(&account_id, initial_bytes);
+
+        self.internal_ft_transfer(&token_id, &account_id, amount, "AMM claim".to_string())
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_claim_pending(account_id, token_id, amount.into()),
+            )
+    }
+
+    /// Restores the pending amount if the transfer of the claim failed.
+    #[private]
+    pub fn on_claim_pending(&mut self, account_id: AccountId, token_id: AccountId, amount: U128) {
+        self.internal_end_transfer(&token_id);
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!("on_claim_pending: the transfer of {} {} to {} failed", amount.0, token_id, account_id);
+            self.internal_add_pending("claim_pending", &account_id, &token_id, amount.0);
+        }
+    }
+
+    // Returns the amounts of the tokens whose payout to the account failed, they can be claimed with `claim_pending`
+    pub fn get_pending(&self, account_id: AccountId) -> BTreeMap<AccountId, U128> {
+        self.pending
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(token_id, amount)| (token_id, U128(amount)))
+            .collect()
+    }
+}
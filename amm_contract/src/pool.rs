@@ -0,0 +1,395 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, require, AccountId, Balance};
+
+use crate::concentrated_pool::ConcentratedPool;
+use crate::math::{self, U256};
+use crate::shares::LpShares;
+use crate::simple_pool::SimplePool;
+use crate::stable_swap_pool::StableSwapPool;
+use crate::weighted_pool::WeightedPool;
+
+/// The largest number of tokens in a pool.
+pub const MAX_POOL_TOKENS: usize = 8;
+
+const NO_SHARES: &str = "The liquidity of a concentrated pool is provided with positions";
+
+/// All the kinds of the pools the AMM supports.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub enum Pool {
+    SimplePool(SimplePool),
+    StableSwapPool(StableSwapPool),
+    WeightedPool(WeightedPool),
+    ConcentratedPool(ConcentratedPool),
+}
+
+impl Pool {
+    /// Returns the name of the pool kind.
+    pub fn kind(&self) -> String {
+        match self {
+            Pool::SimplePool(_) => "SIMPLE_POOL".to_string(),
+            Pool::StableSwapPool(_) => "STABLE_SWAP".to_string(),
+            Pool::WeightedPool(_) => "WEIGHTED_POOL".to_string(),
+            Pool::ConcentratedPool(_) => "CONCENTRATED_POOL".to_string(),
+        }
+    }
+
+    /// Returns the tokens of the pool.
+    pub fn tokens(&self) -> Vec<AccountId> {
+        match self {
+            Pool::SimplePool(pool) => vec![pool.token_a.clone(), pool.token_b.clone()],
+            Pool::StableSwapPool(pool) => pool.tokens.clone(),
+            Pool::WeightedPool(pool) => pool.tokens.clone(),
+            Pool::ConcentratedPool(pool) => pool.tokens.clone(),
+        }
+    }
+
+    /// Returns the amounts of the tokens held by the pool, in base units.
+    pub fn reserves(&self) -> Vec<Balance> {
+        match self {
+            Pool::SimplePool(pool) => vec![pool.reserve_a, pool.reserve_b],
+            Pool::StableSwapPool(pool) => pool.reserves.clone(),
+            Pool::WeightedPool(pool) => pool.reserves.clone(),
+            Pool::ConcentratedPool(pool) => pool.reserves.clone(),
+        }
+    }
+
+    /// Overwrites the reserve of the token, panics for a concentrated pool whose reserves are defined by its positions.
+    pub fn set_reserve(&mut self, token_id: &AccountId, reserve: Balance) {
+        match self {
+            Pool::SimplePool(pool) => pool.set_reserve(token_id, reserve),
+            Pool::StableSwapPool(pool) => {
+                let index = pool.index_of(token_id);
+                pool.reserves[index] = reserve;
+            }
+            Pool::WeightedPool(pool) => {
+                let index = pool.index_of(token_id);
+                pool.reserves[index] = reserve;
+            }
+            Pool::ConcentratedPool(_) => env::panic_str("The reserves of a concentrated pool are defined by its positions"),
+        }
+    }
+
+    /// Returns true if the token is one of the pool.
+    pub fn contains(&self, token_id: &AccountId) -> bool {
+        self.tokens().contains(token_id)
+    }
+
+    /// Returns the opposite token of a pool of two tokens, panics if `token_id` is not part of the pool.
+    pub fn opposite_token(&self, token_id: &AccountId) -> AccountId {
+        let tokens = self.tokens();
+        require!(tokens.len() == 2, "The output token should be given for a pool of more than two tokens");
+        if tokens[0] == *token_id {
+            tokens[1].clone()
+        } else if tokens[1] == *token_id {
+            tokens[0].clone()
+        } else {
+            env::panic_str(format!("Unsupported token contract id: {}", token_id).as_str());
+        }
+    }
+
+    /// Returns the invariant of the pool.
+    pub fn invariant(&self) -> U256 {
+        match self {
+            Pool::SimplePool(pool) => math::invariant(pool.reserve_a, pool.reserve_b),
+            Pool::StableSwapPool(pool) => pool.invariant(),
+            Pool::WeightedPool(pool) => pool.invariant(),
+            Pool::ConcentratedPool(pool) => pool.invariant(),
+        }
+    }
+
+    /// Returns the swap fee in basis points.
+    pub fn fee_bps(&self) -> u32 {
+        match self {
+            Pool::SimplePool(pool) => pool.fee_bps,
+            Pool::StableSwapPool(pool) => pool.fee_bps,
+            Pool::WeightedPool(pool) => pool.fee_bps,
+            Pool::ConcentratedPool(pool) => pool.fee_bps,
+        }
+    }
+
+    /// Returns the part of the swap fee in basis points of the fee that is accrued for the owner.
+    pub fn protocol_share_bps(&self) -> u32 {
+        match self {
+            Pool::SimplePool(pool) => pool.protocol_share_bps,
+            Pool::StableSwapPool(pool) => pool.protocol_share_bps,
+            Pool::WeightedPool(pool) => pool.protocol_share_bps,
+            Pool::ConcentratedPool(pool) => pool.protocol_share_bps,
+        }
+    }
+
+    /// Sets the swap fee and the protocol part of it.
+    pub fn set_fee(&mut self, fee_bps: u32, protocol_share_bps: u32) {
+        match self {
+            Pool::SimplePool(pool) => {
+                pool.fee_bps = fee_bps;
+                pool.protocol_share_bps = protocol_share_bps;
+            }
+            Pool::StableSwapPool(pool) => {
+                pool.fee_bps = fee_bps;
+                pool.protocol_share_bps = protocol_share_bps;
+            }
+            Pool::WeightedPool(pool) => {
+                pool.fee_bps = fee_bps;
+                pool.protocol_share_bps = protocol_share_bps;
+            }
+            Pool::ConcentratedPool(pool) => {
+                pool.fee_bps = fee_bps;
+                pool.protocol_share_bps = protocol_share_bps;
+            }
+        }
+    }
+
+    /// Returns the protocol fees of the tokens accrued for the owner.
+    pub fn protocol_fees(&self) -> Vec<Balance> {
+        match self {
+            Pool::SimplePool(pool) => vec![pool.protocol_fee_a, pool.protocol_fee_b],
+            Pool::StableSwapPool(pool) => pool.protocol_fees.clone(),
+            Pool::WeightedPool(pool) => pool.protocol_fees.clone(),
+            Pool::ConcentratedPool(pool) => pool.protocol_fees.clone(),
+        }
+    }
+
+    /// Accrues the protocol part of the swap fee in the token.
+    pub fn add_protocol_fee(&mut self, token_id: &AccountId, amount: Balance) {
+        match self {
+            Pool::SimplePool(pool) => pool.add_protocol_fee(token_id, amount),
+            Pool::StableSwapPool(pool) => {
+                let index = pool.index_of(token_id);
+                pool.protocol_fees[index] += amount;
+            }
+            Pool::WeightedPool(pool) => {
+                let index = pool.index_of(token_id);
+                pool.protocol_fees[index] += amount;
+            }
+            Pool::ConcentratedPool(pool) => {
+                let index = pool.index_of(token_id);
+                pool.protocol_fees[index] += amount;
+            }
+        }
+    }
+
+    /// Removes the accrued protocol fee in the token.
+    pub fn sub_protocol_fee(&mut self, token_id: &AccountId, amount: Balance) {
+        match self {
+            Pool::SimplePool(pool) => pool.sub_protocol_fee(token_id, amount),
+            Pool::StableSwapPool(pool) => {
+                let index = pool.index_of(token_id);
+                pool.protocol_fees[index] -= amount;
+            }
+            Pool::WeightedPool(pool) => {
+                let index = pool.index_of(token_id);
+                pool.protocol_fees[index] -= amount;
+            }
+            Pool::ConcentratedPool(pool) => {
+                let index = pool.index_of(token_id);
+                pool.protocol_fees[index] -= amount;
+            }
+        }
+    }
+
+    /// Returns the amount of `token_out` per one unit of `token_in` with `PRICE_PRECISION`, without the fee.
+    /// Zero if the pool has no liquidity.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        match self {
+            Pool::SimplePool(pool) => {
+                require!(pool.opposite_token(token_in) == *token_out, "The tokens of the swap should be different");
+                math::spot_price(pool.reserve_of(token_in), pool.reserve_of(token_out))
+            }
+            Pool::StableSwapPool(pool) => pool.spot_price(token_in, token_out),
+            Pool::WeightedPool(pool) => pool.spot_price(token_in, token_out),
+            Pool::ConcentratedPool(pool) => pool.spot_price(token_in, token_out),
+        }
+    }
+
+    /// Returns the amount of `token_out` that a swap of `amount_in` of `token_in` would pay out.
+    pub fn get_return(&self, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> Balance {
+        match self {
+            Pool::SimplePool(pool) => {
+                require!(pool.opposite_token(token_in) == *token_out, "The tokens of the swap should be different");
+                pool.get_return(token_in, amount_in).1
+            }
+            Pool::StableSwapPool(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::WeightedPool(pool) => pool.get_return(token_in, amount_in, token_out),
+            Pool::ConcentratedPool(pool) => pool.get_return(token_in, amount_in, token_out),
+        }
+    }
+
+    /// Returns the least amount of `token_in` whose swap pays out at least `amount_out` of `token_out`.
+    /// The simple pools invert their formula exactly, the stable and the weighted pools start from the inverse of
+    /// their invariant and correct its rounding with a few calls of `get_return`, so the amount is exactly what
+    /// the swap needs. The concentrated pools have no closed-form inverse across the ticks and are searched.
+    pub fn get_amount_in(&self, token_in: &AccountId, amount_out: Balance, token_out: &AccountId) -> Balance {
+        require!(amount_out > 0, "The amount should be a positive number");
+        let index = self.tokens().iter().position(|t| t == token_out);
+        require!(index.is_some(), format!("Unsupported token contract id: {}", token_out));
+        require!(
+            amount_out < self.reserves()[index.unwrap()],
+            format!("The pool can't pay out {} of {}", amount_out, token_out)
+        );
+
+        let estimate = match self {
+            Pool::SimplePool(pool) => {
+                require!(pool.opposite_token(token_in) == *token_out, "The tokens of the swap should be different");
+                let (reserve_in, reserve_out) = (pool.reserve_of(token_in), pool.reserve_of(token_out));
+                return math::get_amount_in(amount_out, reserve_in, reserve_out, pool.fee_bps);
+            }
+            Pool::StableSwapPool(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::WeightedPool(pool) => pool.get_amount_in(token_in, amount_out, token_out),
+            Pool::ConcentratedPool(_) => amount_out,
+        };
+        self.find_amount_in(token_in, amount_out, token_out, estimate.max(1))
+    }
+
+    /// Returns the least amount of `token_in` whose swap pays out at least `amount_out` of `token_out`, searched
+    /// with `get_return` from `estimate` by doubling the step away from it, then by halving the found interval.
+    fn find_amount_in(&self, token_in: &AccountId, amount_out: Balance, token_out: &AccountId, estimate: Balance) -> Balance {
+        let pays_out = |amount_in: Balance| self.get_return(token_in, amount_in, token_out) >= amount_out;
+        let cant_pay_out = || env::panic_str(format!("The pool can't pay out {} of {}", amount_out, token_out).as_str());
+
+        // The swap of `low` pays out less than `amount_out`, the swap of `high` pays out enough
+        let (mut low, mut high);
+        let mut step: Balance = 1;
+        if pays_out(estimate) {
+            high = estimate;
+            low = high.saturating_sub(step);
+            while low > 0 && pays_out(low) {
+                high = low;
+                step = step.saturating_mul(2);
+                low = low.saturating_sub(step);
+            }
+        } else {
+            low = estimate;
+            high = low.checked_add(step).unwrap_or_else(cant_pay_out);
+            while !pays_out(high) {
+                low = high;
+                step = step.checked_mul(2).unwrap_or_else(cant_pay_out);
+                high = high.checked_add(step).unwrap_or_else(cant_pay_out);
+            }
+        }
+        while high - low > 1 {
+            let middle = low + (high - low) / 2;
+            if pays_out(middle) {
+                high = middle;
+            } else {
+                low = middle;
+            }
+        }
+        high
+    }
+
+    /// Exchanges `amount_in` of `token_in` to `token_out` and updates the reserves.
+    /// Returns the amount of `token_out` that has to be paid out and the accrued protocol fee.
+    pub fn swap(&mut self, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> (Balance, Balance) {
+        match self {
+            Pool::SimplePool(pool) => {
+                require!(pool.opposite_token(token_in) == *token_out, "The tokens of the swap should be different");
+                let (_, amount_out, protocol_fee) = pool.swap(token_in, amount_in);
+                (amount_out, protocol_fee)
+            }
+            Pool::StableSwapPool(pool) => pool.swap(token_in, amount_in, token_out),
+            Pool::WeightedPool(pool) => pool.swap(token_in, amount_in, token_out),
+            Pool::ConcentratedPool(pool) => pool.swap(token_in, amount_in, token_out),
+        }
+    }
+
+    /// Returns the LP shares of the pool, panics for a concentrated pool.
+    pub fn shares(&self) -> &LpShares {
+        match self {
+            Pool::SimplePool(pool) => &pool.shares,
+            Pool::StableSwapPool(pool) => &pool.shares,
+            Pool::WeightedPool(pool) => &pool.shares,
+            Pool::ConcentratedPool(_) => env::panic_str(NO_SHARES),
+        }
+    }
+
+    /// Returns the LP shares of the pool for the update, panics for a concentrated pool.
+    pub fn shares_mut(&mut self) -> &mut LpShares {
+        match self {
+            Pool::SimplePool(pool) => &mut pool.shares,
+            Pool::StableSwapPool(pool) => &mut pool.shares,
+            Pool::WeightedPool(pool) => &mut pool.shares,
+            Pool::ConcentratedPool(_) => env::panic_str(NO_SHARES),
+        }
+    }
+
+    /// Returns the total amount of LP shares, zero for a concentrated pool.
+    pub fn shares_total_supply(&self) -> Balance {
+        match self {
+            Pool::ConcentratedPool(_) => 0,
+            _ => self.shares().total_supply,
+        }
+    }
+
+    /// Returns true if the account holds LP shares of the pool, or positions of a concentrated pool.
+    pub fn is_held_by(&self, account_id: &AccountId) -> bool {
+        match self {
+            Pool::ConcentratedPool(pool) => pool.account_positions.get(account_id).is_some(),
+            _ => self.shares().balance_of(account_id) > 0,
+        }
+    }
+
+    /// Adds the tokens to the reserves, the amounts are given in the order of `tokens`.
+    /// Returns the amounts of the tokens that are actually used, the shares to mint to the provider
+    /// and the shares to lock on the first deposit. The shares are not minted here.
+    pub fn add_liquidity(&mut self, amounts: &[Balance]) -> (Vec<Balance>, Balance, Balance) {
+        match self {
+            Pool::SimplePool(pool) => {
+                require!(amounts.len() == 2, "The amounts should be given for all the tokens of the pool");
+                let (amount_a, amount_b, shares, locked_shares) = pool.add_liquidity(amounts[0], amounts[1]);
+                (vec![amount_a, amount_b], shares, locked_shares)
+            }
+            Pool::StableSwapPool(pool) => {
+                let (shares, locked_shares) = pool.add_liquidity(amounts);
+                (amounts.to_vec(), shares, locked_shares)
+            }
+            Pool::WeightedPool(pool) => pool.add_liquidity(amounts),
+            Pool::ConcentratedPool(_) => env::panic_str(NO_SHARES),
+        }
+    }
+
+    /// Removes the pro rata part of all the reserves for `shares`, the shares are not burned here.
+    /// Returns the amounts of the tokens that have to be paid out.
+    pub fn remove_liquidity(&mut self, shares: Balance) -> Vec<Balance> {
+        match self {
+            Pool::SimplePool(pool) => {
+                let (amount_a, amount_b) = pool.remove_liquidity(shares);
+                vec![amount_a, amount_b]
+            }
+            Pool::StableSwapPool(pool) => pool.remove_liquidity(shares),
+            Pool::WeightedPool(pool) => pool.remove_liquidity(shares),
+            Pool::ConcentratedPool(_) => env::panic_str(NO_SHARES),
+        }
+    }
+
+    /// Returns the StableSwap pool, panics for the other kinds.
+    pub fn as_stable(&self) -> &StableSwapPool {
+        match self {
+            Pool::StableSwapPool(pool) => pool,
+            _ => env::panic_str("The pool is not a StableSwap pool"),
+        }
+    }
+
+    /// Returns the StableSwap pool for the update, panics for the other kinds.
+    pub fn as_stable_mut(&mut self) -> &mut StableSwapPool {
+        match self {
+            Pool::StableSwapPool(pool) => pool,
+            _ => env::panic_str("The pool is not a StableSwap pool"),
+        }
+    }
+
+    /// Returns the concentrated pool, panics for the other kinds.
+    pub fn as_concentrated(&self) -> &ConcentratedPool {
+        match self {
+            Pool::ConcentratedPool(pool) => pool,
+            _ => env::panic_str("The pool is not a concentrated pool"),
+        }
+    }
+
+    /// Returns the concentrated pool for the update, panics for the other kinds.
+    pub fn as_concentrated_mut(&mut self) -> &mut ConcentratedPool {
+        match self {
+            Pool::ConcentratedPool(pool) => pool,
+            _ => env::panic_str("The pool is not a concentrated pool"),
+        }
+    }
+}
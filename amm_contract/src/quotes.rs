@@ -0,0 +1,59 @@
+//! Quotes of the swaps for the frontends.
+//!
+//! The views run the same code of the pools as the swaps, so a quote is exactly what the swap pays out
+//! against the current reserves. `token_out` (or `quote_token`) can be omitted for the pools of two tokens.
+//! The quotes of the swaps panic with the same messages as the swaps while the pool doesn't trade: the AMM or
+//! the pool is paused, a flash loan of the pool is in flight or the metadata of its tokens is not known yet.
+//! The spot price only reads the reserves, so it is also given for the pools that don't trade.
+
+use near_sdk::json_types::U128;
+use near_sdk::{near_bindgen, AccountId};
+
+use crate::math::U256;
+use crate::*;
+
+impl Contract {
+    /// Returns the output token of a swap of `token_in`, the opposite token if it is not given.
+    fn internal_token_out(&self, pool_id: u64, token_in: &AccountId, token_out: Option<AccountId>) -> AccountId {
+        token_out.unwrap_or_else(|| self.internal_unwrap_pool(pool_id).opposite_token(token_in))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    // Returns the amount of `token_out` that a swap of `amount_in` of `token_in` in the pool would pay out
+    pub fn get_return(&self, pool_id: u64, token_in: AccountId, amount_in: U128, token_out: Option<AccountId>) -> U128 {
+        let token_out = self.internal_token_out(pool_id, &token_in, token_out);
+        self.internal_get_return(pool_id, &token_in, amount_in.0, &token_out).into()
+    }
+
+    // Returns the least amount of `token_in` a swap in the pool needs to pay out `amount_out` of `token_out`
+    pub fn get_amount_in(&self, pool_id: u64, token_out: AccountId, amount_out: U128, token_in: Option<AccountId>) -> U128 {
+        let token_in = self.internal_token_out(pool_id, &token_out, token_in);
+        self.internal_unwrap_tradable_pool(pool_id).get_amount_in(&token_in, amount_out.0, &token_out).into()
+    }
+
+    // Returns the amount of `quote_token` per one unit of `base_token` with `PRICE_PRECISION`, without the fee.
+    // Zero if the pool has no liquidity
+    pub fn spot_price(&self, pool_id: u64, base_token: AccountId, quote_token: Option<AccountId>) -> U128 {
+        let quote_token = self.internal_token_out(pool_id, &base_token, quote_token);
+        let price = self.internal_unwrap_pool(pool_id).spot_price(&base_token, &quote_token);
+        require!(price <= U256::from(u128::MAX), "The price doesn't fit into U128");
+        price.as_u128().into()
+    }
+
+    // Returns how much less than at the spot price a swap of `amount_in` of `token_in` pays out, in basis points,
+    // the fee included
+    pub fn price_impact(&self, pool_id: u64, token_in: AccountId, amount_in: U128, token_out: Option<AccountId>) -> U128 {
+        let token_out = self.internal_token_out(pool_id, &token_in, token_out);
+        let pool = self.internal_unwrap_tradable_pool(pool_id);
+        let at_spot_price = U256::from(amount_in.0) * pool.spot_price(&token_in, &token_out) / U256::from(math::PRICE_PRECISION);
+        if at_spot_price.is_zero() {
+            return U128(0);
+        }
+
+        let amount_out = U256::from(pool.get_return(&token_in, amount_in.0, &token_out));
+        let impact = (at_spot_price - amount_out.min(at_spot_price)) * U256::from(math::FEE_DIVISOR) / at_spot_price;
+        impact.as_u128().into()
+    }
+}
@@ -0,0 +1,112 @@
+//! Access control of the AMM.
+//!
+//! The owner holds every role and is the only one who grants and revokes them. The ownership itself
+//! is transferred in two steps: the owner proposes the new owner, the new owner accepts it.
+
+use std::collections::BTreeSet;
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, require, AccountId};
+
+use crate::*;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Grants and revokes the roles and receives the protocol fees, held by `owner_id` only
+    Owner,
+    /// Sets the fees and the amplification of the pools and withdraws the protocol fees to the owner
+    FeeManager,
+    /// Pauses and unpauses the trading
+    Pauser,
+    /// Registers the tokens and creates the pools
+    PoolCreator,
+    /// Reconciles the reserves with the balances of the AMM with `sync` and `skim`, refreshes the metadata of the tokens
+    /// and fills the limit orders
+    Keeper,
+}
+
+impl Contract {
+    /// Returns true if the account is the owner or has been granted the role.
+    pub(crate) fn internal_has_role(&self, account_id: &AccountId, role: Role) -> bool {
+        *account_id == self.owner_id || self.roles.get(account_id).is_some_and(|roles| roles.contains(&role))
+    }
+
+    pub(crate) fn internal_assert_role(&self, role: Role) {
+        require!(
+            self.internal_has_role(&env::predecessor_account_id(), role),
+            format!("The method requires the role {:?}", role)
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Grants the role to the account, only the owner can call it.
+    pub fn grant_role(&mut self, account_id: AccountId, role: Role) {
+        self.internal_assert_owner();
+        require!(role != Role::Owner, "The ownership is transferred with propose_owner and accept_owner");
+
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        roles.insert(role);
+        self.roles.insert(&account_id, &roles);
+        log!("grant_role: {:?} to {}", role, account_id);
+    }
+
+    /// Revokes the role from the account, only the owner can call it.
+    pub fn revoke_role(&mut self, account_id: AccountId, role: Role) {
+        self.internal_assert_owner();
+        require!(role != Role::Owner, "The ownership is transferred with propose_owner and accept_owner");
+
+        let mut roles = self.roles.get(&account_id).unwrap_or_default();
+        require!(roles.remove(&role), format!("{} doesn't have the role {:?}", account_id, role));
+        if roles.is_empty() {
+            self.roles.remove(&account_id);
+        } else {
+            self.roles.insert(&account_id, &roles);
+        }
+        log!("revoke_role: {:?} from {}", role, account_id);
+    }
+
+    /// Proposes the new owner, the ownership is transferred once it calls `accept_owner`.
+    /// Only the owner can call it, a new proposal replaces the previous one.
+    pub fn propose_owner(&mut self, new_owner_id: AccountId) {
+        self.internal_assert_owner();
+        log!("propose_owner: {} proposes {}", self.owner_id, new_owner_id);
+        self.proposed_owner_id = Some(new_owner_id);
+    }
+
+    /// Makes the caller the owner, only the proposed owner can call it.
+    pub fn accept_owner(&mut self) {
+        let account_id = env::predecessor_account_id();
+        require!(
+            self.proposed_owner_id.as_ref() == Some(&account_id),
+            "Only the proposed owner can accept the ownership"
+        );
+        log!("accept_owner: {} -> {}", self.owner_id, account_id);
+        self.owner_id = account_id;
+        self.proposed_owner_id = None;
+    }
+
+    // Returns true if the account has the role, the owner has all the roles
+    pub fn has_role(&self, account_id: AccountId, role: Role) -> bool {
+        self.internal_has_role(&account_id, role)
+    }
+
+    // Returns the roles granted to the account
+    pub fn get_roles(&self, account_id: AccountId) -> BTreeSet<Role> {
+        self.roles.get(&account_id).unwrap_or_default()
+    }
+
+    // Returns the owner of the AMM
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    // Returns the account proposed to become the owner, if any
+    pub fn get_proposed_owner(&self) -> Option<AccountId> {
+        self.proposed_owner_id.clone()
+    }
+}
@@ -0,0 +1,98 @@
+//! Multi-hop swaps through a sequence of pools.
+
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, require, AccountId, Balance, Promise};
+
+use crate::*;
+
+/// The longest route that can be swapped in one call.
+pub const MAX_HOPS: usize = 4;
+
+/// One hop of a route, exchanges `token_in` to `token_out` in the pool `pool_id`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapHop {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+}
+
+/// A hop that has been applied to the reserves.
+#[derive(Clone, Debug)]
+pub struct SwapResult {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: U128,
+    pub token_out: AccountId,
+    pub amount_out: U128,
+}
+
+impl Contract {
+    /// Swaps `amount_in` of `token_in` through all the hops, the output of a hop is the input of the next one.
+    /// The reserves of all the pools are updated, so any failure in the middle of the route panics and nothing is applied.
+    /// Returns the applied hops, the last one holds the final amount out.
+    pub(crate) fn internal_swap_route(
+        &mut self,
+        account_id: &AccountId,
+        token_in: &AccountId,
+        amount_in: Balance,
+        hops: &[SwapHop],
+    ) -> Vec<SwapResult> {
+        require!(!hops.is_empty(), "The route should have at least one hop");
+        require!(hops.len() <= MAX_HOPS, format!("The route can't have more than {} hops", MAX_HOPS));
+        require!(hops[0].token_in == *token_in, format!("The route should start with {}", token_in));
+
+        let mut results: Vec<SwapResult> = Vec::with_capacity(hops.len());
+        let mut amount = amount_in;
+        for (i, hop) in hops.iter().enumerate() {
+            if i > 0 {
+                require!(hop.token_in == hops[i - 1].token_out, format!("Hop {} doesn't continue the route", i));
+            }
+
+            let (amount_out, _) = self.internal_swap(hop.pool_id, account_id, &hop.token_in, amount, &hop.token_out);
+
+            results.push(SwapResult {
+                pool_id: hop.pool_id,
+                token_in: hop.token_in.clone(),
+                amount_in: amount.into(),
+                token_out: hop.token_out.clone(),
+                amount_out: amount_out.into(),
+            });
+            amount = amount_out;
+        }
+
+        results
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Exchanges `amount` of the deposit of the input token of the first hop through the pools of the `hops`,
+    /// in return, sends the output token of the last hop to the caller like `deposit_contract`.
+    /// The call fails if it pays out less than `min_amount_out` or the block timestamp is beyond `deadline` (in nanoseconds).
+    /// Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn deposit_route(&mut self, hops: Vec<SwapHop>, amount: U128, min_amount_out: U128, deadline: Option<U64>) -> Promise {
+        assert_one_yocto();
+        require!(amount.0 > 0, "The amount should be a positive number");
+        require!(!internal::is_deadline_passed(deadline), "deposit_route: the deadline has passed");
+        require!(!hops.is_empty(), "The route should have at least one hop");
+
+        let sender_id = env::predecessor_account_id();
+        let initial_bytes = self.internal_records_bytes(&sender_id);
+        let token_in = hops[0].token_in.clone();
+
+        self.internal_withdraw(&sender_id, &token_in, amount.0);
+        let results = self.internal_swap_route(&sender_id, &token_in, amount.0, &hops);
+        let last = results.last().unwrap();
+        require!(
+            last.amount_out.0 >= min_amount_out.0,
+            format!("deposit_route: amount out {} is less than min amount out {}", last.amount_out.0, min_amount_out.0)
+        );
+        self.internal_charge_storage(&sender_id, initial_bytes);
+
+        log!("deposit_route: {} swapped {} {} to {} {}", sender_id, amount.0, token_in, last.amount_out.0, last.token_out);
+        self.internal_send(&sender_id, &last.token_out, last.amount_out.0, "AMM swap")
+    }
+}
@@ -0,0 +1,61 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::{env, require, AccountId, Balance};
+
+use crate::StorageKey;
+
+/// LP shares of a pool.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LpShares {
+    /// LP shares of each liquidity provider
+    pub accounts: LookupMap<AccountId, Balance>,
+    /// Total amount of LP shares, including the locked minimum liquidity
+    pub total_supply: Balance,
+}
+
+impl LpShares {
+    pub fn new(pool_id: u64) -> Self {
+        Self {
+            accounts: LookupMap::new(StorageKey::Shares { pool_id }.try_to_vec().unwrap()),
+            total_supply: 0,
+        }
+    }
+
+    /// Returns the amount of LP shares owned by the account.
+    pub fn balance_of(&self, account_id: &AccountId) -> Balance {
+        self.accounts.get(account_id).unwrap_or(0)
+    }
+
+    /// Deposits some amount of LP shares into an account.
+    pub fn deposit(&mut self, account_id: &AccountId, amount: Balance) {
+        let balance = self.balance_of(account_id)
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Shares overflow"));
+        self.accounts.insert(account_id, &balance);
+    }
+
+    /// Withdraws some amount of LP shares from an account, the empty records are removed.
+    pub fn withdraw(&mut self, account_id: &AccountId, amount: Balance) {
+        let balance = self.balance_of(account_id);
+        require!(balance >= amount, "The account doesn't have enough shares");
+        if balance == amount {
+            self.accounts.remove(account_id);
+        } else {
+            self.accounts.insert(account_id, &(balance - amount));
+        }
+    }
+
+    /// Mints `amount` of LP shares to the account.
+    pub fn mint(&mut self, account_id: &AccountId, amount: Balance) {
+        self.deposit(account_id, amount);
+        self.total_supply = self.total_supply
+            .checked_add(amount)
+            .unwrap_or_else(|| env::panic_str("Shares overflow"));
+    }
+
+    /// Burns `amount` of LP shares of the account.
+    pub fn burn(&mut self, account_id: &AccountId, amount: Balance) {
+        self.withdraw(account_id, amount);
+        self.total_supply -= amount;
+    }
+}
@@ -0,0 +1,186 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, require, AccountId, Balance};
+
+use crate::math;
+use crate::shares::LpShares;
+
+/// Amount of LP shares that is locked forever on the first deposit,
+/// so the price of a share can't be inflated to the point where nobody can afford it.
+pub const MINIMUM_LIQUIDITY: Balance = 1_000;
+
+/// Constant product (x * y = k) pool of two tokens.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct SimplePool {
+    pub token_a: AccountId,
+    pub token_b: AccountId,
+    /// Amount of token A held by the pool, in base units
+    pub reserve_a: Balance,
+    /// Amount of token B held by the pool, in base units
+    pub reserve_b: Balance,
+    /// Swap fee in basis points, taken from the input amount
+    pub fee_bps: u32,
+    /// Part of the swap fee in basis points of the fee that is accrued for the owner instead of the LPs
+    pub protocol_share_bps: u32,
+    /// Protocol fee of token A accrued for the owner
+    pub protocol_fee_a: Balance,
+    /// Protocol fee of token B accrued for the owner
+    pub protocol_fee_b: Balance,
+    /// LP shares of the pool
+    pub shares: LpShares,
+}
+
+impl SimplePool {
+    pub fn new(pool_id: u64, token_a: AccountId, token_b: AccountId, fee_bps: u32) -> Self {
+        require!(token_a != token_b, "The tokens of the pool should be different");
+        Self {
+            token_a,
+            token_b,
+            reserve_a: 0,
+            reserve_b: 0,
+            fee_bps,
+            protocol_share_bps: 0,
+            protocol_fee_a: 0,
+            protocol_fee_b: 0,
+            shares: LpShares::new(pool_id),
+        }
+    }
+
+    /// Returns true if the token is one of the pair.
+    pub fn contains(&self, token_id: &AccountId) -> bool {
+        self.token_a == *token_id || self.token_b == *token_id
+    }
+
+    /// Returns the opposite token of the pair, panics if `token_id` is not part of the pair.
+    pub fn opposite_token(&self, token_id: &AccountId) -> AccountId {
+        if self.token_a == *token_id {
+            self.token_b.clone()
+        } else if self.token_b == *token_id {
+            self.token_a.clone()
+        } else {
+            env::panic_str(format!("Unsupported token contract id: {}", token_id).as_str());
+        }
+    }
+
+    /// Returns the reserve of the token that is held by the pool, in base units.
+    pub fn reserve_of(&self, token_id: &AccountId) -> Balance {
+        if self.token_a == *token_id {
+            self.reserve_a
+        } else if self.token_b == *token_id {
+            self.reserve_b
+        } else {
+            env::panic_str(format!("Unsupported token contract id: {}", token_id).as_str());
+        }
+    }
+
+    /// Overwrites the reserve of the token that is held by the pool.
+    pub fn set_reserve(&mut self, token_id: &AccountId, reserve: Balance) {
+        if self.token_a == *token_id {
+            self.reserve_a = reserve;
+        } else if self.token_b == *token_id {
+            self.reserve_b = reserve;
+        } else {
+            env::panic_str(format!("Unsupported token contract id: {}", token_id).as_str());
+        }
+    }
+
+    /// Returns the opposite token and the amount of it that a swap of `amount_in` of `token_in` would pay out.
+    pub fn get_return(&self, token_in: &AccountId, amount_in: Balance) -> (AccountId, Balance) {
+        let token_out = self.opposite_token(token_in);
+
+        let reserve_in = self.reserve_of(token_in);
+        let reserve_out = self.reserve_of(&token_out);
+
+        (token_out, math::get_amount_out(amount_in, reserve_in, reserve_out, self.fee_bps))
+    }
+
+    /// Exchanges `amount_in` of `token_in` using the constant product formula and updates the reserves.
+    /// The LP part of the fee stays in the reserves, the protocol part is accrued separately.
+    /// Returns the opposite token, the amount of it that has to be paid out and the accrued protocol fee.
+    pub fn swap(&mut self, token_in: &AccountId, amount_in: Balance) -> (AccountId, Balance, Balance) {
+        let (token_out, amount_out) = self.get_return(token_in, amount_in);
+        require!(amount_out > 0, "swap: the amount is too small to be swapped");
+
+        let reserve_in = self.reserve_of(token_in);
+        let reserve_out = self.reserve_of(&token_out);
+
+        let protocol_fee = math::fee_of(math::fee_of(amount_in, self.fee_bps), self.protocol_share_bps);
+        let reserve_in_post = reserve_in + amount_in - protocol_fee;
+        let reserve_out_post = reserve_out - amount_out;
+
+        // The rounding must always be in favor of the pool
+        require!(
+            math::invariant(reserve_in_post, reserve_out_post) >= math::invariant(reserve_in, reserve_out),
+            "swap: the invariant has decreased"
+        );
+
+        self.set_reserve(token_in, reserve_in_post);
+        self.set_reserve(&token_out, reserve_out_post);
+        self.add_protocol_fee(token_in, protocol_fee);
+
+        (token_out, amount_out, protocol_fee)
+    }
+
+    /// Accrues the protocol part of the swap fee in the token.
+    pub fn add_protocol_fee(&mut self, token_id: &AccountId, amount: Balance) {
+        if self.token_a == *token_id {
+            self.protocol_fee_a += amount;
+        } else {
+            self.protocol_fee_b += amount;
+        }
+    }
+
+    /// Removes the accrued protocol fee in the token.
+    pub fn sub_protocol_fee(&mut self, token_id: &AccountId, amount: Balance) {
+        if self.token_a == *token_id {
+            self.protocol_fee_a -= amount;
+        } else {
+            self.protocol_fee_b -= amount;
+        }
+    }
+
+    /// Adds both tokens to the reserves in proportion to the current reserves.
+    /// Returns the amounts of the tokens that are actually used, the shares to mint to the provider
+    /// and the shares to lock on the first deposit. The shares are not minted here.
+    pub fn add_liquidity(&mut self, amount_a: Balance, amount_b: Balance) -> (Balance, Balance, Balance, Balance) {
+        require!(amount_a > 0 && amount_b > 0, "Both amounts should be positive numbers");
+
+        let (amount_a, amount_b, shares, locked_shares) = if self.shares.total_supply == 0 {
+            // The first deposit defines the ratio, a part of the shares is locked forever
+            let shares = math::sqrt_of_product(amount_a, amount_b);
+            require!(shares > MINIMUM_LIQUIDITY, "Insufficient initial liquidity");
+            (amount_a, amount_b, shares - MINIMUM_LIQUIDITY, MINIMUM_LIQUIDITY)
+        } else {
+            let shares = std::cmp::min(
+                math::mul_div(amount_a, self.shares.total_supply, self.reserve_a),
+                math::mul_div(amount_b, self.shares.total_supply, self.reserve_b),
+            );
+            require!(shares > 0, "Insufficient liquidity minted");
+            // The rounding must always be in favor of the pool
+            (
+                math::mul_div_ceil(shares, self.reserve_a, self.shares.total_supply),
+                math::mul_div_ceil(shares, self.reserve_b, self.shares.total_supply),
+                shares,
+                0,
+            )
+        };
+
+        self.reserve_a += amount_a;
+        self.reserve_b += amount_b;
+
+        (amount_a, amount_b, shares, locked_shares)
+    }
+
+    /// Removes the pro rata part of both reserves for `shares`, the shares are not burned here.
+    /// Returns the amounts of the tokens that have to be paid out.
+    pub fn remove_liquidity(&mut self, shares: Balance) -> (Balance, Balance) {
+        require!(shares > 0, "The amount of shares should be a positive number");
+
+        let amount_a = math::mul_div(shares, self.reserve_a, self.shares.total_supply);
+        let amount_b = math::mul_div(shares, self.reserve_b, self.shares.total_supply);
+
+        self.reserve_a -= amount_a;
+        self.reserve_b -= amount_b;
+
+        (amount_a, amount_b)
+    }
+}
@@ -0,0 +1,158 @@
+//! Arithmetic of the StableSwap invariant of Curve:
+//! `A * n^n * sum(x_i) + D = A * D * n^n + D^(n+1) / (n^n * prod(x_i))`.
+//!
+//! All the balances are normalized to the same number of decimals,
+//! the invariant is solved with the Newton's method over 256-bit integers.
+
+use near_sdk::require;
+
+use crate::math::{PRICE_PRECISION, U256};
+
+/// The lowest amplification coefficient.
+pub const MIN_AMP: u64 = 1;
+/// The highest amplification coefficient.
+pub const MAX_AMP: u64 = 1_000_000;
+
+/// The Newton's method converges in a few iterations for any sane balances.
+const MAX_ITERATIONS: usize = 256;
+
+/// Returns `A * n^n`.
+fn amp_times_coins(amp: u64, n: usize) -> U256 {
+    U256::from(amp) * U256::from(n).pow(U256::from(n))
+}
+
+/// Returns the invariant D of the normalized balances `xp`.
+pub fn compute_d(xp: &[U256], amp: u64) -> U256 {
+    let n = U256::from(xp.len());
+    let sum = xp.iter().fold(U256::zero(), |sum, x| sum + x);
+    if sum.is_zero() {
+        return U256::zero();
+    }
+    require!(xp.iter().all(|x| !x.is_zero()), "The pool has no liquidity");
+
+    let ann = amp_times_coins(amp, xp.len());
+    let mut d = sum;
+    for _ in 0..MAX_ITERATIONS {
+        // D^(n+1) / (n^n * prod(x_i))
+        let d_p = xp.iter().fold(d, |d_p, x| d_p * d / (*x * n));
+        let d_prev = d;
+        d = (ann * sum + d_p * n) * d / ((ann - 1) * d + (n + 1) * d_p);
+        if abs_diff(d, d_prev) <= U256::one() {
+            return d;
+        }
+    }
+    near_sdk::env::panic_str("The invariant doesn't converge")
+}
+
+/// Returns the normalized balance of the token `j` that keeps the invariant `d`
+/// when the normalized balance of the token `i` becomes `x`.
+pub fn compute_y(xp: &[U256], amp: u64, i: usize, j: usize, x: U256, d: U256) -> U256 {
+    require!(i != j && i < xp.len() && j < xp.len(), "Invalid token indexes");
+
+    let n = U256::from(xp.len());
+    let ann = amp_times_coins(amp, xp.len());
+
+    let mut c = d;
+    let mut sum = U256::zero();
+    for (k, balance) in xp.iter().enumerate() {
+        let balance = if k == i {
+            x
+        } else if k != j {
+            *balance
+        } else {
+            continue;
+        };
+        sum += balance;
+        c = c * d / (balance * n);
+    }
+    c = c * d / (ann * n);
+    let b = sum + d / ann;
+
+    let mut y = d;
+    for _ in 0..MAX_ITERATIONS {
+        let y_prev = y;
+        y = (y * y + c) / (y * 2 + b - d);
+        if abs_diff(y, y_prev) <= U256::one() {
+            return y;
+        }
+    }
+    near_sdk::env::panic_str("The invariant doesn't converge")
+}
+
+/// Returns the marginal price of the token `i` in the token `j` of the normalized balances `xp`
+/// with `PRICE_PRECISION`, the ratio of the partial derivatives of the invariant:
+/// `x_j * (Ann * x_i + c) / (x_i * (Ann * x_j + c))`, where `c = D^(n+1) / (n^n * prod(x_k))`.
+pub fn spot_price(xp: &[U256], amp: u64, i: usize, j: usize) -> U256 {
+    require!(i != j && i < xp.len() && j < xp.len(), "Invalid token indexes");
+    if xp.iter().any(|x| x.is_zero()) {
+        return U256::zero();
+    }
+
+    let n = U256::from(xp.len());
+    let ann = amp_times_coins(amp, xp.len());
+    let d = compute_d(xp, amp);
+    let c = xp.iter().fold(d, |c, x| c * d / (*x * n));
+    // Keeps the intermediate products within 256 bits
+    (ann * xp[i] + c) * U256::from(PRICE_PRECISION) / (ann * xp[j] + c) * xp[j] / xp[i]
+}
+
+fn abs_diff(a: U256, b: U256) -> U256 {
+    if a > b { a - b } else { b - a }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xp(balances: &[u128]) -> Vec<U256> {
+        balances.iter().map(|b| U256::from(*b)).collect()
+    }
+
+    #[test]
+    fn test_compute_d_balanced() {
+        // D equals the sum of the balances when the pool is balanced
+        let d = compute_d(&xp(&[1_000_000, 1_000_000]), 100);
+        assert!(abs_diff(d, U256::from(2_000_000)) <= U256::one());
+        assert!(compute_d(&xp(&[0, 0]), 100).is_zero());
+    }
+
+    #[test]
+    fn test_compute_d_is_between_product_and_sum() {
+        let balances = xp(&[1_000_000_000, 3_000_000_000]);
+        let d = compute_d(&balances, 50);
+        // Constant product: 2 * sqrt(x * y), constant sum: x + y
+        assert!(d > U256::from(2 * 1_732_050_807_u128));
+        assert!(d < U256::from(4_000_000_000_u128));
+    }
+
+    #[test]
+    fn test_compute_y_keeps_invariant() {
+        let balances = xp(&[10_u128.pow(24), 10_u128.pow(24)]);
+        for amp in [1, 100, 10_000] {
+            let d = compute_d(&balances, amp);
+            let x = balances[0] + U256::from(10_u128.pow(21));
+            let y = compute_y(&balances, amp, 0, 1, x, d);
+            let d_post = compute_d(&[x, y], amp);
+            assert!(abs_diff(d_post, d) <= U256::from(2));
+            // The higher the amplification, the closer the price is to 1
+            assert!(balances[1] - y <= U256::from(10_u128.pow(21)));
+        }
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let one = U256::from(PRICE_PRECISION);
+        assert_eq!(spot_price(&xp(&[10_u128.pow(24), 10_u128.pow(24)]), 100, 0, 1), one);
+
+        // The price of the scarce token is between 1 and the price of the constant product
+        let balances = xp(&[10_u128.pow(24), 3 * 10_u128.pow(24)]);
+        let price = spot_price(&balances, 100, 0, 1);
+        assert!(price > one && price < one * 3);
+
+        // A small swap pays out close to the spot price
+        let d = compute_d(&balances, 100);
+        let dx = U256::from(10_u128.pow(18));
+        let dy = balances[1] - compute_y(&balances, 100, 0, 1, balances[0] + dx, d);
+        assert!(abs_diff(dy * one / dx, price) <= price / 1_000_000);
+    }
+}
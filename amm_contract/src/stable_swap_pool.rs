@@ -0,0 +1,326 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U64;
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance};
+
+use crate::math::{self, U256};
+use crate::pool::{Pool, MAX_POOL_TOKENS};
+use crate::shares::LpShares;
+use crate::simple_pool::MINIMUM_LIQUIDITY;
+use crate::stable_math::{self, MAX_AMP, MIN_AMP};
+use crate::*;
+
+/// The shortest ramp of the amplification coefficient, 1 day in nanoseconds.
+pub const MIN_RAMP_DURATION: u64 = 86_400 * 1_000_000_000;
+/// The amplification coefficient can change at most 10 times in one ramp.
+pub const MAX_AMP_CHANGE: u64 = 10;
+
+/// StableSwap pool of the tokens that are expected to trade close to 1:1.
+/// The balances of the tokens with different decimals are normalized to the largest decimals.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StableSwapPool {
+    pub tokens: Vec<AccountId>,
+    /// Decimals of the tokens from the cached metadata
+    pub decimals: Vec<u8>,
+    /// Amounts of the tokens held by the pool, in base units
+    pub reserves: Vec<Balance>,
+    /// Swap fee in basis points, taken from the input amount
+    pub fee_bps: u32,
+    /// Part of the swap fee in basis points of the fee that is accrued for the owner instead of the LPs
+    pub protocol_share_bps: u32,
+    /// Protocol fees of the tokens accrued for the owner
+    pub protocol_fees: Vec<Balance>,
+    /// Amplification coefficient at the start of the ramp
+    pub init_amp: u64,
+    /// Amplification coefficient at the end of the ramp
+    pub target_amp: u64,
+    /// Block timestamp of the start of the ramp, in nanoseconds
+    pub init_amp_time: u64,
+    /// Block timestamp of the end of the ramp, in nanoseconds
+    pub stop_amp_time: u64,
+    /// LP shares of the pool
+    pub shares: LpShares,
+}
+
+impl StableSwapPool {
+    pub fn new(pool_id: u64, tokens: Vec<AccountId>, decimals: Vec<u8>, fee_bps: u32, amp: u64) -> Self {
+        require!(
+            (2..=MAX_POOL_TOKENS).contains(&tokens.len()),
+            format!("The pool should have from 2 to {} tokens", MAX_POOL_TOKENS)
+        );
+        require!(tokens.len() == decimals.len(), "The decimals should be given for all the tokens of the pool");
+        require!(
+            (1..tokens.len()).all(|i| !tokens[..i].contains(&tokens[i])),
+            "The tokens of the pool should be different"
+        );
+        require!((MIN_AMP..=MAX_AMP).contains(&amp), format!("The amplification should be in [{}, {}]", MIN_AMP, MAX_AMP));
+
+        let n = tokens.len();
+        Self {
+            tokens,
+            decimals,
+            reserves: vec![0; n],
+            fee_bps,
+            protocol_share_bps: 0,
+            protocol_fees: vec![0; n],
+            init_amp: amp,
+            target_amp: amp,
+            init_amp_time: 0,
+            stop_amp_time: 0,
+            shares: LpShares::new(pool_id),
+        }
+    }
+
+    /// Returns the index of the token, panics if `token_id` is not part of the pool.
+    pub fn index_of(&self, token_id: &AccountId) -> usize {
+        self.tokens
+            .iter()
+            .position(|t| t == token_id)
+            .unwrap_or_else(|| env::panic_str(format!("Unsupported token contract id: {}", token_id).as_str()))
+    }
+
+    /// Returns the current amplification coefficient, it changes linearly during the ramp.
+    pub fn amp(&self) -> u64 {
+        let now = env::block_timestamp();
+        if now >= self.stop_amp_time {
+            return self.target_amp;
+        }
+
+        let elapsed = (now - self.init_amp_time) as u128;
+        let duration = (self.stop_amp_time - self.init_amp_time) as u128;
+        if self.target_amp > self.init_amp {
+            self.init_amp + ((self.target_amp - self.init_amp) as u128 * elapsed / duration) as u64
+        } else {
+            self.init_amp - ((self.init_amp - self.target_amp) as u128 * elapsed / duration) as u64
+        }
+    }
+
+    /// Starts changing the amplification coefficient from the current value to `target_amp` until `stop_time`.
+    pub fn ramp_amp(&mut self, target_amp: u64, stop_time: u64) {
+        let now = env::block_timestamp();
+        require!(
+            (MIN_AMP..=MAX_AMP).contains(&target_amp),
+            format!("The amplification should be in [{}, {}]", MIN_AMP, MAX_AMP)
+        );
+        require!(stop_time >= now + MIN_RAMP_DURATION, "The ramp is too short");
+
+        let amp = self.amp();
+        require!(
+            target_amp <= amp * MAX_AMP_CHANGE && amp <= target_amp * MAX_AMP_CHANGE,
+            format!("The amplification can change at most {} times", MAX_AMP_CHANGE)
+        );
+
+        self.init_amp = amp;
+        self.target_amp = target_amp;
+        self.init_amp_time = now;
+        self.stop_amp_time = stop_time;
+    }
+
+    /// Stops the ramp at the current amplification coefficient.
+    pub fn stop_ramp_amp(&mut self) {
+        let amp = self.amp();
+        let now = env::block_timestamp();
+        self.init_amp = amp;
+        self.target_amp = amp;
+        self.init_amp_time = now;
+        self.stop_amp_time = now;
+    }
+
+    /// Returns the multiplier of the token that normalizes its balance to the largest decimals of the pool.
+    fn rate(&self, index: usize) -> U256 {
+        let max_decimals = *self.decimals.iter().max().unwrap();
+        U256::from(10).pow(U256::from(max_decimals - self.decimals[index]))
+    }
+
+    /// Returns the balances normalized to the largest decimals of the pool.
+    fn normalize(&self, balances: &[Balance]) -> Vec<U256> {
+        balances
+            .iter()
+            .enumerate()
+            .map(|(i, balance)| U256::from(*balance) * self.rate(i))
+            .collect()
+    }
+
+    /// Returns the invariant D of the current reserves.
+    pub fn invariant(&self) -> U256 {
+        stable_math::compute_d(&self.normalize(&self.reserves), self.amp())
+    }
+
+    /// Returns the amount of `token_out` per one unit of `token_in` with `PRICE_PRECISION`, without the fee.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        let price = stable_math::spot_price(&self.normalize(&self.reserves), self.amp(), i, j);
+        // The price of the normalized balances to the price of the base units
+        price * self.rate(i) / self.rate(j)
+    }
+
+    /// Returns the amount of `token_out` that a swap of `amount_in` of `token_in` would pay out.
+    pub fn get_return(&self, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> Balance {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        require!(i != j, "The tokens of the swap should be different");
+        require!(self.reserves.iter().all(|r| *r > 0), "The pool has no liquidity");
+
+        let amp = self.amp();
+        let xp = self.normalize(&self.reserves);
+        let d = stable_math::compute_d(&xp, amp);
+
+        let amount_in_with_fee = amount_in - math::fee_of(amount_in, self.fee_bps);
+        let x = xp[i] + U256::from(amount_in_with_fee) * self.rate(i);
+        let y = stable_math::compute_y(&xp, amp, i, j, x, d);
+
+        // The rounding must always be in favor of the pool
+        if xp[j] <= y + 1 {
+            return 0;
+        }
+        ((xp[j] - y - 1) / self.rate(j)).as_u128()
+    }
+
+    /// Returns the amount of `token_in` whose swap pays out `amount_out` of `token_out`, the inverse of `get_return`
+    /// solved with `compute_y` for the input balance. It can be off by the rounding of the invariant.
+    pub fn get_amount_in(&self, token_in: &AccountId, amount_out: Balance, token_out: &AccountId) -> Balance {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        require!(i != j, "The tokens of the swap should be different");
+        require!(self.reserves.iter().all(|r| *r > 0), "The pool has no liquidity");
+
+        let amp = self.amp();
+        let xp = self.normalize(&self.reserves);
+        let d = stable_math::compute_d(&xp, amp);
+
+        let y = U256::from(amount_out) * self.rate(j) + 1;
+        require!(y < xp[j], format!("The pool can't pay out {} of {}", amount_out, token_out));
+        let x = stable_math::compute_y(&xp, amp, j, i, xp[j] - y, d);
+        if x <= xp[i] {
+            return 0;
+        }
+        let amount_in_with_fee = ((x - xp[i] + self.rate(i) - 1) / self.rate(i)).as_u128();
+        math::add_fee(amount_in_with_fee, self.fee_bps)
+    }
+
+    /// Exchanges `amount_in` of `token_in` to `token_out` and updates the reserves.
+    /// The LP part of the fee stays in the reserves, the protocol part is accrued separately.
+    /// Returns the amount of `token_out` that has to be paid out and the accrued protocol fee.
+    pub fn swap(&mut self, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> (Balance, Balance) {
+        let amount_out = self.get_return(token_in, amount_in, token_out);
+        require!(amount_out > 0, "swap: the amount is too small to be swapped");
+
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        require!(amount_out < self.reserves[j], "swap: not enough liquidity");
+
+        let protocol_fee = math::fee_of(math::fee_of(amount_in, self.fee_bps), self.protocol_share_bps);
+        self.reserves[i] += amount_in - protocol_fee;
+        self.reserves[j] -= amount_out;
+        self.protocol_fees[i] += protocol_fee;
+
+        (amount_out, protocol_fee)
+    }
+
+    /// Adds the tokens to the reserves in any proportion.
+    /// The part of the deposit that changes the proportion of the reserves pays the swap fee, which stays in the reserves.
+    /// Returns the shares to mint to the provider and the shares to lock on the first deposit. The shares are not minted here.
+    pub fn add_liquidity(&mut self, amounts: &[Balance]) -> (Balance, Balance) {
+        require!(amounts.len() == self.tokens.len(), "The amounts should be given for all the tokens of the pool");
+        require!(amounts.iter().all(|a| *a > 0), "All the amounts should be positive numbers");
+
+        let amp = self.amp();
+        let new_reserves: Vec<Balance> = self.reserves.iter().zip(amounts).map(|(r, a)| r + a).collect();
+        let d1 = stable_math::compute_d(&self.normalize(&new_reserves), amp);
+
+        let (shares, locked_shares) = if self.shares.total_supply == 0 {
+            // The first deposit mints D shares, a part of them is locked forever
+            require!(d1 <= U256::from(Balance::MAX), "Balance overflow");
+            let shares = d1.as_u128();
+            require!(shares > MINIMUM_LIQUIDITY, "Insufficient initial liquidity");
+            (shares - MINIMUM_LIQUIDITY, MINIMUM_LIQUIDITY)
+        } else {
+            let d0 = stable_math::compute_d(&self.normalize(&self.reserves), amp);
+            require!(d1 > d0, "Insufficient liquidity minted");
+
+            // fee * n / (4 * (n - 1)) is charged from the difference with the proportional deposit
+            let n = self.tokens.len() as u128;
+            let fee_bps = (self.fee_bps as u128 * n / (4 * (n - 1))) as u32;
+            let balances: Vec<Balance> = self.reserves
+                .iter()
+                .zip(&new_reserves)
+                .map(|(old, new)| {
+                    let ideal = (d1 * U256::from(*old) / d0).as_u128();
+                    new - math::fee_of(ideal.abs_diff(*new), fee_bps)
+                })
+                .collect();
+            let d2 = stable_math::compute_d(&self.normalize(&balances), amp);
+
+            let shares = U256::from(self.shares.total_supply) * (d2 - d0) / d0;
+            require!(shares > U256::zero(), "Insufficient liquidity minted");
+            require!(shares <= U256::from(Balance::MAX), "Balance overflow");
+            (shares.as_u128(), 0)
+        };
+
+        self.reserves = new_reserves;
+        (shares, locked_shares)
+    }
+
+    /// Removes the pro rata part of all the reserves for `shares`, the shares are not burned here.
+    /// Returns the amounts of the tokens that have to be paid out.
+    pub fn remove_liquidity(&mut self, shares: Balance) -> Vec<Balance> {
+        require!(shares > 0, "The amount of shares should be a positive number");
+
+        let total_supply = self.shares.total_supply;
+        self.reserves
+            .iter_mut()
+            .map(|reserve| {
+                let amount = math::mul_div(shares, *reserve, total_supply);
+                *reserve -= amount;
+                amount
+            })
+            .collect()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Registers a new StableSwap pool of 2 to 8 tokens with the given swap fee in basis points
+    /// and amplification coefficient and returns its id.
+    /// The metadata of all the tokens should be cached, the decimals are taken from it. Requires the role `PoolCreator`.
+    pub fn add_stable_pool(&mut self, tokens: Vec<AccountId>, fee_bps: u32, amp: u64) -> u64 {
+        self.internal_assert_role(Role::PoolCreator);
+        require!(fee_bps <= fees::MAX_FEE_BPS, format!("The fee can't exceed {} bps", fees::MAX_FEE_BPS));
+
+        let decimals = tokens
+            .iter()
+            .map(|token_id| {
+                let metadata = self.tokens.get(token_id);
+                require!(metadata.is_some(), format!("The metadata of {} is not cached, register the token first", token_id));
+                metadata.unwrap().decimals
+            })
+            .collect();
+
+        let pool_id = self.pools.len();
+        let pool = StableSwapPool::new(pool_id, tokens.clone(), decimals, fee_bps, amp);
+        self.internal_add_pool(Pool::StableSwapPool(pool));
+        log!("add_stable_pool: pool {} of {:?} with fee {} bps and amp {}", pool_id, tokens, fee_bps, amp);
+        pool_id
+    }
+
+    /// Starts changing the amplification coefficient of the pool linearly
+    /// from the current value to `target_amp` until `stop_time` (in nanoseconds), requires the role `FeeManager`.
+    pub fn ramp_amp(&mut self, pool_id: u64, target_amp: u64, stop_time: U64) {
+        self.internal_assert_role(Role::FeeManager);
+
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        pool.as_stable_mut().ramp_amp(target_amp, stop_time.0);
+        self.internal_save_pool(pool_id, &pool);
+        log!("ramp_amp: pool {} to {} until {}", pool_id, target_amp, stop_time.0);
+    }
+
+    /// Stops the ramp of the amplification coefficient of the pool at the current value, requires the role `FeeManager`.
+    pub fn stop_ramp_amp(&mut self, pool_id: u64) {
+        self.internal_assert_role(Role::FeeManager);
+
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        pool.as_stable_mut().stop_ramp_amp();
+        self.internal_save_pool(pool_id, &pool);
+    }
+
+    // Returns the current amplification coefficient of the StableSwap pool
+    pub fn get_amp(&self, pool_id: u64) -> u64 {
+        self.internal_unwrap_pool(pool_id).as_stable().amp()
+    }
+}
@@ -0,0 +1,361 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, log, near_bindgen, require, AccountId, Balance, Promise, StorageUsage};
+
+use crate::concentrated_pool::Position;
+use crate::limit_orders::LimitOrder;
+use crate::shares::LpShares;
+use crate::*;
+
+// The structure that will be returned for the methods:
+// * `storage_deposit`
+// * `storage_withdraw`
+// * `storage_balance_of`
+// The `total` and `available` values are string representations of unsigned
+// 128-bit integers showing the balance of a specific account in yoctoⓃ.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+// The below structure will be returned for the method `storage_balance_bounds`.
+// Both `min` and `max` are string representations of unsigned 128-bit integers.
+//
+// `min` is the amount of tokens required to start using this contract at all
+// (eg to register with the contract). If a new contract user attaches `min`
+// NEAR to a `storage_deposit` call, subsequent calls to `storage_balance_of`
+// for this user must show their `total` equal to `min` and `available=0` .
+//
+// The AMM charges the bytes each account actually uses, so it has no `max`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+/// Storage paid by an account.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct AccountStorage {
+    /// Attached deposit in yoctoⓃ
+    pub deposit: Balance,
+    /// Bytes of the records of the account, including this record
+    pub usage: StorageUsage,
+}
+
+impl AccountStorage {
+    /// Returns the part of the deposit that is not locked by the used bytes.
+    pub fn available(&self) -> Balance {
+        self.deposit.saturating_sub(Balance::from(self.usage) * env::storage_byte_cost())
+    }
+}
+
+pub trait StorageManagement {
+    /************************************/
+    /* CHANGE METHODS on the AMM        */
+    /************************************/
+    // Payable method that receives an attached deposit of Ⓝ for a given account.
+    //
+    // If `account_id` is omitted, the deposit MUST go toward predecessor account.
+    // If provided, deposit MUST go toward this account. If invalid, contract MUST
+    // panic.
+    //
+    // If `registration_only=true`, contract MUST refund above the minimum balance
+    // if the account wasn't registered and refund full deposit if already
+    // registered.
+    //
+    // Returns the StorageBalance structure showing updated balances.
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance;
+
+    // Withdraws `amount` of the available storage balance of the predecessor, the whole
+    // available balance if `amount` is omitted. Requires exactly 1 yoctoⓃ attached.
+    //
+    // Returns the StorageBalance structure showing updated balances.
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance;
+
+    // Unregisters the predecessor and returns its whole storage deposit. The account
+    // must have no LP shares, positions, deposits, pending amounts or limit orders, `force` is not supported.
+    // Requires exactly 1 yoctoⓃ attached.
+    //
+    // Returns true if the account was registered.
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool;
+
+    /****************/
+    /* VIEW METHODS */
+    /****************/
+    // Returns minimum and maximum allowed balance amounts to interact with this
+    // contract. See StorageBalanceBounds.
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds;
+
+    // Returns the StorageBalance structure of the valid `account_id`
+    // provided. Must panic if `account_id` is invalid.
+    //
+    // If `account_id` is not registered, must return `null`.
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+}
+
+impl Contract {
+    /// Measures the bytes of the storage record of the longest account id.
+    pub(crate) fn measure_bytes_for_account_storage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        self.storage_accounts.insert(&tmp_account_id, &AccountStorage { deposit: 0, usage: 0 });
+        self.bytes_for_account_storage = env::storage_usage() - initial_storage_usage;
+        self.storage_accounts.remove(&tmp_account_id);
+    }
+
+    /// Measures the bytes of each kind of the records of the longest account id, the tokens of the longest id.
+    pub(crate) fn measure_bytes_for_records(&mut self) {
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        let tmp_id = u64::MAX;
+
+        self.bytes_for_token_record = measure_bytes_for_token_record(&mut self.deposits);
+
+        let initial_storage_usage = env::storage_usage();
+        let mut shares = LpShares::new(tmp_id);
+        shares.accounts.insert(&tmp_account_id, &Balance::MAX);
+        self.account_pools.insert(&tmp_account_id, &BTreeSet::from([tmp_id]));
+        self.bytes_for_pool_record = env::storage_usage() - initial_storage_usage;
+        shares.accounts.remove(&tmp_account_id);
+        self.account_pools.remove(&tmp_account_id);
+
+        let initial_storage_usage = env::storage_usage();
+        let mut positions = LookupMap::new(StorageKey::Positions { pool_id: tmp_id }.try_to_vec().unwrap());
+        let mut account_positions = LookupMap::new(StorageKey::AccountPositions { pool_id: tmp_id }.try_to_vec().unwrap());
+        positions.insert(
+            &tmp_id,
+            &Position {
+                owner_id: tmp_account_id.clone(),
+                tick_lower: 0,
+                tick_upper: 0,
+                liquidity: 0,
+                fee_growth_inside_last: [0; 2],
+                tokens_owed: [0; 2],
+            },
+        );
+        account_positions.insert(&tmp_account_id, &vec![tmp_id]);
+        self.bytes_for_position = env::storage_usage() - initial_storage_usage;
+        positions.remove(&tmp_id);
+        account_positions.remove(&tmp_account_id);
+
+        let initial_storage_usage = env::storage_usage();
+        let order = LimitOrder {
+            order_id: tmp_id,
+            account_id: tmp_account_id.clone(),
+            pool_id: tmp_id,
+            token_in: tmp_account_id.clone(),
+            amount_in: U128(Balance::MAX),
+            token_out: tmp_account_id.clone(),
+            min_price: U128(Balance::MAX),
+            withdraw_on_fill: false,
+        };
+        let key = (tmp_id, tmp_account_id.clone(), Balance::MAX, tmp_id);
+        self.orders.insert(&tmp_id, &order);
+        self.order_book.insert(&key, &());
+        self.account_orders.insert(&tmp_account_id, &BTreeSet::from([tmp_id]));
+        self.bytes_for_order = env::storage_usage() - initial_storage_usage;
+        self.orders.remove(&tmp_id);
+        self.order_book.remove(&key);
+        self.account_orders.remove(&tmp_account_id);
+    }
+
+    /// Returns the bytes of the records the account keeps in the AMM with the measured size of each record:
+    /// the tokens of its deposits and pending amounts, the pools it holds and its open limit orders.
+    /// The positions are reserved when they are opened. The writes shared by all the accounts, as the pools,
+    /// the oracles and the counters of the AMM, are not the records of any account.
+    pub(crate) fn internal_records_bytes(&self, account_id: &AccountId) -> StorageUsage {
+        let tokens = self.deposits.get(account_id).map_or(0, |deposits| deposits.len())
+            + self.pending.get(account_id).map_or(0, |pending| pending.len());
+        let pools = self.account_pools.get(account_id).map_or(0, |account_pools| account_pools.len());
+        let orders = self.account_orders.get(account_id).map_or(0, |account_orders| account_orders.len());
+        tokens as StorageUsage * self.bytes_for_token_record
+            + pools as StorageUsage * self.bytes_for_pool_record
+            + orders as StorageUsage * self.bytes_for_order
+    }
+
+    /// Adds `bytes` to the usage of the account for a record written later on its behalf, so writing it can't
+    /// fail on the storage deposit. Panics if the account is not registered or its deposit doesn't cover the bytes.
+    pub(crate) fn internal_reserve_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        let storage = self.storage_accounts.get(account_id);
+        require!(storage.is_some(), format!("The account {} is not registered", account_id));
+        let mut storage = storage.unwrap();
+        storage.usage += bytes;
+        let required = Balance::from(storage.usage) * env::storage_byte_cost();
+        require!(
+            storage.deposit >= required,
+            format!("Not enough storage deposit of {}: {} is less than {}", account_id, storage.deposit, required)
+        );
+        self.storage_accounts.insert(account_id, &storage);
+    }
+
+    /// Returns the bytes reserved with `internal_reserve_storage` to the available balance of the account.
+    pub(crate) fn internal_release_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        if let Some(mut storage) = self.storage_accounts.get(account_id) {
+            storage.usage = storage.usage.saturating_sub(bytes).max(self.bytes_for_account_storage);
+            self.storage_accounts.insert(account_id, &storage);
+        }
+    }
+
+    /// Charges the account for its records the call added since `initial_bytes` of `internal_records_bytes`,
+    /// the released records are returned to the available balance. Panics if the account is not registered
+    /// or its deposit doesn't cover the bytes. The records released by an unregistered account are not tracked.
+    pub(crate) fn internal_charge_storage(&mut self, account_id: &AccountId, initial_bytes: StorageUsage) {
+        let bytes = self.internal_records_bytes(account_id);
+        if !self.storage_accounts.contains_key(account_id) {
+            require!(bytes <= initial_bytes, format!("The account {} is not registered", account_id));
+            return;
+        }
+        if bytes > initial_bytes {
+            self.internal_reserve_storage(account_id, bytes - initial_bytes);
+        } else {
+            self.internal_release_storage(account_id, initial_bytes - bytes);
+        }
+    }
+
+    /// Tracks the records a callback added to the account since `initial_bytes`, as the refunds and the pending
+    /// amounts. The callback can't fail, so the bytes are added even if the deposit doesn't cover them, the
+    /// account can't withdraw its storage deposit until they are covered or released.
+    pub(crate) fn internal_track_storage(&mut self, account_id: &AccountId, initial_bytes: StorageUsage) {
+        let bytes = self.internal_records_bytes(account_id);
+        if let Some(mut storage) = self.storage_accounts.get(account_id) {
+            storage.usage = (storage.usage + bytes)
+                .saturating_sub(initial_bytes)
+                .max(self.bytes_for_account_storage);
+            self.storage_accounts.insert(account_id, &storage);
+        }
+    }
+
+    pub(crate) fn internal_assert_registered(&self, account_id: &AccountId) {
+        require!(self.storage_accounts.contains_key(account_id), format!("The account {} is not registered", account_id));
+    }
+
+    /// Returns true if the account holds no LP shares, positions, deposits, pending amounts or limit orders.
+    fn internal_has_no_records(&self, account_id: &AccountId) -> bool {
+        self.deposits.get(account_id).is_none()
+            && self.pending.get(account_id).is_none()
+            && self.account_orders.get(account_id).is_none()
+            && self.account_pools.get(account_id).is_none()
+            && (0..self.unindexed_pools).all(|pool_id| !self.internal_unwrap_pool(pool_id).is_held_by(account_id))
+    }
+}
+
+/// Measures the bytes of the deposit record of the longest account id holding one token of the longest id.
+pub(crate) fn measure_bytes_for_token_record(deposits: &mut LookupMap<AccountId, BTreeMap<AccountId, Balance>>) -> StorageUsage {
+    let initial_storage_usage = env::storage_usage();
+    let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+    deposits.insert(&tmp_account_id, &BTreeMap::from([(tmp_account_id.clone(), 0)]));
+    let bytes = env::storage_usage() - initial_storage_usage;
+    deposits.remove(&tmp_account_id);
+    bytes
+}
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount: Balance = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+
+        let storage = match self.storage_accounts.get(&account_id) {
+            Some(mut storage) => {
+                if registration_only {
+                    log!("The account is already registered, refunding the deposit");
+                    if amount > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(amount);
+                    }
+                } else {
+                    storage.deposit += amount;
+                    self.storage_accounts.insert(&account_id, &storage);
+                }
+                storage
+            }
+            None => {
+                let min_balance = self.storage_balance_bounds().min.0;
+                require!(amount >= min_balance, "The attached deposit is less than the minimum storage balance");
+
+                let deposit = if registration_only { min_balance } else { amount };
+                let refund = amount - deposit;
+                if refund > 0 {
+                    Promise::new(env::predecessor_account_id()).transfer(refund);
+                }
+
+                let storage = AccountStorage { deposit, usage: self.bytes_for_account_storage };
+                self.storage_accounts.insert(&account_id, &storage);
+                storage
+            }
+        };
+
+        log!("storage_deposit: account_id: {}, attached_deposit: {}", account_id, amount);
+        StorageBalance { total: storage.deposit.into(), available: storage.available().into() }
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut storage = self
+            .storage_accounts
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str(format!("The account {} is not registered", account_id).as_str()));
+
+        let available = storage.available();
+        let amount = amount.map_or(available, |amount| amount.0);
+        require!(amount <= available, format!("Not enough available storage balance: {} is less than {}", available, amount));
+
+        if amount > 0 {
+            storage.deposit -= amount;
+            self.storage_accounts.insert(&account_id, &storage);
+            Promise::new(account_id).transfer(amount);
+        }
+        StorageBalance { total: storage.deposit.into(), available: storage.available().into() }
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        require!(!force.unwrap_or(false), "Force unregister is not supported");
+
+        let account_id = env::predecessor_account_id();
+        let storage = match self.storage_accounts.get(&account_id) {
+            Some(storage) => storage,
+            None => return false,
+        };
+        require!(
+            self.internal_has_no_records(&account_id),
+            "The account still has LP shares, positions, deposits, pending amounts or limit orders"
+        );
+
+        self.storage_accounts.remove(&account_id);
+        if storage.deposit > 0 {
+            Promise::new(account_id.clone()).transfer(storage.deposit);
+        }
+        log!("storage_unregister: account_id: {}", account_id);
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        // The registration pays for its own record, the rest is charged by usage
+        StorageBalanceBounds {
+            min: (Balance::from(self.bytes_for_account_storage) * env::storage_byte_cost()).into(),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts
+            .get(&account_id)
+            .map(|storage| StorageBalance { total: storage.deposit.into(), available: storage.available().into() })
+    }
+}
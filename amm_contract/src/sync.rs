@@ -0,0 +1,239 @@
+//! Recovery of the accounting from the actual balances of the AMM in the token contracts.
+//!
+//! The AMM owes every token it holds to the reserves and the protocol fees of the pools, the deposits
+//! and the pending payouts, their running sum per token is kept in the totals. `sync` moves the difference between the balance and what is owed
+//! into the reserves of a pool, `skim` sends the surplus away.
+//!
+//! Both compare the balance with the totals, which only match while no transfer of the token is in flight. The
+//! transfers sent by the AMM are counted until their callbacks run, and a token is skipped if its total changes
+//! between the query of the balance and the callback. The tokens received with `ft_transfer_call` are in the
+//! balance before `ft_on_transfer` runs and the unused amounts stay there until the token contract refunds them,
+//! the AMM can't see those transfers, so the keeper has to call both while no such transfers are pending.
+
+use near_sdk::json_types::U128;
+use near_sdk::serde_json;
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance, Promise, PromiseResult};
+
+use crate::events::{ReservesSynced, TransferFailed};
+use crate::external::{ext_self, ext_token};
+use crate::pool::Pool;
+use crate::*;
+
+/// The most tokens `skim` sends in one call, the callback needs the gas of a transfer for each of them.
+pub const MAX_SKIM_TOKENS: usize = 8;
+
+impl Contract {
+    pub(crate) fn internal_add_total(&mut self, token_id: &AccountId, amount: Balance) {
+        let total = self.internal_totals.get(token_id).unwrap_or(0);
+        self.internal_totals.insert(token_id, &(total + amount));
+    }
+
+    pub(crate) fn internal_sub_total(&mut self, token_id: &AccountId, amount: Balance) {
+        let total = self.internal_totals.get(token_id).unwrap_or(0) - amount;
+        // An empty total releases its storage
+        if total == 0 {
+            self.internal_totals.remove(token_id);
+        } else {
+            self.internal_totals.insert(token_id, &total);
+        }
+    }
+
+    /// Moves the change of the reserves and the protocol fees of a pool written to the registry into the totals.
+    pub(crate) fn internal_update_totals(&mut self, old: Option<&Pool>, new: &Pool) {
+        let held = |pool: &Pool| -> Vec<Balance> {
+            pool.reserves().into_iter().zip(pool.protocol_fees()).map(|(reserve, fee)| reserve + fee).collect()
+        };
+        let new_held = held(new);
+        let old_held = old.map_or_else(|| vec![0; new_held.len()], held);
+        for ((token_id, old), new) in new.tokens().iter().zip(old_held).zip(new_held) {
+            if new > old {
+                self.internal_add_total(token_id, new - old);
+            } else if old > new {
+                self.internal_sub_total(token_id, old - new);
+            }
+        }
+    }
+
+    /// Sends `amount` of the token to the receiver with `ft_transfer`, the transfer is counted as in flight
+    /// until its callback calls `internal_end_transfer`.
+    pub(crate) fn internal_ft_transfer(&mut self, token_id: &AccountId, receiver_id: &AccountId, amount: Balance, memo: String) -> Promise {
+        let count = self.transfers_in_flight.get(token_id).unwrap_or(0);
+        self.transfers_in_flight.insert(token_id, &(count + 1));
+        ext_token::ext(token_id.clone())
+            .with_attached_deposit(1)
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(receiver_id.clone(), amount.into(), Some(memo))
+    }
+
+    /// Ends a transfer started by `internal_ft_transfer`, the callback calls it whatever the result of the transfer.
+    pub(crate) fn internal_end_transfer(&mut self, token_id: &AccountId) {
+        match self.transfers_in_flight.get(token_id).unwrap_or(0) {
+            0 | 1 => {
+                self.transfers_in_flight.remove(token_id);
+            }
+            count => {
+                self.transfers_in_flight.insert(token_id, &(count - 1));
+            }
+        }
+    }
+
+    fn internal_assert_no_transfer_in_flight(&self, token_id: &AccountId) {
+        require!(
+            !self.transfers_in_flight.contains_key(token_id),
+            format!("The transfers of {} are in flight, the balance doesn't match the accounting", token_id)
+        );
+    }
+
+    /// Returns true if the balance of the token queried along with `total` can still be compared with the accounting.
+    fn internal_is_balance_current(&self, token_id: &AccountId, total: Balance) -> bool {
+        !self.transfers_in_flight.contains_key(token_id) && self.internal_accounted_balance(token_id) == total
+    }
+
+    /// Returns the amount of the token the AMM owes: the reserves and the protocol fees of all the pools,
+    /// the deposits, the pending payouts and the open limit orders.
+    pub(crate) fn internal_accounted_balance(&self, token_id: &AccountId) -> Balance {
+        self.internal_totals.get(token_id).unwrap_or(0)
+    }
+}
+
+/// Returns the balance reported by `ft_balance_of` of the promise `index`, none if the call failed.
+fn balance_of_result(index: u64) -> Option<Balance> {
+    match env::promise_result(index) {
+        PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value).ok().map(|b| b.0),
+        _ => None,
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the reserves of the pool to the balances of the AMM in the token contracts less everything else
+    /// the AMM owes, requires the role `Keeper`. A surplus is added to the reserves, a deficit is taken from them.
+    /// Panics while a transfer of a token of the pool is in flight, the reserve of a token whose accounting
+    /// changes before the balance is received is kept.
+    pub fn sync(&mut self, pool_id: u64) -> Promise {
+        self.internal_assert_role(Role::Keeper);
+        self.internal_assert_no_flash_loan(pool_id);
+        let pool = self.internal_unwrap_pool(pool_id);
+        require!(
+            !matches!(pool, Pool::ConcentratedPool(_)),
+            "The reserves of a concentrated pool are defined by its positions"
+        );
+        let tokens = pool.tokens();
+        tokens.iter().for_each(|token_id| self.internal_assert_no_transfer_in_flight(token_id));
+        let totals = tokens.iter().map(|token_id| U128(self.internal_accounted_balance(token_id))).collect();
+
+        tokens
+            .into_iter()
+            .map(|token_id| ext_token::ext(token_id).ft_balance_of(env::current_account_id()))
+            .reduce(|balances, balance| balances.and(balance))
+            .unwrap()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_sync(pool_id, env::predecessor_account_id(), totals),
+            )
+    }
+
+    #[private]
+    pub fn on_sync(&mut self, pool_id: u64, account_id: AccountId, totals: Vec<U128>) {
+        let mut pool = self.internal_unwrap_pool(pool_id);
+        for (i, (token_id, total)) in pool.tokens().iter().zip(totals).enumerate() {
+            let balance = match balance_of_result(i as u64) {
+                Some(balance) => balance,
+                None => {
+                    log!("on_sync: the balance of {} is unknown, the reserve is kept", token_id);
+                    continue;
+                }
+            };
+            if !self.internal_is_balance_current(token_id, total.0) {
+                log!("on_sync: the accounting of {} has changed since the query, the reserve is kept", token_id);
+                continue;
+            }
+
+            let reserve = pool.reserves()[i];
+            let owed_elsewhere = total.0 - reserve;
+            let synced = balance.saturating_sub(owed_elsewhere);
+            log!("on_sync: pool {} reserve of {} {} -> {}", pool_id, token_id, reserve, synced);
+            pool.set_reserve(token_id, synced);
+        }
+        self.internal_save_pool(pool_id, &pool);
+        ReservesSynced {
+            pool_id,
+            account_id: &account_id,
+            reserves: &internal::reserves_of(&pool),
+        }
+        .emit();
+    }
+
+    /// Sends the balances of the AMM in the token contracts above everything the AMM owes to `receiver_id`,
+    /// for the given tokens (at most `MAX_SKIM_TOKENS`), requires the role `Keeper`.
+    /// Panics while a transfer of any of the tokens is in flight, a token whose accounting changes before
+    /// the balance is received is skipped.
+    pub fn skim(&mut self, token_ids: Vec<AccountId>, receiver_id: AccountId) -> Promise {
+        self.internal_assert_role(Role::Keeper);
+        require!(!token_ids.is_empty(), "The tokens to skim should be given");
+        require!(token_ids.len() <= MAX_SKIM_TOKENS, format!("At most {} tokens can be skimmed in one call", MAX_SKIM_TOKENS));
+        let mut token_ids = token_ids;
+        token_ids.sort();
+        token_ids.dedup();
+        for token_id in &token_ids {
+            require!(self.tokens.contains_key(token_id), format!("Unsupported token contract id: {}", token_id));
+            self.internal_assert_no_transfer_in_flight(token_id);
+        }
+        let totals: Vec<U128> = token_ids.iter().map(|token_id| U128(self.internal_accounted_balance(token_id))).collect();
+
+        token_ids
+            .iter()
+            .map(|token_id| ext_token::ext(token_id.clone()).ft_balance_of(env::current_account_id()))
+            .reduce(|balances, balance| balances.and(balance))
+            .unwrap()
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE + (GAS_FOR_FT_TRANSFER + GAS_FOR_RESOLVE) * token_ids.len() as u64)
+                    .on_skim(token_ids, totals, receiver_id),
+            )
+    }
+
+    #[private]
+    pub fn on_skim(&mut self, token_ids: Vec<AccountId>, totals: Vec<U128>, receiver_id: AccountId) {
+        for (i, (token_id, total)) in token_ids.into_iter().zip(totals).enumerate() {
+            let surplus = balance_of_result(i as u64).map_or(0, |balance| balance.saturating_sub(total.0));
+            if surplus == 0 {
+                continue;
+            }
+            if !self.internal_is_balance_current(&token_id, total.0) {
+                log!("on_skim: the accounting of {} has changed since the query, skipping it", token_id);
+                continue;
+            }
+
+            log!("on_skim: sending the surplus of {} {} to {}", surplus, token_id, receiver_id);
+            self.internal_ft_transfer(&token_id, &receiver_id, surplus, "AMM skim".to_string()).then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_skim_sent(token_id, receiver_id.clone(), surplus.into()),
+            );
+        }
+    }
+
+    /// Ends the transfer of the surplus, the surplus whose transfer failed stays in the AMM.
+    #[private]
+    pub fn on_skim_sent(&mut self, token_id: AccountId, receiver_id: AccountId, amount: U128) {
+        self.internal_end_transfer(&token_id);
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!("on_skim_sent: the transfer of {} {} to {} failed", amount.0, token_id, receiver_id);
+            TransferFailed {
+                action: "skim",
+                account_id: &receiver_id,
+                token_id: &token_id,
+                amount: &amount,
+                memo: Some("the surplus stays in the AMM"),
+            }
+            .emit();
+        }
+    }
+
+    // Returns the number of the transfers of the token sent by the AMM whose callbacks haven't run yet
+    pub fn get_transfers_in_flight(&self, token_id: AccountId) -> u32 {
+        self.transfers_in_flight.get(&token_id).unwrap_or(0)
+    }
+}
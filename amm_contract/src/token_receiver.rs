@@ -0,0 +1,161 @@
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::Deserialize;
+use near_sdk::{env, ext_contract, log, near_bindgen, AccountId, Balance, PromiseOrValue, PromiseResult};
+
+use crate::external::ext_self;
+use crate::internal::is_deadline_passed;
+use crate::router::SwapHop;
+use crate::*;
+
+/// Message passed by the user in the `msg` argument of `ft_transfer_call`.
+#[derive(Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum TokenReceiverMessage {
+    /// Exchanges the transferred tokens to the opposite token of the pool `pool_id`,
+    /// `token_out` is required for the pools of more than two tokens.
+    /// The whole amount is returned if the swap pays out less than `min_amount_out`
+    /// or the block timestamp is beyond `deadline` (in nanoseconds).
+    Swap {
+        pool_id: u64,
+        token_out: Option<AccountId>,
+        min_amount_out: U128,
+        deadline: Option<U64>,
+    },
+    /// Exchanges the transferred tokens through the pools of the `hops`, the first hop starts with the transferred token.
+    /// The whole amount is returned if the last hop pays out less than `min_amount_out`
+    /// or the block timestamp is beyond `deadline` (in nanoseconds).
+    SwapRoute {
+        hops: Vec<SwapHop>,
+        min_amount_out: U128,
+        deadline: Option<U64>,
+    },
+    /// Adds the transferred tokens to the internal balance of the sender.
+    Deposit,
+    /// Repays the flash loan `loan_id` of the pool `pool_id`, the amount above the amount and the fee is returned.
+    RepayFlashLoan { pool_id: u64, loan_id: u64 },
+}
+
+#[ext_contract(ext_ft_receiver)]
+pub trait FungibleTokenReceiver {
+    /// Called by fungible token contract after `ft_transfer_call` was initiated by
+    /// `sender_id` of the given `amount` with the transfer message given in `msg` field.
+    /// The `amount` of tokens were already transferred to this contract account and ready to be used.
+    ///
+    /// The method must return the amount of tokens that are *not* used/accepted by this contract from the transferred
+    /// amount.
+    ///
+    /// Arguments:
+    /// - `sender_id` - the account ID that initiated the transfer.
+    /// - `amount` - the amount of tokens that were transferred to this account in a decimal string representation.
+    /// - `msg` - a string message that was passed with this transfer call.
+    ///
+    /// Returns the amount of unused tokens that should be returned to sender, in a decimal string representation.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        // The token contract is the predecessor of the call
+        let token_in = env::predecessor_account_id();
+        require!(amount.0 > 0, "The amount should be a positive number");
+
+        let message = near_sdk::serde_json::from_str::<TokenReceiverMessage>(&msg)
+            .unwrap_or_else(|_| env::panic_str(format!("ft_on_transfer: invalid message: {}", msg).as_str()));
+
+        match message {
+            TokenReceiverMessage::Swap { pool_id, token_out, min_amount_out, deadline } => {
+                let pool = self.internal_unwrap_pool(pool_id);
+                require!(pool.contains(&token_in), format!("Unsupported token contract id: {}", token_in));
+                let token_out = token_out.unwrap_or_else(|| pool.opposite_token(&token_in));
+                // The pool stays empty until then, since its tokens can't be deposited
+                self.internal_assert_metadata_known(&pool);
+
+                if is_deadline_passed(deadline) {
+                    log!("ft_on_transfer: the deadline has passed, returning {}", amount.0);
+                    return PromiseOrValue::Value(amount);
+                }
+
+                // Nothing to pay out or the price is worse than the user accepts, return the whole amount back
+                let amount_out = self.internal_get_return(pool_id, &token_in, amount.0, &token_out);
+                if amount_out == 0 || amount_out < min_amount_out.0 {
+                    log!("ft_on_transfer: amount out {} is less than min amount out {}, returning {}", amount_out, min_amount_out.0, amount.0);
+                    return PromiseOrValue::Value(amount);
+                }
+
+                let (amount_out, _) = self.internal_swap(pool_id, &sender_id, &token_in, amount.0, &token_out);
+                self.internal_pay_out_swap(&sender_id, &token_out, amount_out)
+            }
+            TokenReceiverMessage::SwapRoute { hops, min_amount_out, deadline } => {
+                if is_deadline_passed(deadline) {
+                    log!("ft_on_transfer: the deadline has passed, returning {}", amount.0);
+                    return PromiseOrValue::Value(amount);
+                }
+
+                // The hops are already applied, so the whole route is rolled back by the panic and the token
+                // contract refunds the whole amount
+                let results = self.internal_swap_route(&sender_id, &token_in, amount.0, &hops);
+                let last = results.last().unwrap();
+                require!(
+                    last.amount_out.0 >= min_amount_out.0,
+                    format!("ft_on_transfer: amount out {} is less than min amount out {}", last.amount_out.0, min_amount_out.0)
+                );
+                self.internal_pay_out_swap(&sender_id, &last.token_out, last.amount_out.0)
+            }
+            TokenReceiverMessage::Deposit => {
+                self.internal_assert_not_paused();
+                require!(self.tokens.contains_key(&token_in), format!("Unsupported token contract id: {}", token_in));
+                let initial_bytes = self.internal_records_bytes(&sender_id);
+                self.internal_deposit(&sender_id, &token_in, amount.0);
+                self.internal_charge_storage(&sender_id, initial_bytes);
+                log!("ft_on_transfer: {} deposited {} {}", sender_id, amount.0, token_in);
+                PromiseOrValue::Value(U128(0))
+            }
+            TokenReceiverMessage::RepayFlashLoan { pool_id, loan_id } => {
+                let unused = self.internal_repay_flash_loan(pool_id, loan_id, &token_in, amount.0);
+                log!("ft_on_transfer: {} repaid {} {} of flash loan {}", sender_id, amount.0 - unused, token_in, loan_id);
+                PromiseOrValue::Value(U128(unused))
+            }
+        }
+    }
+}
+
+impl Contract {
+    /// Sends the output of a swap started in `ft_on_transfer` to the sender. The swap is final, so a payout
+    /// that fails is kept as pending for the sender rather than rolled back against reserves that may have moved.
+    fn internal_pay_out_swap(&mut self, sender_id: &AccountId, token_out: &AccountId, amount_out: Balance) -> PromiseOrValue<U128> {
+        self.internal_ft_transfer(token_out, sender_id, amount_out, "AMM swap".to_string())
+            .then(
+                ext_self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_RESOLVE)
+                    .on_swap_payout(sender_id.clone(), token_out.clone(), amount_out.into()),
+            )
+            .into()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Resolves the payout of a swap started in `ft_on_transfer`, the payout that failed is recorded as pending.
+    /// Returns zero, the whole input is used by the swap.
+    #[private]
+    pub fn on_swap_payout(&mut self, sender_id: AccountId, token_id: AccountId, amount: U128) -> U128 {
+        self.internal_end_transfer(&token_id);
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!("on_swap_payout: the payout of {} {} to {} failed", amount.0, token_id, sender_id);
+            self.internal_add_pending("swap", &sender_id, &token_id, amount.0);
+        }
+        U128(0)
+    }
+}
@@ -0,0 +1,644 @@
+//! Upgrades of the contract code and migrations of its state.
+//!
+//! The state is a plain Borsh struct, so its layout is recorded separately as a version under
+//! [`STATE_VERSION_KEY`]. The first deployment didn't write it, a missing version is the layout [`ContractV0`].
+//! A new layout adds a variant to [`VersionedState`], keeps the previous struct to read it and a step that
+//! converts it into the next layout.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use near_sdk::collections::{LookupMap, LookupSet, TreeMap, Vector};
+use near_sdk::json_types::Base64VecU8;
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance, Gas, Promise, StorageUsage};
+
+use crate::limit_orders::{LimitOrder, OrderKey};
+use crate::metadata::FungibleTokenMetadata;
+use crate::oracle::Oracle;
+use crate::pool::Pool;
+use crate::simple_pool::SimplePool;
+use crate::storage::AccountStorage;
+use crate::*;
+
+/// Storage key of the version of the state layout
+pub const STATE_VERSION_KEY: &[u8] = b"v";
+/// Version of the layout of [`Contract`]
+pub const CURRENT_STATE_VERSION: u32 = 9;
+
+/// Gas for the call of `migrate` on the deployed code
+const GAS_FOR_MIGRATE: Gas = Gas(100_000_000_000_000);
+
+/// The layout of the first deployment: a single pair that kept the balances of the AMM
+/// in the metadata of the tokens.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV0 {
+    pub owner_id: AccountId,
+    pub token_a: AccountId,
+    pub token_a_meta: FungibleTokenMetadata,
+    pub token_b: AccountId,
+    pub token_b_meta: FungibleTokenMetadata,
+    pub tokens_ratio: u128,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+}
+
+/// The layout of the pools, the roles and the pause.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV1 {
+    pub owner_id: AccountId,
+    pub proposed_owner_id: Option<AccountId>,
+    pub roles: LookupMap<AccountId, BTreeSet<Role>>,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    pub pools: Vector<Pool>,
+    pub oracles: LookupMap<u64, Oracle>,
+    pub deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub internal_totals: LookupMap<AccountId, Balance>,
+    pub paused: bool,
+    pub paused_pools: LookupSet<u64>,
+    pub circuit_breakers: LookupMap<u64, u32>,
+}
+
+/// The layout that added the storage of the accounts.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV2 {
+    pub owner_id: AccountId,
+    pub proposed_owner_id: Option<AccountId>,
+    pub roles: LookupMap<AccountId, BTreeSet<Role>>,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    pub pools: Vector<Pool>,
+    pub oracles: LookupMap<u64, Oracle>,
+    pub deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub internal_totals: LookupMap<AccountId, Balance>,
+    pub paused: bool,
+    pub paused_pools: LookupSet<u64>,
+    pub circuit_breakers: LookupMap<u64, u32>,
+    pub storage_accounts: LookupMap<AccountId, AccountStorage>,
+    pub bytes_for_account_storage: StorageUsage,
+}
+
+/// The layout that added the timestamps of the metadata of the tokens.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV3 {
+    pub owner_id: AccountId,
+    pub proposed_owner_id: Option<AccountId>,
+    pub roles: LookupMap<AccountId, BTreeSet<Role>>,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    pub tokens_refreshed_at: LookupMap<AccountId, u64>,
+    pub pools: Vector<Pool>,
+    pub oracles: LookupMap<u64, Oracle>,
+    pub deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub internal_totals: LookupMap<AccountId, Balance>,
+    pub paused: bool,
+    pub paused_pools: LookupSet<u64>,
+    pub circuit_breakers: LookupMap<u64, u32>,
+    pub storage_accounts: LookupMap<AccountId, AccountStorage>,
+    pub bytes_for_account_storage: StorageUsage,
+}
+
+/// The layout that added the flash loans, locked by the id of the pool.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV4 {
+    pub owner_id: AccountId,
+    pub proposed_owner_id: Option<AccountId>,
+    pub roles: LookupMap<AccountId, BTreeSet<Role>>,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    pub tokens_refreshed_at: LookupMap<AccountId, u64>,
+    pub pools: Vector<Pool>,
+    pub oracles: LookupMap<u64, Oracle>,
+    pub deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub internal_totals: LookupMap<AccountId, Balance>,
+    pub paused: bool,
+    pub paused_pools: LookupSet<u64>,
+    pub circuit_breakers: LookupMap<u64, u32>,
+    pub storage_accounts: LookupMap<AccountId, AccountStorage>,
+    pub bytes_for_account_storage: StorageUsage,
+    pub flash_loans: LookupSet<u64>,
+}
+
+/// Key of an order in the book of its pool in [`ContractV5`]: the token to sell, the limit price and the id.
+pub type OrderKeyV5 = (AccountId, Balance, u64);
+
+/// The layout that added the limit orders, kept in a book per pool.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV5 {
+    pub owner_id: AccountId,
+    pub proposed_owner_id: Option<AccountId>,
+    pub roles: LookupMap<AccountId, BTreeSet<Role>>,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    pub tokens_refreshed_at: LookupMap<AccountId, u64>,
+    pub pools: Vector<Pool>,
+    pub oracles: LookupMap<u64, Oracle>,
+    pub deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub internal_totals: LookupMap<AccountId, Balance>,
+    pub paused: bool,
+    pub paused_pools: LookupSet<u64>,
+    pub circuit_breakers: LookupMap<u64, u32>,
+    pub storage_accounts: LookupMap<AccountId, AccountStorage>,
+    pub bytes_for_account_storage: StorageUsage,
+    pub flash_loans: LookupSet<u64>,
+    pub orders: LookupMap<u64, LimitOrder>,
+    pub order_book: LookupMap<u64, BTreeSet<OrderKeyV5>>,
+    pub account_orders: LookupMap<AccountId, BTreeSet<u64>>,
+    pub next_order_id: u64,
+}
+
+/// The repayment of a flash loan in flight up to [`ContractV8`], the loans had no collateral.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OpenLoanV8 {
+    pub loan_id: u64,
+    pub token_id: AccountId,
+    pub owed: Balance,
+    pub repaid: Balance,
+}
+
+/// The layout that added the index of the LP holders, the flash loans by id, the order book sorted
+/// across the pools and the transfers in flight. The storage was charged by the usage of the whole call.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV6 {
+    pub owner_id: AccountId,
+    pub proposed_owner_id: Option<AccountId>,
+    pub roles: LookupMap<AccountId, BTreeSet<Role>>,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    pub tokens_refreshed_at: LookupMap<AccountId, u64>,
+    pub pools: Vector<Pool>,
+    pub oracles: LookupMap<u64, Oracle>,
+    pub deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub internal_totals: LookupMap<AccountId, Balance>,
+    pub paused: bool,
+    pub paused_pools: LookupSet<u64>,
+    pub circuit_breakers: LookupMap<u64, u32>,
+    pub storage_accounts: LookupMap<AccountId, AccountStorage>,
+    pub bytes_for_account_storage: StorageUsage,
+    pub account_pools: LookupMap<AccountId, BTreeSet<u64>>,
+    pub flash_loans: LookupMap<u64, OpenLoanV8>,
+    pub next_loan_id: u64,
+    pub orders: LookupMap<u64, LimitOrder>,
+    pub order_book: TreeMap<OrderKey, ()>,
+    pub account_orders: LookupMap<AccountId, BTreeSet<u64>>,
+    pub next_order_id: u64,
+    pub bytes_for_order_output: StorageUsage,
+    pub transfers_in_flight: LookupMap<AccountId, u32>,
+    pub unindexed_pools: u64,
+}
+
+/// The layout that added the measured sizes of the records of the accounts.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV7 {
+    pub owner_id: AccountId,
+    pub proposed_owner_id: Option<AccountId>,
+    pub roles: LookupMap<AccountId, BTreeSet<Role>>,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    pub tokens_refreshed_at: LookupMap<AccountId, u64>,
+    pub pools: Vector<Pool>,
+    pub oracles: LookupMap<u64, Oracle>,
+    pub deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub internal_totals: LookupMap<AccountId, Balance>,
+    pub paused: bool,
+    pub paused_pools: LookupSet<u64>,
+    pub circuit_breakers: LookupMap<u64, u32>,
+    pub storage_accounts: LookupMap<AccountId, AccountStorage>,
+    pub bytes_for_account_storage: StorageUsage,
+    pub account_pools: LookupMap<AccountId, BTreeSet<u64>>,
+    pub flash_loans: LookupMap<u64, OpenLoanV8>,
+    pub next_loan_id: u64,
+    pub orders: LookupMap<u64, LimitOrder>,
+    pub order_book: TreeMap<OrderKey, ()>,
+    pub account_orders: LookupMap<AccountId, BTreeSet<u64>>,
+    pub next_order_id: u64,
+    pub bytes_for_token_record: StorageUsage,
+    pub transfers_in_flight: LookupMap<AccountId, u32>,
+    pub unindexed_pools: u64,
+    pub bytes_for_pool_record: StorageUsage,
+    pub bytes_for_position: StorageUsage,
+    pub bytes_for_order: StorageUsage,
+}
+
+/// The layout that added the index of the tokens of the pools.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV8 {
+    pub owner_id: AccountId,
+    pub proposed_owner_id: Option<AccountId>,
+    pub roles: LookupMap<AccountId, BTreeSet<Role>>,
+    pub tokens: LookupMap<AccountId, FungibleTokenMetadata>,
+    pub tokens_refreshed_at: LookupMap<AccountId, u64>,
+    pub pools: Vector<Pool>,
+    pub oracles: LookupMap<u64, Oracle>,
+    pub deposits: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub pending: LookupMap<AccountId, BTreeMap<AccountId, Balance>>,
+    pub internal_totals: LookupMap<AccountId, Balance>,
+    pub paused: bool,
+    pub paused_pools: LookupSet<u64>,
+    pub circuit_breakers: LookupMap<u64, u32>,
+    pub storage_accounts: LookupMap<AccountId, AccountStorage>,
+    pub bytes_for_account_storage: StorageUsage,
+    pub account_pools: LookupMap<AccountId, BTreeSet<u64>>,
+    pub flash_loans: LookupMap<u64, OpenLoanV8>,
+    pub next_loan_id: u64,
+    pub orders: LookupMap<u64, LimitOrder>,
+    pub order_book: TreeMap<OrderKey, ()>,
+    pub account_orders: LookupMap<AccountId, BTreeSet<u64>>,
+    pub next_order_id: u64,
+    pub bytes_for_token_record: StorageUsage,
+    pub transfers_in_flight: LookupMap<AccountId, u32>,
+    pub unindexed_pools: u64,
+    pub bytes_for_pool_record: StorageUsage,
+    pub bytes_for_position: StorageUsage,
+    pub bytes_for_order: StorageUsage,
+    pub pool_tokens: LookupSet<AccountId>,
+}
+
+/// All the layouts of the state the contract has been deployed with.
+pub enum VersionedState {
+    V0(ContractV0),
+    V1(ContractV1),
+    V2(ContractV2),
+    V3(ContractV3),
+    V4(ContractV4),
+    V5(ContractV5),
+    V6(ContractV6),
+    V7(ContractV7),
+    V8(ContractV8),
+    V9(Contract),
+}
+
+impl VersionedState {
+    /// Reads the state in the layout of its recorded version.
+    pub fn read() -> Self {
+        let version = read_state_version();
+        match version {
+            0 => VersionedState::V0(read_state()),
+            1 => VersionedState::V1(read_state()),
+            2 => VersionedState::V2(read_state()),
+            3 => VersionedState::V3(read_state()),
+            4 => VersionedState::V4(read_state()),
+            5 => VersionedState::V5(read_state()),
+            6 => VersionedState::V6(read_state()),
+            7 => VersionedState::V7(read_state()),
+            8 => VersionedState::V8(read_state()),
+            9 => VersionedState::V9(read_state()),
+            _ => env::panic_str(format!("Unknown state version {}", version).as_str()),
+        }
+    }
+
+    /// Converts the state into the current layout, one version at a time. The sizes of the records are
+    /// measured again by the current code, as the records may have changed with the layout.
+    pub fn migrate(self) -> Contract {
+        let mut state = self;
+        let mut contract = loop {
+            state = match state {
+                VersionedState::V0(old) => break migrate_v0(old),
+                VersionedState::V1(old) => VersionedState::V2(migrate_v1(old)),
+                VersionedState::V2(old) => VersionedState::V3(migrate_v2(old)),
+                VersionedState::V3(old) => VersionedState::V4(migrate_v3(old)),
+                VersionedState::V4(old) => VersionedState::V5(migrate_v4(old)),
+                VersionedState::V5(old) => VersionedState::V6(migrate_v5(old)),
+                VersionedState::V6(old) => VersionedState::V7(migrate_v6(old)),
+                VersionedState::V7(old) => VersionedState::V8(migrate_v7(old)),
+                VersionedState::V8(old) => VersionedState::V9(migrate_v8(old)),
+                VersionedState::V9(contract) => break contract,
+            }
+        };
+        contract.measure_bytes_for_account_storage();
+        contract.measure_bytes_for_records();
+        contract
+    }
+}
+
+fn read_state<T: BorshDeserialize>() -> T {
+    env::state_read().unwrap_or_else(|| env::panic_str("The contract is not initialized"))
+}
+
+/// Returns the recorded version of the state layout, a missing version is the first deployment.
+pub(crate) fn read_state_version() -> u32 {
+    env::storage_read(STATE_VERSION_KEY)
+        .map_or(0, |version| u32::try_from_slice(&version).unwrap_or_else(|_| env::panic_str("Invalid state version")))
+}
+
+/// Records that the state is in the current layout.
+pub(crate) fn write_state_version() {
+    env::storage_write(STATE_VERSION_KEY, &CURRENT_STATE_VERSION.try_to_vec().unwrap());
+}
+
+/// The pair becomes the main pool. Its reserves start empty: the old balances were not tracked in base units,
+/// the tokens held by the AMM are a surplus that can be skimmed and added back as liquidity.
+/// The first deployment has no records for the later steps, so it is converted straight into the current layout.
+fn migrate_v0(old: ContractV0) -> Contract {
+    let mut this = Contract::internal_new(old.owner_id);
+    this.tokens = old.tokens;
+
+    // The metadata resolved by the old code is kept, the rest is queried again by `internal_add_pool`
+    for (token_id, metadata) in [(&old.token_a, &old.token_a_meta), (&old.token_b, &old.token_b_meta)] {
+        if !this.tokens.contains_key(token_id) && !metadata.spec.is_empty() {
+            this.tokens.insert(token_id, metadata);
+        }
+    }
+
+    let pool = SimplePool::new(MAIN_POOL_ID, old.token_a, old.token_b, fees::DEFAULT_FEE_BPS);
+    this.internal_add_pool(Pool::SimplePool(pool));
+    this
+}
+
+/// The accounts that already hold records are not registered, they are charged for the storage
+/// once they call `storage_deposit`. Until then they can only release storage.
+fn migrate_v1(old: ContractV1) -> ContractV2 {
+    ContractV2 {
+        owner_id: old.owner_id,
+        proposed_owner_id: old.proposed_owner_id,
+        roles: old.roles,
+        tokens: old.tokens,
+        pools: old.pools,
+        oracles: old.oracles,
+        deposits: old.deposits,
+        pending: old.pending,
+        internal_totals: old.internal_totals,
+        paused: old.paused,
+        paused_pools: old.paused_pools,
+        circuit_breakers: old.circuit_breakers,
+        storage_accounts: LookupMap::new(StorageKey::StorageAccounts.try_to_vec().unwrap()),
+        bytes_for_account_storage: 0,
+    }
+}
+
+/// The metadata resolved before keeps no timestamp until it is refreshed.
+fn migrate_v2(old: ContractV2) -> ContractV3 {
+    ContractV3 {
+        owner_id: old.owner_id,
+        proposed_owner_id: old.proposed_owner_id,
+        roles: old.roles,
+        tokens: old.tokens,
+        tokens_refreshed_at: LookupMap::new(StorageKey::TokensRefreshedAt.try_to_vec().unwrap()),
+        pools: old.pools,
+        oracles: old.oracles,
+        deposits: old.deposits,
+        pending: old.pending,
+        internal_totals: old.internal_totals,
+        paused: old.paused,
+        paused_pools: old.paused_pools,
+        circuit_breakers: old.circuit_breakers,
+        storage_accounts: old.storage_accounts,
+        bytes_for_account_storage: old.bytes_for_account_storage,
+    }
+}
+
+/// No pool has a flash loan in flight.
+fn migrate_v3(old: ContractV3) -> ContractV4 {
+    ContractV4 {
+        owner_id: old.owner_id,
+        proposed_owner_id: old.proposed_owner_id,
+        roles: old.roles,
+        tokens: old.tokens,
+        tokens_refreshed_at: old.tokens_refreshed_at,
+        pools: old.pools,
+        oracles: old.oracles,
+        deposits: old.deposits,
+        pending: old.pending,
+        internal_totals: old.internal_totals,
+        paused: old.paused,
+        paused_pools: old.paused_pools,
+        circuit_breakers: old.circuit_breakers,
+        storage_accounts: old.storage_accounts,
+        bytes_for_account_storage: old.bytes_for_account_storage,
+        flash_loans: LookupSet::new(StorageKey::FlashLoans.try_to_vec().unwrap()),
+    }
+}
+
+/// The books of the limit orders start empty.
+fn migrate_v4(old: ContractV4) -> ContractV5 {
+    ContractV5 {
+        owner_id: old.owner_id,
+        proposed_owner_id: old.proposed_owner_id,
+        roles: old.roles,
+        tokens: old.tokens,
+        tokens_refreshed_at: old.tokens_refreshed_at,
+        pools: old.pools,
+        oracles: old.oracles,
+        deposits: old.deposits,
+        pending: old.pending,
+        internal_totals: old.internal_totals,
+        paused: old.paused,
+        paused_pools: old.paused_pools,
+        circuit_breakers: old.circuit_breakers,
+        storage_accounts: old.storage_accounts,
+        bytes_for_account_storage: old.bytes_for_account_storage,
+        flash_loans: old.flash_loans,
+        orders: LookupMap::new(StorageKey::Orders.try_to_vec().unwrap()),
+        order_book: LookupMap::new(StorageKey::OrderBook.try_to_vec().unwrap()),
+        account_orders: LookupMap::new(StorageKey::AccountOrders.try_to_vec().unwrap()),
+        next_order_id: 0,
+    }
+}
+
+/// The flash loans are keyed by their id and the books of the pools are merged into one sorted map,
+/// so the migration requires that no pool has a loan in flight. The owed totals start to include the reserves
+/// and the protocol fees of the pools, and the orders that add their output to the deposit reserve its record.
+/// The holders of the existing pools are not indexed in `account_pools`, they are read from those pools.
+/// No transfer is counted in flight, the callbacks of the transfers sent by the old code don't go below zero.
+fn migrate_v5(old: ContractV5) -> ContractV6 {
+    let pools_count = old.pools.len();
+    require!(
+        (0..pools_count).all(|pool_id| !old.flash_loans.contains(&pool_id)),
+        "The flash loans in flight should be resolved before the migration"
+    );
+
+    let mut this = ContractV6 {
+        owner_id: old.owner_id,
+        proposed_owner_id: old.proposed_owner_id,
+        roles: old.roles,
+        tokens: old.tokens,
+        tokens_refreshed_at: old.tokens_refreshed_at,
+        pools: old.pools,
+        oracles: old.oracles,
+        deposits: old.deposits,
+        pending: old.pending,
+        internal_totals: old.internal_totals,
+        paused: old.paused,
+        paused_pools: old.paused_pools,
+        circuit_breakers: old.circuit_breakers,
+        storage_accounts: old.storage_accounts,
+        bytes_for_account_storage: old.bytes_for_account_storage,
+        account_pools: LookupMap::new(StorageKey::AccountPools.try_to_vec().unwrap()),
+        flash_loans: LookupMap::new(StorageKey::FlashLoans.try_to_vec().unwrap()),
+        next_loan_id: 0,
+        orders: old.orders,
+        order_book: TreeMap::new(StorageKey::OrderBook.try_to_vec().unwrap()),
+        account_orders: old.account_orders,
+        next_order_id: old.next_order_id,
+        bytes_for_order_output: 0,
+        transfers_in_flight: LookupMap::new(StorageKey::TransfersInFlight.try_to_vec().unwrap()),
+        unindexed_pools: pools_count,
+    };
+    this.bytes_for_order_output = storage::measure_bytes_for_token_record(&mut this.deposits);
+
+    let mut old_order_book = old.order_book;
+    for pool_id in 0..pools_count {
+        let pool = this.pools.get(pool_id).unwrap();
+        for ((token_id, reserve), protocol_fee) in pool.tokens().iter().zip(pool.reserves()).zip(pool.protocol_fees()) {
+            let total = this.internal_totals.get(token_id).unwrap_or(0);
+            this.internal_totals.insert(token_id, &(total + reserve + protocol_fee));
+        }
+
+        // The old keys are the id of the pool, shorter than the keys of the tree under the same prefix
+        for (token_in, min_price, order_id) in old_order_book.remove(&pool_id).unwrap_or_default() {
+            this.order_book.insert(&(pool_id, token_in, min_price, order_id), &());
+            let order = this.orders.get(&order_id).unwrap();
+            if !order.withdraw_on_fill {
+                if let Some(mut storage) = this.storage_accounts.get(&order.account_id) {
+                    storage.usage += this.bytes_for_order_output;
+                    this.storage_accounts.insert(&order.account_id, &storage);
+                }
+            }
+        }
+    }
+    this
+}
+
+/// The usage recorded by the old code is kept: it also counted the shared writes of the calls, so it is
+/// released with the records down to the storage record of the account.
+fn migrate_v6(old: ContractV6) -> ContractV7 {
+    ContractV7 {
+        owner_id: old.owner_id,
+        proposed_owner_id: old.proposed_owner_id,
+        roles: old.roles,
+        tokens: old.tokens,
+        tokens_refreshed_at: old.tokens_refreshed_at,
+        pools: old.pools,
+        oracles: old.oracles,
+        deposits: old.deposits,
+        pending: old.pending,
+        internal_totals: old.internal_totals,
+        paused: old.paused,
+        paused_pools: old.paused_pools,
+        circuit_breakers: old.circuit_breakers,
+        storage_accounts: old.storage_accounts,
+        bytes_for_account_storage: old.bytes_for_account_storage,
+        account_pools: old.account_pools,
+        flash_loans: old.flash_loans,
+        next_loan_id: old.next_loan_id,
+        orders: old.orders,
+        order_book: old.order_book,
+        account_orders: old.account_orders,
+        next_order_id: old.next_order_id,
+        bytes_for_token_record: old.bytes_for_order_output,
+        transfers_in_flight: old.transfers_in_flight,
+        unindexed_pools: old.unindexed_pools,
+        bytes_for_pool_record: 0,
+        bytes_for_position: 0,
+        bytes_for_order: 0,
+    }
+}
+
+/// The tokens of the existing pools are indexed.
+fn migrate_v7(old: ContractV7) -> ContractV8 {
+    let mut pool_tokens = LookupSet::new(StorageKey::PoolTokens.try_to_vec().unwrap());
+    for pool in old.pools.iter() {
+        for token_id in pool.tokens() {
+            pool_tokens.insert(&token_id);
+        }
+    }
+
+    ContractV8 {
+        owner_id: old.owner_id,
+        proposed_owner_id: old.proposed_owner_id,
+        roles: old.roles,
+        tokens: old.tokens,
+        tokens_refreshed_at: old.tokens_refreshed_at,
+        pools: old.pools,
+        oracles: old.oracles,
+        deposits: old.deposits,
+        pending: old.pending,
+        internal_totals: old.internal_totals,
+        paused: old.paused,
+        paused_pools: old.paused_pools,
+        circuit_breakers: old.circuit_breakers,
+        storage_accounts: old.storage_accounts,
+        bytes_for_account_storage: old.bytes_for_account_storage,
+        account_pools: old.account_pools,
+        flash_loans: old.flash_loans,
+        next_loan_id: old.next_loan_id,
+        orders: old.orders,
+        order_book: old.order_book,
+        account_orders: old.account_orders,
+        next_order_id: old.next_order_id,
+        bytes_for_token_record: old.bytes_for_token_record,
+        transfers_in_flight: old.transfers_in_flight,
+        unindexed_pools: old.unindexed_pools,
+        bytes_for_pool_record: old.bytes_for_pool_record,
+        bytes_for_position: old.bytes_for_position,
+        bytes_for_order: old.bytes_for_order,
+        pool_tokens,
+    }
+}
+
+/// The flash loans escrow a collateral, so the migration requires that no pool has a loan in flight.
+fn migrate_v8(old: ContractV8) -> Contract {
+    require!(
+        (0..old.pools.len()).all(|pool_id| !old.flash_loans.contains_key(&pool_id)),
+        "The flash loans in flight should be resolved before the migration"
+    );
+
+    Contract {
+        owner_id: old.owner_id,
+        proposed_owner_id: old.proposed_owner_id,
+        roles: old.roles,
+        tokens: old.tokens,
+        tokens_refreshed_at: old.tokens_refreshed_at,
+        pools: old.pools,
+        oracles: old.oracles,
+        deposits: old.deposits,
+        pending: old.pending,
+        internal_totals: old.internal_totals,
+        paused: old.paused,
+        paused_pools: old.paused_pools,
+        circuit_breakers: old.circuit_breakers,
+        storage_accounts: old.storage_accounts,
+        bytes_for_account_storage: old.bytes_for_account_storage,
+        account_pools: old.account_pools,
+        flash_loans: LookupMap::new(StorageKey::FlashLoans.try_to_vec().unwrap()),
+        next_loan_id: old.next_loan_id,
+        orders: old.orders,
+        order_book: old.order_book,
+        account_orders: old.account_orders,
+        next_order_id: old.next_order_id,
+        bytes_for_token_record: old.bytes_for_token_record,
+        transfers_in_flight: old.transfers_in_flight,
+        unindexed_pools: old.unindexed_pools,
+        bytes_for_pool_record: old.bytes_for_pool_record,
+        bytes_for_position: old.bytes_for_position,
+        bytes_for_order: old.bytes_for_order,
+        pool_tokens: old.pool_tokens,
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Deploys `code` to the contract account and calls `migrate` of the new code in the same batch,
+    /// the deployment is reverted if the migration fails. Only the owner can call it.
+    pub fn upgrade(&self, code: Base64VecU8) -> Promise {
+        self.internal_assert_owner();
+        log!("upgrade: deploying {} bytes of code", code.0.len());
+
+        Promise::new(env::current_account_id())
+            .deploy_contract(code.into())
+            .function_call("migrate".to_string(), vec![], 0, GAS_FOR_MIGRATE)
+    }
+
+    /// Converts the state from the layout of the previous code into the current one.
+    #[init(ignore_state)]
+    #[private]
+    pub fn migrate() -> Self {
+        let contract = VersionedState::read().migrate();
+        write_state_version();
+        contract
+    }
+
+    // Returns the recorded version of the layout of the state
+    pub fn get_state_version(&self) -> u32 {
+        read_state_version()
+    }
+}
@@ -0,0 +1,252 @@
+//! Fixed-point arithmetic of the weighted product invariant of Balancer:
+//! `V = prod(B_i ^ w_i)` where the normalized weights `w_i` sum up to one.
+//!
+//! The fixed-point numbers have 18 decimals, the powers with fractional exponents
+//! are evaluated as `exp(y * ln(x))` over 256-bit integers.
+
+use near_sdk::{require, Balance};
+
+use crate::math::U256;
+
+/// One in the fixed-point representation.
+pub const ONE: u128 = 1_000_000_000_000_000_000;
+
+/// ln(2) in the fixed-point representation.
+const LN_2: u128 = 693_147_180_559_945_309;
+
+/// The relative error of `pow`, the results are adjusted by it in favor of the pool.
+const MAX_POW_RELATIVE_ERROR: u128 = 10_000;
+
+/// A swap can't take in more than 30% of the input reserve and pay out more than 30% of the output reserve.
+pub const MAX_IN_RATIO: u128 = 300_000_000_000_000_000;
+pub const MAX_OUT_RATIO: u128 = 300_000_000_000_000_000;
+
+/// Signed fixed-point number, `ln` of the values below one is negative.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Signed {
+    pub negative: bool,
+    pub value: U256,
+}
+
+/// Returns `ln(x)` of the positive fixed-point `x`.
+pub fn ln(x: U256) -> Signed {
+    require!(!x.is_zero(), "ln of zero");
+    let one = U256::from(ONE);
+    let two = one * 2;
+
+    // x = m * 2^k, where m is in [1, 2)
+    let (mut m, mut k, negative) = (x, 0_u32, x < one);
+    if negative {
+        while m < one {
+            m <<= 1;
+            k += 1;
+        }
+    } else {
+        while m >= two {
+            m >>= 1;
+            k += 1;
+        }
+    }
+
+    // ln(m) = 2 * atanh(z) = 2 * (z + z^3 / 3 + z^5 / 5 + ...), where z = (m - 1) / (m + 1) is in [0, 1/3)
+    let z = (m - one) * one / (m + one);
+    let z_squared = z * z / one;
+    let (mut term, mut series, mut i) = (z, U256::zero(), 1_u32);
+    while !term.is_zero() {
+        series += term / U256::from(i);
+        term = term * z_squared / one;
+        i += 2;
+    }
+    let ln_m = series * 2;
+    let k_ln_2 = U256::from(LN_2) * U256::from(k);
+
+    if !negative {
+        Signed { negative: false, value: k_ln_2 + ln_m }
+    } else if k_ln_2 >= ln_m {
+        Signed { negative: true, value: k_ln_2 - ln_m }
+    } else {
+        Signed { negative: false, value: ln_m - k_ln_2 }
+    }
+}
+
+/// Returns `e^y` of the signed fixed-point `y`.
+pub fn exp(y: Signed) -> U256 {
+    let one = U256::from(ONE);
+    let ln_2 = U256::from(LN_2);
+
+    // y = k * ln(2) + r, where r is in [0, ln(2))
+    let mut k = (y.value / ln_2).as_u32();
+    let mut r = y.value % ln_2;
+    if y.negative && !r.is_zero() {
+        k += 1;
+        r = ln_2 - r;
+    }
+
+    // e^r = 1 + r + r^2 / 2! + ...
+    let (mut term, mut result, mut i) = (one, one, 1_u32);
+    while !term.is_zero() {
+        term = term * r / one / U256::from(i);
+        result += term;
+        i += 1;
+    }
+
+    if y.negative {
+        if k >= 256 { U256::zero() } else { result >> k }
+    } else {
+        require!(k < 128, "exp overflow");
+        result << k
+    }
+}
+
+/// Returns `x^y` of the fixed-point `x` and `y`.
+pub fn pow(x: U256, y: U256) -> U256 {
+    let one = U256::from(ONE);
+    if y.is_zero() {
+        return one;
+    }
+    let ln_x = ln(x);
+    exp(Signed { negative: ln_x.negative, value: ln_x.value * y / one })
+}
+
+/// Returns `x^y` rounded up by the relative error of `pow`.
+fn pow_up(x: U256, y: U256) -> U256 {
+    let power = pow(x, y);
+    power + power * U256::from(MAX_POW_RELATIVE_ERROR) / U256::from(ONE) + 1
+}
+
+/// Returns the amount of the output token for `amount_in` of the input token, the fee is already taken:
+/// `amount_out = B_out * (1 - (B_in / (B_in + amount_in)) ^ (w_in / w_out))`.
+pub fn get_amount_out(
+    balance_in: Balance,
+    weight_in: u128,
+    balance_out: Balance,
+    weight_out: u128,
+    amount_in: Balance,
+) -> Balance {
+    require!(balance_in > 0 && balance_out > 0, "The pool has no liquidity");
+    require!(
+        U256::from(amount_in) * U256::from(ONE) <= U256::from(balance_in) * U256::from(MAX_IN_RATIO),
+        "swap: the amount in is too large for the pool"
+    );
+
+    let one = U256::from(ONE);
+    let base = U256::from(balance_in) * one / (U256::from(balance_in) + U256::from(amount_in));
+    let exponent = U256::from(weight_in) * one / U256::from(weight_out);
+    // The rounding must always be in favor of the pool
+    let power = pow_up(base, exponent);
+    if power >= one {
+        return 0;
+    }
+    let amount_out = (U256::from(balance_out) * (one - power) / one).as_u128();
+    require!(
+        U256::from(amount_out) * one <= U256::from(balance_out) * U256::from(MAX_OUT_RATIO),
+        "swap: the amount out is too large for the pool"
+    );
+    amount_out
+}
+
+/// Returns the amount of the input token, before the fee, whose swap pays out `amount_out` of the output token:
+/// `amount_in = B_in * ((B_out / (B_out - amount_out)) ^ (w_out / w_in) - 1)`.
+/// The inverse of `get_amount_out` within the relative error of `pow`, so it is not rounded in favor of the pool.
+pub fn get_amount_in(
+    balance_in: Balance,
+    weight_in: u128,
+    balance_out: Balance,
+    weight_out: u128,
+    amount_out: Balance,
+) -> Balance {
+    require!(balance_in > 0 && balance_out > 0, "The pool has no liquidity");
+    require!(amount_out < balance_out, "The pool can't pay out the amount");
+
+    let one = U256::from(ONE);
+    let base = U256::from(balance_out) * one / U256::from(balance_out - amount_out);
+    let exponent = U256::from(weight_out) * one / U256::from(weight_in);
+    let power = pow(base, exponent);
+    let amount_in = U256::from(balance_in) * (power - power.min(one)) / one;
+    require!(amount_in <= U256::from(Balance::MAX), "Balance overflow");
+    amount_in.as_u128()
+}
+
+/// Returns the invariant `V = prod(B_i ^ w_i)` of the balances in base units.
+pub fn invariant(balances: &[Balance], weights: &[u128]) -> U256 {
+    let one = U256::from(ONE);
+    // ln(V) = sum(w_i * ln(B_i))
+    let (mut positive, mut negative) = (U256::zero(), U256::zero());
+    for (balance, weight) in balances.iter().zip(weights) {
+        if *balance == 0 {
+            return U256::zero();
+        }
+        let ln_b = ln(U256::from(*balance) * one);
+        let term = ln_b.value * U256::from(*weight) / one;
+        if ln_b.negative { negative += term } else { positive += term }
+    }
+    let ln_v = if positive >= negative {
+        Signed { negative: false, value: positive - negative }
+    } else {
+        Signed { negative: true, value: negative - positive }
+    };
+    exp(ln_v) / one
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed(value: u128) -> U256 {
+        U256::from(value) * U256::from(ONE)
+    }
+
+    fn assert_close(a: U256, b: U256, tolerance: u128) {
+        let diff = if a > b { a - b } else { b - a };
+        assert!(diff <= U256::from(tolerance), "{} != {}", a, b);
+    }
+
+    #[test]
+    fn test_ln_and_exp() {
+        assert!(ln(fixed(1)).value.is_zero());
+        assert_close(ln(fixed(2)).value, U256::from(LN_2), 10);
+        let ln_half = ln(U256::from(ONE / 2));
+        assert!(ln_half.negative);
+        assert_close(ln_half.value, U256::from(LN_2), 10);
+
+        assert_close(exp(Signed { negative: false, value: U256::from(LN_2) }), fixed(2), 10);
+        assert_close(exp(Signed { negative: true, value: U256::from(LN_2) }), U256::from(ONE / 2), 10);
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_close(pow(fixed(4), U256::from(ONE / 2)), fixed(2), 1_000);
+        assert_close(pow(fixed(2), fixed(10)), fixed(1_024), 1_000_000);
+        assert_close(pow(U256::from(ONE / 4), U256::from(ONE / 2)), U256::from(ONE / 2), 1_000);
+    }
+
+    #[test]
+    fn test_get_amount_out_equal_weights_is_constant_product() {
+        let (balance, amount_in) = (1_000_000_000_000_u128, 1_000_000_000_u128);
+        let amount_out = get_amount_out(balance, ONE / 2, balance, ONE / 2, amount_in);
+        let constant_product = balance * amount_in / (balance + amount_in);
+        assert!(amount_out <= constant_product && constant_product - amount_out < 1_000);
+    }
+
+    #[test]
+    fn test_get_amount_out_80_20() {
+        // The token with the weight of 80% is worth 4 times more per unit at the equal balances
+        let balance = 1_000_000_000_000_000_u128;
+        let amount_out = get_amount_out(balance, ONE * 8 / 10, balance, ONE * 2 / 10, 1_000_000);
+        assert!(amount_out > 3_999_900 && amount_out < 4_000_000);
+    }
+
+    #[test]
+    fn test_get_amount_in_inverts_get_amount_out() {
+        let balance = 1_000_000_000_000_000_u128;
+        let amount_in = 1_000_000_000_u128;
+        let amount_out = get_amount_out(balance, ONE * 8 / 10, balance, ONE * 2 / 10, amount_in);
+        assert_close(U256::from(get_amount_in(balance, ONE * 8 / 10, balance, ONE * 2 / 10, amount_out)), U256::from(amount_in), 100_000);
+    }
+
+    #[test]
+    fn test_invariant() {
+        assert_close(invariant(&[100, 100], &[ONE / 2, ONE / 2]), U256::from(100), 1);
+        assert_close(invariant(&[10_u128.pow(24), 10_u128.pow(24)], &[ONE * 8 / 10, ONE * 2 / 10]), U256::from(10_u128.pow(24)), 10_u128.pow(10));
+    }
+}
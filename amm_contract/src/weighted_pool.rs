@@ -0,0 +1,224 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, log, near_bindgen, require, AccountId, Balance};
+
+use crate::math::{self, U256};
+use crate::pool::{Pool, MAX_POOL_TOKENS};
+use crate::shares::LpShares;
+use crate::simple_pool::MINIMUM_LIQUIDITY;
+use crate::weighted_math::{self, ONE};
+use crate::*;
+
+/// The lowest weight of a token in basis points, 1%.
+pub const MIN_WEIGHT_BPS: u32 = 100;
+
+/// Pool of 2 to 8 tokens with the weighted product invariant, like 80/20 or 33/33/33.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct WeightedPool {
+    pub tokens: Vec<AccountId>,
+    /// Normalized weights of the tokens in the fixed-point representation, they sum up to `ONE`
+    pub weights: Vec<u128>,
+    /// Amounts of the tokens held by the pool, in base units
+    pub reserves: Vec<Balance>,
+    /// Swap fee in basis points, taken from the input amount
+    pub fee_bps: u32,
+    /// Part of the swap fee in basis points of the fee that is accrued for the owner instead of the LPs
+    pub protocol_share_bps: u32,
+    /// Protocol fees of the tokens accrued for the owner
+    pub protocol_fees: Vec<Balance>,
+    /// LP shares of the pool
+    pub shares: LpShares,
+}
+
+impl WeightedPool {
+    /// Creates the pool with the weights given in basis points, they should sum up to 100%.
+    pub fn new(pool_id: u64, tokens: Vec<AccountId>, weights_bps: Vec<u32>, fee_bps: u32) -> Self {
+        require!(
+            (2..=MAX_POOL_TOKENS).contains(&tokens.len()),
+            format!("The pool should have from 2 to {} tokens", MAX_POOL_TOKENS)
+        );
+        require!(tokens.len() == weights_bps.len(), "The weights should be given for all the tokens of the pool");
+        require!(
+            (1..tokens.len()).all(|i| !tokens[..i].contains(&tokens[i])),
+            "The tokens of the pool should be different"
+        );
+        require!(
+            weights_bps.iter().all(|w| *w >= MIN_WEIGHT_BPS),
+            format!("The weight of a token can't be less than {} bps", MIN_WEIGHT_BPS)
+        );
+        require!(
+            weights_bps.iter().sum::<u32>() == math::FEE_DIVISOR,
+            format!("The weights should sum up to {} bps", math::FEE_DIVISOR)
+        );
+
+        let n = tokens.len();
+        Self {
+            tokens,
+            weights: weights_bps
+                .iter()
+                .map(|w| *w as u128 * ONE / math::FEE_DIVISOR as u128)
+                .collect(),
+            reserves: vec![0; n],
+            fee_bps,
+            protocol_share_bps: 0,
+            protocol_fees: vec![0; n],
+            shares: LpShares::new(pool_id),
+        }
+    }
+
+    /// Returns the index of the token, panics if `token_id` is not part of the pool.
+    pub fn index_of(&self, token_id: &AccountId) -> usize {
+        self.tokens
+            .iter()
+            .position(|t| t == token_id)
+            .unwrap_or_else(|| env::panic_str(format!("Unsupported token contract id: {}", token_id).as_str()))
+    }
+
+    /// Returns the invariant V of the current reserves.
+    pub fn invariant(&self) -> U256 {
+        weighted_math::invariant(&self.reserves, &self.weights)
+    }
+
+    /// Returns the amount of `token_out` per one unit of `token_in` with `PRICE_PRECISION`, without the fee:
+    /// `(B_out / w_out) / (B_in / w_in)`.
+    pub fn spot_price(&self, token_in: &AccountId, token_out: &AccountId) -> U256 {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        if self.reserves[i] == 0 {
+            return U256::zero();
+        }
+        U256::from(self.reserves[j]) * U256::from(self.weights[i]) * U256::from(math::PRICE_PRECISION)
+            / (U256::from(self.reserves[i]) * U256::from(self.weights[j]))
+    }
+
+    /// Returns the amount of `token_out` that a swap of `amount_in` of `token_in` would pay out.
+    pub fn get_return(&self, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> Balance {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        require!(i != j, "The tokens of the swap should be different");
+
+        let amount_in_with_fee = amount_in - math::fee_of(amount_in, self.fee_bps);
+        weighted_math::get_amount_out(
+            self.reserves[i],
+            self.weights[i],
+            self.reserves[j],
+            self.weights[j],
+            amount_in_with_fee,
+        )
+    }
+
+    /// Returns the amount of `token_in` whose swap pays out `amount_out` of `token_out`, the inverse of `get_return`.
+    /// The error of `pow` in the inverse is corrected with one Newton step on `get_return`, the marginal rate
+    /// of the swap is `(B_out - amount_out) * w_in / ((B_in + amount_in) * w_out)`.
+    pub fn get_amount_in(&self, token_in: &AccountId, amount_out: Balance, token_out: &AccountId) -> Balance {
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        require!(i != j, "The tokens of the swap should be different");
+
+        let amount_in_with_fee = weighted_math::get_amount_in(
+            self.reserves[i],
+            self.weights[i],
+            self.reserves[j],
+            self.weights[j],
+            amount_out,
+        );
+        let amount_in = math::add_fee(amount_in_with_fee, self.fee_bps);
+        let paid_out = self.get_return(token_in, amount_in, token_out);
+        if paid_out == 0 || paid_out == amount_out {
+            return amount_in;
+        }
+
+        let missing = amount_out.abs_diff(paid_out);
+        let step = U256::from(missing) * U256::from(self.reserves[i] + amount_in_with_fee) * U256::from(self.weights[j])
+            / (U256::from(self.reserves[j] - paid_out) * U256::from(self.weights[i]));
+        let step = math::add_fee(step.as_u128(), self.fee_bps);
+        if paid_out < amount_out {
+            amount_in + step
+        } else {
+            amount_in.saturating_sub(step)
+        }
+    }
+
+    /// Exchanges `amount_in` of `token_in` to `token_out` and updates the reserves.
+    /// The LP part of the fee stays in the reserves, the protocol part is accrued separately.
+    /// Returns the amount of `token_out` that has to be paid out and the accrued protocol fee.
+    pub fn swap(&mut self, token_in: &AccountId, amount_in: Balance, token_out: &AccountId) -> (Balance, Balance) {
+        let amount_out = self.get_return(token_in, amount_in, token_out);
+        require!(amount_out > 0, "swap: the amount is too small to be swapped");
+
+        let (i, j) = (self.index_of(token_in), self.index_of(token_out));
+        let protocol_fee = math::fee_of(math::fee_of(amount_in, self.fee_bps), self.protocol_share_bps);
+        self.reserves[i] += amount_in - protocol_fee;
+        self.reserves[j] -= amount_out;
+        self.protocol_fees[i] += protocol_fee;
+
+        (amount_out, protocol_fee)
+    }
+
+    /// Adds all the tokens to the reserves in proportion to the current reserves.
+    /// Returns the amounts of the tokens that are actually used, the shares to mint to the provider
+    /// and the shares to lock on the first deposit. The shares are not minted here.
+    pub fn add_liquidity(&mut self, amounts: &[Balance]) -> (Vec<Balance>, Balance, Balance) {
+        require!(amounts.len() == self.tokens.len(), "The amounts should be given for all the tokens of the pool");
+        require!(amounts.iter().all(|a| *a > 0), "All the amounts should be positive numbers");
+
+        let total_supply = self.shares.total_supply;
+        let (amounts, shares, locked_shares) = if total_supply == 0 {
+            // The first deposit defines the prices, it mints V of the deposited amounts
+            let shares = weighted_math::invariant(amounts, &self.weights);
+            require!(shares <= U256::from(Balance::MAX), "Balance overflow");
+            let shares = shares.as_u128();
+            require!(shares > MINIMUM_LIQUIDITY, "Insufficient initial liquidity");
+            (amounts.to_vec(), shares - MINIMUM_LIQUIDITY, MINIMUM_LIQUIDITY)
+        } else {
+            let shares = amounts
+                .iter()
+                .zip(&self.reserves)
+                .map(|(amount, reserve)| math::mul_div(*amount, total_supply, *reserve))
+                .min()
+                .unwrap();
+            require!(shares > 0, "Insufficient liquidity minted");
+            // The rounding must always be in favor of the pool
+            let amounts = self
+                .reserves
+                .iter()
+                .map(|reserve| math::mul_div_ceil(shares, *reserve, total_supply))
+                .collect();
+            (amounts, shares, 0)
+        };
+
+        for (reserve, amount) in self.reserves.iter_mut().zip(&amounts) {
+            *reserve += amount;
+        }
+        (amounts, shares, locked_shares)
+    }
+
+    /// Removes the pro rata part of all the reserves for `shares`, the shares are not burned here.
+    /// Returns the amounts of the tokens that have to be paid out.
+    pub fn remove_liquidity(&mut self, shares: Balance) -> Vec<Balance> {
+        require!(shares > 0, "The amount of shares should be a positive number");
+
+        let total_supply = self.shares.total_supply;
+        self.reserves
+            .iter_mut()
+            .map(|reserve| {
+                let amount = math::mul_div(shares, *reserve, total_supply);
+                *reserve -= amount;
+                amount
+            })
+            .collect()
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Registers a new weighted pool of 2 to 8 tokens with the given weights and swap fee in basis points
+    /// and returns its id. The weights should sum up to 100%, e.g. `[8000, 2000]` for a 80/20 pool.
+    /// Requires the role `PoolCreator`.
+    pub fn add_weighted_pool(&mut self, tokens: Vec<AccountId>, weights_bps: Vec<u32>, fee_bps: u32) -> u64 {
+        self.internal_assert_role(Role::PoolCreator);
+        require!(fee_bps <= fees::MAX_FEE_BPS, format!("The fee can't exceed {} bps", fees::MAX_FEE_BPS));
+
+        let pool_id = self.pools.len();
+        let pool = WeightedPool::new(pool_id, tokens.clone(), weights_bps.clone(), fee_bps);
+        self.internal_add_pool(Pool::WeightedPool(pool));
+        log!("add_weighted_pool: pool {} of {:?} with weights {:?} and fee {} bps", pool_id, tokens, weights_bps, fee_bps);
+        pool_id
+    }
+}
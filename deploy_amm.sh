@@ -29,7 +29,7 @@ near call $AMM_CONTRACT_ID \
     --gas=$GAS_FOR_RESOLVE_TRANSFER
 
 # Prints a metadata of the deployed contracts
-near view $AMM_CONTRACT_ID get_tokens_ratio
-near view $AMM_CONTRACT_ID tokens_full_info
-near view $AMM_CONTRACT_ID token_info_by_id '{ "token_contract_id":"'$TOKEN_A_CONTRACT_ID'" }'
-near view $AMM_CONTRACT_ID token_info_by_id '{ "token_contract_id":"'$TOKEN_B_CONTRACT_ID'" }'
+near view $AMM_CONTRACT_ID get_tokens_ratio '{ "pool_id": 0 }'
+near view $AMM_CONTRACT_ID tokens_full_info '{ "pool_id": 0 }'
+near view $AMM_CONTRACT_ID token_info_by_id '{ "pool_id": 0, "token_contract_id":"'$TOKEN_A_CONTRACT_ID'" }'
+near view $AMM_CONTRACT_ID token_info_by_id '{ "pool_id": 0, "token_contract_id":"'$TOKEN_B_CONTRACT_ID'" }'
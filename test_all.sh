@@ -61,39 +61,60 @@ near view $TOKEN_A_CONTRACT_ID \
 # A -> B
 echo "[6] A -> B"
 echo ""
+near call $AMM_CONTRACT_ID storage_deposit '{}' --accountId=$USER_TOKEN_A_001 --amount 0.1
+near call $TOKEN_A_CONTRACT_ID \
+    ft_transfer_call '{
+        "receiver_id": "'$AMM_CONTRACT_ID'",
+        "amount": "5000000000000000",
+        "msg": "{\"action\":\"deposit\"}"
+        }' \
+    --accountId $USER_TOKEN_A_001 \
+    --depositYocto 1 \
+    --gas=$GAS_FOR_RESOLVE_TRANSFER
 near call $AMM_CONTRACT_ID \
     deposit_contract '{
-        "amount":50
+        "pool_id":0,
+        "token_contract_id":"'$TOKEN_A_CONTRACT_ID'",
+        "amount":"5000000000000000",
+        "min_amount_out":"1"
         }' \
     --accountId=$USER_TOKEN_A_001 \
+    --depositYocto 1 \
     --gas=$GAS_FOR_RESOLVE_TRANSFER
-    #  \
-    # --depositYocto 1
 
 # Prints all accounts for the Token A contract
 echo "[7]"
 echo ""
 near view $TOKEN_A_CONTRACT_ID print_accounts
 
-# Deposit token A, token B directly by the AMM contract
+# Add liquidity of token A and token B by the owner
 echo "[8]"
 echo ""
-near call $AMM_CONTRACT_ID deposit_token_contract \
-    '{
-        "token_contract_id":"'$TOKEN_A_CONTRACT_ID'",
-        "amount":5000
-    }' \
-    --accountId=$MASTER_ACCOUNT_ID \
+near call $AMM_CONTRACT_ID storage_deposit '{}' --accountId=$MASTER_ACCOUNT_ID --amount 0.1
+near call $TOKEN_A_CONTRACT_ID \
+    ft_transfer_call '{
+        "receiver_id": "'$AMM_CONTRACT_ID'",
+        "amount": "5000000000000000000000",
+        "msg": "{\"action\":\"deposit\"}"
+        }' \
+    --accountId $MASTER_ACCOUNT_ID \
+    --depositYocto 1 \
     --gas=$GAS_FOR_RESOLVE_TRANSFER
-near view $AMM_CONTRACT_ID get_metadata
-near view $AMM_CONTRACT_ID get_tokens_ratio
-
-near call $AMM_CONTRACT_ID deposit_token_contract \
+near call $TOKEN_B_CONTRACT_ID \
+    ft_transfer_call '{
+        "receiver_id": "'$AMM_CONTRACT_ID'",
+        "amount": "5000000000000000000000",
+        "msg": "{\"action\":\"deposit\"}"
+        }' \
+    --accountId $MASTER_ACCOUNT_ID \
+    --depositYocto 1 \
+    --gas=$GAS_FOR_RESOLVE_TRANSFER
+near call $AMM_CONTRACT_ID add_liquidity \
     '{
-        "token_contract_id":"'$TOKEN_B_CONTRACT_ID'",
-        "amount":5000
+        "pool_id":0,
+        "amounts":["5000000000000000000000","5000000000000000000000"]
     }' \
-    --accountId=$MASTER_ACCOUNT_ID \
-    --gas=$GAS_FOR_RESOLVE_TRANSFER
-near view $AMM_CONTRACT_ID get_metadata
-near view $AMM_CONTRACT_ID get_tokens_ratio
+    --accountId=$MASTER_ACCOUNT_ID
+near view $AMM_CONTRACT_ID tokens_full_info '{ "pool_id": 0 }'
+near view $AMM_CONTRACT_ID get_tokens_ratio '{ "pool_id": 0 }'
+near view $AMM_CONTRACT_ID get_shares '{ "pool_id": 0, "account_id":"'$MASTER_ACCOUNT_ID'" }'
@@ -12,9 +12,9 @@ if [[ ! -d "$DIR" ]]; then DIR="$PWD"; fi
 [ -f $AMM_CONTRACT_FILE ] || { echo "$AMM_CONTRACT_FILE does not exist! Build required"; ./build.sh; }
 
 # Prints a metadata of the deployed contracts
-near view $AMM_CONTRACT_ID tokens_full_info
+near view $AMM_CONTRACT_ID tokens_full_info '{ "pool_id": 0 }'
 near view $TOKEN_A_CONTRACT_ID ft_metadata
 near view $TOKEN_B_CONTRACT_ID ft_metadata
-near view $AMM_CONTRACT_ID token_info_by_id '{ "token_contract_id":"'$TOKEN_A_CONTRACT_ID'" }'
-near view $AMM_CONTRACT_ID token_info_by_id '{ "token_contract_id":"'$TOKEN_B_CONTRACT_ID'" }'
-near view $AMM_CONTRACT_ID get_tokens_ratio
+near view $AMM_CONTRACT_ID token_info_by_id '{ "pool_id": 0, "token_contract_id":"'$TOKEN_A_CONTRACT_ID'" }'
+near view $AMM_CONTRACT_ID token_info_by_id '{ "pool_id": 0, "token_contract_id":"'$TOKEN_B_CONTRACT_ID'" }'
+near view $AMM_CONTRACT_ID get_tokens_ratio '{ "pool_id": 0 }'
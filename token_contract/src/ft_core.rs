@@ -71,7 +71,7 @@ impl FungibleTokenCore for Contract {
 
         near_sdk::log!(format!(
             "ft_transfer: self.contract: {} receiver_id: {} amount: {} memo: {}",
-            self.metadata.get().unwrap().name, receiver_id, amount, memo.clone().unwrap()
+            self.metadata.get().unwrap().name, receiver_id, amount, memo.clone().unwrap_or_default()
         ));
         self.internal_transfer(&sender_id, &receiver_id, amount, memo);
     }
@@ -211,7 +211,7 @@ impl Contract {
                 let refund_amount = std::cmp::min(receiver_balance, unused_amount);
                 
                 // Refund the sender for the unused amount.
-                self.internal_transfer(&receiver_id, &sender_id, refund_amount, Some("Refund".to_string()));
+                self.internal_transfer(&receiver_id, sender_id, refund_amount, Some("Refund".to_string()));
                 
                 // Return what was actually used (the amount sent - refund)
                 let used_amount = amount
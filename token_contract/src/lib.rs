@@ -120,7 +120,10 @@ impl Contract {
         let receiver_id = env::predecessor_account_id();
         self.amm_account = Some(receiver_id.clone());
         self.internal_register_account(&receiver_id);
-        self.transfer_from(sender_id, receiver_id, amount)
+        // The AMM starts with empty reserves, nothing to transfer
+        if amount > 0 {
+            self.transfer_from(sender_id, receiver_id, amount)
+        }
     }
 
     #[payable]
@@ -194,7 +197,7 @@ mod tests {
 
         testing_env!(context.build());
         let contract = Contract::new(
-            accounts(1).into(),
+            accounts(1),
             name.into(),
             symbol.into(),
             near_sdk::json_types::U128(total_supply),
@@ -219,7 +222,7 @@ mod tests {
         // decimals: u8,
 
         let mut token_contract = Contract::new(
-            accounts(2).into(),
+            accounts(2),
             "Token A".into(),
             "tkn_A".into(),
             TOTAL_SUPPLY.into(),